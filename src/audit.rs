@@ -0,0 +1,107 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Context, Result},
+    utils,
+};
+
+/// An execution recorded in the audit log.
+pub struct AuditEntry<'a> {
+    pub input: &'a str,
+    pub profile: &'a str,
+    pub origin: String,
+    pub command: &'a str, // executed action, followed by the arguments given to the shell if any
+}
+
+/// Append-only audit log of the executed actions, rotated when it grows too large.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    rotate: usize,
+}
+
+impl AuditLog {
+    /**
+      Create an audit log writing to `path`. Once the file exceeds `max_size` bytes it is renamed
+      to `path.1` (shifting the previous ones up to `path.<rotate>`) and a new file is started.
+    */
+    pub fn new(path: &Path, max_size: u64, rotate: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            max_size,
+            rotate,
+        }
+    }
+
+    /**
+      Record an entry as a tab separated line: timestamp, user, profile, origin, input, command.
+      The user is the effective user of the process, not the one the environment claims to be.
+    */
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let context = || format!("writing audit log '{}'", self.path.display());
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).with_context(context)?;
+        }
+        self.rotate_if_needed().with_context(context)?;
+
+        let user = utils::effective_user();
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            chrono::Local::now().to_rfc3339(),
+            escape(&user),
+            escape(entry.profile),
+            escape(&entry.origin),
+            escape(entry.input),
+            escape(entry.command),
+        );
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(context)?;
+        file.write_all(line.as_bytes()).with_context(context)?;
+        Ok(())
+    }
+
+    fn rotate_if_needed(&self) -> Result<()> {
+        let size = match self.path.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(()), // nothing to rotate yet
+        };
+        if size < self.max_size {
+            return Ok(());
+        }
+
+        if self.rotate == 0 {
+            fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+
+        // log.(n-1) -> log.n, ..., log.1 -> log.2, log -> log.1
+        for n in (1..self.rotate).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+/// Keep each entry on a single line with unambiguous fields.
+fn escape(s: &str) -> String {
+    s.escape_debug().to_string()
+}
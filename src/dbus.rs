@@ -0,0 +1,129 @@
+use std::fmt;
+
+use log::{debug, warn};
+use zbus::{
+    blocking::{Connection, MessageIterator},
+    message::{Header, Type},
+};
+
+use crate::error::{Context, Result};
+
+/// Well-known name of the service on the session bus.
+pub const SERVICE_NAME: &str = "org.rrr.Opener";
+
+/// Path of the object implementing the interface.
+pub const OBJECT_PATH: &str = "/org/rrr/Opener";
+
+/// Interface with the `Open(input, profile) -> action` method.
+pub const INTERFACE: &str = "org.rrr.Opener";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.rrr.Opener">
+    <method name="Open">
+      <arg name="input" type="s" direction="in"/>
+      <arg name="profile" type="s" direction="in"/>
+      <arg name="action" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/**
+  D-Bus service dispatching the `Open` method calls to a handler.
+  Calls are handled one at a time on the calling thread, so the handler does not
+  need to be shareable across threads.
+*/
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    /// Connect to the session bus and own the service name.
+    pub fn register() -> Result<Self> {
+        let connection = Connection::session().context("cannot connect to the session bus")?;
+        connection
+            .request_name(SERVICE_NAME)
+            .with_context(|| format!("cannot own the name '{}'", SERVICE_NAME))?;
+        Ok(DbusService { connection })
+    }
+
+    /**
+      Serve the method calls forever. The handler gets the input and the profile
+      (empty for the default one) and returns the executed action, or `None` when
+      no rule matches the input.
+    */
+    pub fn serve<F, E>(&self, mut handler: F) -> Result<()>
+    where
+        F: FnMut(&str, &str) -> Result<Option<String>, E>,
+        E: fmt::Display,
+    {
+        for message in MessageIterator::from(&self.connection) {
+            let message = message.context("receiving D-Bus message")?;
+            let header = message.header();
+            if header.message_type() != Type::MethodCall {
+                continue;
+            }
+
+            let interface = header.interface().map(|i| i.as_str());
+            let member = header.member().map(|m| m.as_str()).unwrap_or("");
+            debug!("method call {}.{}", interface.unwrap_or("*"), member);
+
+            let result = match (interface, member) {
+                (Some(INTERFACE) | None, "Open") => match message.body().deserialize() {
+                    Ok((input, profile)) => self.open(&header, &mut handler, input, profile),
+                    Err(e) => self.connection.reply_error(
+                        &header,
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        &e.to_string(),
+                    ),
+                },
+                (Some("org.freedesktop.DBus.Introspectable") | None, "Introspect") => {
+                    self.connection.reply(&header, &INTROSPECTION)
+                }
+                _ => self.connection.reply_error(
+                    &header,
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    &format!("unknown method '{}'", member),
+                ),
+            };
+
+            if let Err(e) = result {
+                warn!("cannot reply to method call: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn open<F, E>(
+        &self,
+        header: &Header<'_>,
+        handler: &mut F,
+        input: &str,
+        profile: &str,
+    ) -> zbus::Result<()>
+    where
+        F: FnMut(&str, &str) -> Result<Option<String>, E>,
+        E: fmt::Display,
+    {
+        match handler(input, profile) {
+            Ok(Some(action)) => self.connection.reply(header, &action),
+            Ok(None) => self.connection.reply_error(
+                header,
+                "org.rrr.Opener.Error.NoMatch",
+                &format!("no match for '{}'", input),
+            ),
+            Err(e) => self.connection.reply_error(
+                header,
+                "org.rrr.Opener.Error.Failed",
+                &format!("{:#}", e),
+            ),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+
+use crate::{
+    error::Result,
+    rule_set::{Pattern, RuleSet},
+    utils,
+};
+
+/// A generated .desktop file that launches rrr for the MIME types handled by an action.
+pub struct DesktopEntry {
+    pub file_name: String,
+    pub contents: String,
+}
+
+/**
+  Generate one desktop entry per distinct action of the rule set.
+
+  Each entry advertises the MIME types (and URI scheme handlers) inferred from the glob
+  patterns leading to this action, and its Exec points at rrr itself with the profile of the
+  rule set. Actions for which no MIME type can be inferred are skipped since they could not
+  be associated to anything.
+*/
+pub fn desktop_entries(rule_set: &RuleSet) -> Result<Vec<DesktopEntry>> {
+    let profile = utils::quote(rule_set.profile())?;
+
+    // group the MIME types by action, keeping the precedence order of the rules
+    let mut actions: Vec<(&str, Vec<String>)> = vec![];
+    for rule in rule_set.rules() {
+        let Pattern::Glob(glob) = &rule.pattern else {
+            continue; // we cannot infer anything from a regex
+        };
+        let action = rule.resolved_action();
+        let index = match actions.iter().position(|(a, _)| *a == action) {
+            Some(index) => index,
+            None => {
+                actions.push((action, vec![]));
+                actions.len() - 1
+            }
+        };
+        for mime_type in glob_to_mime_types(glob) {
+            if !actions[index].1.contains(&mime_type) {
+                actions[index].1.push(mime_type);
+            }
+        }
+    }
+
+    let mut file_names = HashSet::new();
+    let entries = actions
+        .into_iter()
+        .filter(|(_, mime_types)| !mime_types.is_empty())
+        .map(|(action, mime_types)| {
+            let name = action_name(action);
+
+            // avoid overwriting an entry of another action with the same program
+            let mut file_name = format!("rrr-{}-{}.desktop", rule_set.profile(), name);
+            let mut n = 2;
+            while !file_names.insert(file_name.clone()) {
+                file_name = format!("rrr-{}-{}-{}.desktop", rule_set.profile(), name, n);
+                n += 1;
+            }
+
+            // files are given as paths, URI handlers need the URI as-is
+            let field_code = if mime_types
+                .iter()
+                .any(|m| m.starts_with("x-scheme-handler/"))
+            {
+                "%U"
+            } else {
+                "%F"
+            };
+
+            let contents = format!(
+                "[Desktop Entry]\n\
+                 Type=Application\n\
+                 Name={} (rrr)\n\
+                 Comment={}\n\
+                 Exec=rrr -p {} {}\n\
+                 Terminal=false\n\
+                 MimeType={};\n",
+                name,
+                action.replace('\n', " "),
+                profile,
+                field_code,
+                mime_types.join(";")
+            );
+
+            DesktopEntry {
+                file_name,
+                contents,
+            }
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/**
+  Generate the desktop entry registering rrr (with the given profile) as the handler of the given
+  MIME types, named rrr.desktop for the default profile and rrr-<profile>.desktop otherwise.
+*/
+pub fn handler_entry(profile: &str, mime_types: &[String]) -> Result<DesktopEntry> {
+    let (file_name, name) = if profile == "default" {
+        ("rrr.desktop".to_string(), "rrr".to_string())
+    } else {
+        (
+            format!("rrr-{}.desktop", profile),
+            format!("rrr ({})", profile),
+        )
+    };
+    let field_code = if mime_types
+        .iter()
+        .any(|m| m.starts_with("x-scheme-handler/"))
+    {
+        "%U"
+    } else {
+        "%F"
+    };
+
+    let contents = format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name={}\n\
+         Comment=Open with the rules of rrr\n\
+         Exec=rrr -p {} {}\n\
+         Terminal=false\n\
+         NoDisplay=true\n\
+         MimeType={};\n",
+        name,
+        utils::quote(profile)?,
+        field_code,
+        mime_types.join(";")
+    );
+
+    Ok(DesktopEntry {
+        file_name,
+        contents,
+    })
+}
+
+/**
+  Generate a shared-mime-info package declaring one custom MIME type per distinct action of the
+  rule set, application/x-rrr-<profile>-<action>, recognized by the globs leading to this action.
+
+  Only the globs on file names are exported (neither URI schemes nor globs on whole paths), so
+  that desktop environments recognize the files of the same types as rrr and can associate them
+  to an application, e.g. with `rrr register`.
+*/
+pub fn mime_info(rule_set: &RuleSet) -> String {
+    let profile = identifier(rule_set.profile());
+
+    // group the globs by action, keeping the precedence order of the rules
+    let mut actions: Vec<(&str, Vec<(&str, bool)>)> = vec![];
+    for rule in rule_set.rules() {
+        let Pattern::Glob(glob) = &rule.pattern else {
+            continue; // shared-mime-info only knows about globs
+        };
+        if glob == "*" || glob.contains('/') || glob.ends_with(":*") || rule.options.extended_glob {
+            continue; // any file, paths, URIs and extended globs are not file name globs
+        }
+        let action = rule.resolved_action();
+        let index = match actions.iter().position(|(a, _)| *a == action) {
+            Some(index) => index,
+            None => {
+                actions.push((action, vec![]));
+                actions.len() - 1
+            }
+        };
+        let glob = (glob.as_str(), !rule.case_insensitive);
+        if !actions[index].1.contains(&glob) {
+            actions[index].1.push(glob);
+        }
+    }
+
+    let mut mime_types = HashSet::new();
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <mime-info xmlns=\"http://www.freedesktop.org/standards/shared-mime-info\">\n",
+    );
+    for (action, globs) in actions {
+        let name = action_name(action);
+
+        // avoid merging the types of two actions with the same program
+        let mut mime_type = format!("application/x-rrr-{}-{}", profile, name);
+        let mut n = 2;
+        while !mime_types.insert(mime_type.clone()) {
+            mime_type = format!("application/x-rrr-{}-{}-{}", profile, name, n);
+            n += 1;
+        }
+
+        xml += &format!("  <mime-type type=\"{}\">\n", mime_type);
+        xml += &format!(
+            "    <comment>{} (rrr)</comment>\n",
+            xml_escape(&action.replace('\n', " "))
+        );
+        for (glob, case_sensitive) in globs {
+            xml += &format!(
+                "    <glob pattern=\"{}\"{}/>\n",
+                xml_escape(glob),
+                if case_sensitive {
+                    " case-sensitive=\"true\""
+                } else {
+                    ""
+                }
+            );
+        }
+        xml += "  </mime-type>\n";
+    }
+    xml += "</mime-info>\n";
+
+    xml
+}
+
+/// Escape the special characters of XML text and attribute values.
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Short name of an action, i.e. the file name of the program it executes.
+fn action_name(action: &str) -> String {
+    let program = shlex::split(action)
+        .and_then(|words| words.into_iter().next())
+        .unwrap_or_else(|| action.to_string());
+    identifier(program.rsplit('/').next().unwrap_or(&program))
+}
+
+/// Replace the characters that are not safe in file names and MIME types with '_'.
+fn identifier(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Infer the MIME types that a glob pattern is likely to match.
+fn glob_to_mime_types(glob: &str) -> Vec<String> {
+    // URI scheme, e.g. https://* or mailto:*
+    if let Some(scheme) = glob
+        .strip_suffix(":*")
+        .or_else(|| glob.strip_suffix("://*"))
+        && !scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return vec![format!("x-scheme-handler/{}", scheme.to_lowercase())];
+    }
+
+    // file extension, e.g. *.pdf
+    if let Some(extension) = glob.strip_prefix("*.")
+        && extension.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return extension_to_mime_types(extension);
+    }
+
+    vec![]
+}
+
+#[cfg(feature = "import")]
+fn extension_to_mime_types(extension: &str) -> Vec<String> {
+    mime_guess::from_ext(extension)
+        .iter_raw()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+#[cfg(not(feature = "import"))]
+fn extension_to_mime_types(_extension: &str) -> Vec<String> {
+    vec![]
+}
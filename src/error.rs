@@ -0,0 +1,135 @@
+use std::{error, fmt, io, result, time::Duration};
+
+use thiserror::Error;
+
+pub type Result<T, E = RrrError> = result::Result<T, E>;
+
+/**
+  Error of the library, so that embedders can match on its kind. The context added along the
+  way (e.g. "including 'file'") wraps the original error, see `RrrError::kind`.
+*/
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RrrError {
+    /// Error located in a config file, displayed with the offending line and a caret under it.
+    #[error("\n{message}")] // the snippet spans several lines, start it on its own line
+    ParseError {
+        file: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Profile '{0}' does not exist")]
+    UnknownProfile(String),
+    #[error("{} invalid rule(s) in profile '{profile}':\n  {}", .errors.len(), .errors.join("\n  "))]
+    InvalidRules {
+        profile: String,
+        errors: Vec<String>, // each invalid rule with its origin
+    },
+    #[error("Alias '{alias}' does not exist in profile '{profile}'")]
+    UnresolvedAlias { alias: String, profile: String },
+    #[error("invalid pattern '{pattern}': {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+    #[error("cannot execute '{command}'")]
+    ExecFailed {
+        command: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("process exited with code {0}")]
+    ExitCode(i32),
+    #[error("process killed by signal {0}")]
+    Signal(i32),
+    #[error("process timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("not compiled with '{0}' feature")]
+    NotCompiled(&'static str),
+    #[error("Invalid {kind} '{value}' for '{name}'")]
+    InvalidValue {
+        kind: &'static str, // what was expected (e.g. "boolean", "duration")
+        value: String,
+        name: String, // setting or option the value was given to
+    },
+    #[error("{context} '{script}': {reason}")]
+    Script {
+        context: &'static str, // what was done with the script (e.g. "evaluating condition")
+        script: String,
+        reason: String,
+    },
+    #[error("{0}")]
+    BadRequest(String), // malformed request received by the server
+    #[cfg(feature = "wasm")]
+    #[error(transparent)]
+    Wasm(#[from] wasmi::Error),
+    #[cfg(feature = "dbus")]
+    #[error(transparent)]
+    Dbus(#[from] zbus::Error),
+    #[cfg(feature = "server")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Invalid(String), // anything else that is wrong with the config, an action or an input
+    #[error("{context}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<RrrError>,
+    },
+}
+
+impl RrrError {
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        Self::Invalid(message.into())
+    }
+
+    /// Error beneath the context that was added along the way.
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::Context { source, .. } => source.kind(),
+            error => error,
+        }
+    }
+
+    /// Error followed by its causes, e.g. "including 'file': No such file or directory".
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = error::Error::source(self);
+        while let Some(error) = source {
+            report = format!("{}: {}", report, error);
+            source = error.source();
+        }
+        report
+    }
+}
+
+/// Same as `anyhow::Context`, adding context to the errors of the library.
+pub(crate) trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<RrrError>> Context<T> for Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|error| RrrError::Context {
+            context: context().to_string(),
+            source: Box::new(error.into()),
+        })
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.ok_or_else(|| RrrError::invalid(context().to_string()))
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use log::debug;
+
+/// A rule created from a desktop file found while scanning an import target.
+pub(crate) struct ImportedRule {
+    pub desktop_file: String,
+    pub glob: String,
+    pub command: String,
+}
+
+/**
+  Fingerprint of an import target from the modification times of its directories and desktop
+  files. It changes whenever a desktop file is added, removed or modified.
+*/
+pub(crate) fn fingerprint(target: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fingerprint_rec(target, &mut hasher);
+    hasher.finish()
+}
+
+fn fingerprint_rec(path: &Path, hasher: &mut DefaultHasher) {
+    let Ok(metadata) = path.metadata() else {
+        return;
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    path.hash(hasher);
+    mtime.hash(hasher);
+
+    if metadata.is_dir()
+        && let Ok(entries) = fs::read_dir(path)
+    {
+        let mut entries: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().and_then(|s| s.to_str()) == Some("desktop") {
+                fingerprint_rec(&entry, hasher);
+            }
+        }
+    }
+}
+
+/// Load the rules cached for the import target if they are still up to date.
+pub(crate) fn load(target: &Path, fingerprint: u64) -> Option<Vec<ImportedRule>> {
+    let cache_path = cache_path(target)?;
+    let contents = fs::read_to_string(&cache_path).ok()?;
+    let mut lines = contents.lines();
+
+    if lines.next()? != fingerprint.to_string() {
+        debug!("import cache '{}' is outdated", cache_path.display());
+        return None;
+    }
+
+    let mut rules = vec![];
+    for line in lines {
+        let mut fields = line.split('\t');
+        let (desktop_file, glob, command) = (fields.next()?, fields.next()?, fields.next()?);
+        rules.push(ImportedRule {
+            desktop_file: desktop_file.to_string(),
+            glob: glob.to_string(),
+            command: command.to_string(),
+        });
+    }
+
+    debug!("import cache '{}' loaded", cache_path.display());
+    Some(rules)
+}
+
+/// Store the rules scanned from the import target, failing to do so is not an error.
+pub(crate) fn store(target: &Path, fingerprint: u64, rules: &[ImportedRule]) {
+    let Some(cache_path) = cache_path(target) else {
+        return;
+    };
+
+    // fields are tab separated, skip caching entirely if any of them cannot be represented
+    let is_storable = |s: &str| !s.contains(['\t', '\n']);
+    if !rules
+        .iter()
+        .all(|r| is_storable(&r.desktop_file) && is_storable(&r.glob) && is_storable(&r.command))
+    {
+        return;
+    }
+
+    let mut contents = format!("{}\n", fingerprint);
+    for rule in rules {
+        contents.push_str(&format!(
+            "{}\t{}\t{}\n",
+            rule.desktop_file, rule.glob, rule.command
+        ));
+    }
+
+    let result = cache_path
+        .parent()
+        .map(fs::create_dir_all)
+        .transpose()
+        .and_then(|_| fs::write(&cache_path, contents));
+    if let Err(e) = result {
+        debug!(
+            "cannot write import cache '{}': {}",
+            cache_path.display(),
+            e
+        );
+    }
+}
+
+/// Cache file of an import target, in $XDG_CACHE_HOME/rrr/import (or ~/.cache/rrr/import).
+fn cache_path(target: &Path) -> Option<PathBuf> {
+    let cache_home = match env::var_os("XDG_CACHE_HOME") {
+        Some(cache_home) if !cache_home.is_empty() => PathBuf::from(cache_home),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+
+    let mut hasher = DefaultHasher::new();
+    target.hash(&mut hasher);
+    Some(
+        cache_home
+            .join("rrr")
+            .join("import")
+            .join(format!("{:016x}", hasher.finish())),
+    )
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use plist::{Dictionary, Value};
+
+use crate::{error::Result, types::ActionCommand, utils};
+
+/// Directories where the applications are installed (and one level of subdirectories).
+const APPLICATION_DIRS: [&str; 2] = ["/Applications", "/System/Applications"];
+
+/// Bundle declaring the system types (public.jpeg, public.html, ...).
+const CORE_TYPES_BUNDLE: &str = "/System/Library/CoreServices/CoreTypes.bundle";
+
+/// Preferences holding the handlers chosen by the user.
+const LAUNCHSERVICES_PREFERENCES: &str =
+    "Library/Preferences/com.apple.LaunchServices/com.apple.launchservices.secure.plist";
+
+/// A rule generated from the handlers registered in LaunchServices.
+pub struct LaunchServicesRule {
+    pub app: String, // path of the application bundle
+    pub glob: String,
+    pub command: ActionCommand,
+}
+
+/// An installed application and the Info.plist of its bundle.
+struct App {
+    path: PathBuf,
+    info: Dictionary,
+}
+
+/**
+  Generate the rules opening the extensions and URL schemes handled by the installed
+  applications with `open -a <app>`. The UTIs of the document types are converted to
+  extensions with the type declarations of the bundles. The handlers chosen by the user
+  come last, so that they take precedence.
+*/
+pub fn launchservices_rules() -> Result<Vec<LaunchServicesRule>> {
+    let home = env::var("HOME").ok().map(PathBuf::from);
+
+    let mut app_dirs: Vec<PathBuf> = APPLICATION_DIRS.iter().map(PathBuf::from).collect();
+    app_dirs.extend(home.iter().map(|home| home.join("Applications")));
+    let mut apps: Vec<App> = app_dirs.iter().flat_map(|dir| find_apps(dir)).collect();
+    apps.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut uti_extensions: HashMap<String, Vec<String>> = HashMap::new();
+    let core_types = read_info(Path::new(CORE_TYPES_BUNDLE));
+    for info in core_types.iter().chain(apps.iter().map(|app| &app.info)) {
+        declared_types(info, &mut uti_extensions);
+    }
+
+    let mut rules = vec![];
+    for app in &apps {
+        for document_type in array(&app.info, "CFBundleDocumentTypes").filter_map(dictionary) {
+            if string(document_type, "CFBundleTypeRole") == Some("None") {
+                continue;
+            }
+            let extensions = strings(document_type, "CFBundleTypeExtensions")
+                .filter(|extension| *extension != "*")
+                .map(str::to_string)
+                .chain(
+                    strings(document_type, "LSItemContentTypes")
+                        .filter_map(|uti| uti_extensions.get(uti))
+                        .flatten()
+                        .cloned(),
+                );
+            for extension in extensions {
+                rules.push(app_rule(&app.path, format!("*.{}", extension))?);
+            }
+        }
+        for url_type in array(&app.info, "CFBundleURLTypes").filter_map(dictionary) {
+            for scheme in strings(url_type, "CFBundleURLSchemes") {
+                rules.push(app_rule(&app.path, format!("{}:*", scheme))?);
+            }
+        }
+    }
+
+    // handlers chosen by the user (e.g. with "Open With > Change All...")
+    let handlers = home
+        .and_then(|home| Value::from_file(home.join(LAUNCHSERVICES_PREFERENCES)).ok())
+        .and_then(Value::into_dictionary)
+        .unwrap_or_default();
+    for handler in array(&handlers, "LSHandlers").filter_map(dictionary) {
+        let bundle_id = string(handler, "LSHandlerRoleAll")
+            .or_else(|| string(handler, "LSHandlerRoleViewer"))
+            .or_else(|| string(handler, "LSHandlerRoleEditor"));
+        let Some(app) = apps.iter().find(|app| {
+            bundle_id.is_some() && string(&app.info, "CFBundleIdentifier") == bundle_id
+        }) else {
+            continue;
+        };
+
+        let globs: Vec<String> = if let Some(uti) = string(handler, "LSHandlerContentType") {
+            let extensions = uti_extensions.get(uti).into_iter().flatten();
+            extensions
+                .map(|extension| format!("*.{}", extension))
+                .collect()
+        } else if let Some(scheme) = string(handler, "LSHandlerURLScheme") {
+            vec![format!("{}:*", scheme)]
+        } else {
+            vec![]
+        };
+        for glob in globs {
+            rules.push(app_rule(&app.path, glob)?);
+        }
+    }
+
+    Ok(rules)
+}
+
+fn app_rule(app_path: &Path, glob: String) -> Result<LaunchServicesRule> {
+    let app = app_path.to_string_lossy().to_string();
+    Ok(LaunchServicesRule {
+        command: format!("open -a {} %s", utils::quote(&app)?),
+        app,
+        glob,
+    })
+}
+
+/// Applications bundles directly in the directory or in one of its subdirectories.
+fn find_apps(dir: &Path) -> Vec<App> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut apps = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "app") {
+            if let Some(info) = read_info(&path) {
+                apps.push(App { path, info });
+            }
+        } else if path.is_dir()
+            && let Ok(entries) = fs::read_dir(&path)
+        {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|extension| extension == "app")
+                    && let Some(info) = read_info(&path)
+                {
+                    apps.push(App { path, info });
+                }
+            }
+        }
+    }
+    apps
+}
+
+fn read_info(bundle: &Path) -> Option<Dictionary> {
+    Value::from_file(bundle.join("Contents").join("Info.plist"))
+        .ok()?
+        .into_dictionary()
+}
+
+/// Collect the extensions of the UTIs exported or imported by a bundle.
+fn declared_types(info: &Dictionary, uti_extensions: &mut HashMap<String, Vec<String>>) {
+    let declarations = array(info, "UTExportedTypeDeclarations")
+        .chain(array(info, "UTImportedTypeDeclarations"))
+        .filter_map(dictionary);
+    for declaration in declarations {
+        let Some(uti) = string(declaration, "UTTypeIdentifier") else {
+            continue;
+        };
+        let Some(tags) = declaration
+            .get("UTTypeTagSpecification")
+            .and_then(Value::as_dictionary)
+        else {
+            continue;
+        };
+        let extensions = uti_extensions.entry(uti.to_string()).or_default();
+        for extension in strings(tags, "public.filename-extension") {
+            if !extensions.iter().any(|e| e == extension) {
+                extensions.push(extension.to_string());
+            }
+        }
+    }
+}
+
+fn dictionary(value: &Value) -> Option<&Dictionary> {
+    value.as_dictionary()
+}
+
+fn string<'a>(dict: &'a Dictionary, key: &str) -> Option<&'a str> {
+    dict.get(key).and_then(Value::as_string)
+}
+
+fn array<'a>(dict: &'a Dictionary, key: &str) -> impl Iterator<Item = &'a Value> {
+    dict.get(key)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+/// Strings of an array, or the string itself (plists often use one for a single value).
+fn strings<'a>(dict: &'a Dictionary, key: &str) -> impl Iterator<Item = &'a str> {
+    let value = dict.get(key);
+    let single = value.and_then(Value::as_string);
+    let many = value.and_then(Value::as_array).into_iter().flatten();
+    single.into_iter().chain(many.filter_map(Value::as_string))
+}
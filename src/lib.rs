@@ -1,4 +1,19 @@
+pub mod audit;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod desktop;
+pub mod error;
+#[cfg(feature = "import")]
+mod import_cache;
+#[cfg(all(target_os = "macos", feature = "launchservices"))]
+mod launchservices;
+pub mod plugin;
 pub mod rrr;
 pub mod rule_set;
+#[cfg(feature = "script")]
+mod script;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod settings;
 mod types;
 mod utils;
@@ -1,45 +1,100 @@
 use std::{
+    collections::HashMap,
     env,
-    io::{self, BufRead},
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    iter, mem,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
     path::{Path, PathBuf},
-    process::exit,
+    process::{self, Child, Stdio, exit},
+    ptr,
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result, ensure};
-use clap::Parser;
-use log::{debug, error, info, warn};
+use anyhow::{Context, Result, bail, ensure};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{Level, debug, error, info, log, warn};
+use notify::{
+    EventKind, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+#[cfg(feature = "dbus")]
+use runrunrun::dbus::{self, DbusService};
+#[cfg(feature = "server")]
+use runrunrun::server::{self, Opened, Server};
 use runrunrun::{
-    rrr::{Rrr, RrrBuilder},
-    rule_set::{ExecutionType, Rule},
+    audit::{AuditEntry, AuditLog},
+    desktop,
+    rrr::{DEFAULT_CONFIG, Rrr, RrrBuilder},
+    rule_set::{
+        self, ConfigOrigin, ExecutionType, InputFields, Match, Pattern, PreparedAction, Rule,
+        RuleOrigin,
+    },
 };
+#[cfg(feature = "json")]
+use serde::Serialize;
 
-#[derive(Parser, Debug)]
-#[command(version, about)]
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("input_list").args(["stdin", "input_file"])))]
 struct Args {
     /// Increase verbosity level
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
     verbose: u8,
 
     /// Do not execute any matching rule
-    #[arg(short = 'n', long = "dry-run")]
+    #[arg(short = 'n', long = "dry-run", global = true)]
     dry_run: bool,
 
     /// Choose the main configuration file
-    #[arg(short, long, env = "RRR_CONFIG")]
+    #[arg(short, long, global = true, env = "RRR_CONFIG")]
     config: Option<PathBuf>,
 
+    /// Do not load the system configuration file (e.g. /etc/rrr.conf)
+    #[arg(long = "no-system-config", global = true)]
+    no_system_config: bool,
+
+    /// Do not load the user configuration file (~/.config/rrr.conf)
+    #[arg(long = "no-user-config", global = true)]
+    no_user_config: bool,
+
+    /// Only load the configuration file given with --config, never the system, user or default one
+    #[arg(long = "isolated", global = true)]
+    isolated: bool,
+
     /// Choose the profile
-    #[arg(short, long, env = "RRR_PROFILE", default_value = "default")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        env = "RRR_PROFILE",
+        default_value = "default"
+    )]
     profile: String,
 
     /// Just print the action instead of executing it
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     query: bool,
 
+    /// Print the action of every matching rule, in the order they would be tried as fallbacks
+    #[arg(long = "query-all", conflicts_with = "group")]
+    query_all: bool,
+
     /// Match in case sensitive mode
     #[arg(
         short = 's',
         long = "case-sensitive",
+        global = true,
         env = "RRR_CASE_SENSITIVE",
         default_value = "false"
     )]
@@ -49,26 +104,316 @@ struct Args {
     #[arg(long = "stdin")]
     stdin: bool,
 
+    /// Keep reading stdin at its end (e.g. a FIFO whose writers come and go) until interrupted,
+    /// the actions are then always forked
+    #[arg(long = "follow", requires = "stdin", conflicts_with = "group")]
+    follow: bool,
+
+    /// Save the data read from stdin in a temporary file with this name and process it (e.g. from
+    /// an action decrypting or decompressing a file)
+    #[arg(
+        long = "stdin-data",
+        value_name = "NAME",
+        conflicts_with = "input_list"
+    )]
+    stdin_data: Option<String>,
+
+    /// Read the inputs from a file instead of stdin, which stays free for the executed actions
+    #[arg(long = "input-file")]
+    input_file: Option<PathBuf>,
+
+    /// Separate the inputs read from stdin or a file with this character instead of newlines
+    #[arg(short = 'd', long = "delimiter", requires = "input_list")]
+    delimiter: Option<char>,
+
+    /// Separate the inputs read from stdin or a file with NUL characters (e.g. from find -print0)
+    #[arg(
+        short = '0',
+        long = "null",
+        requires = "input_list",
+        conflicts_with = "delimiter"
+    )]
+    null: bool,
+
+    /// Process each file under the inputs that are directories instead of the directories themselves
+    #[arg(short = 'r', long = "recurse")]
+    recurse: bool,
+
+    /// Do not process the files deeper than this number of directories under the input
+    #[arg(long = "max-depth", requires = "recurse", value_parser = clap::value_parser!(u32).range(1..))]
+    max_depth: Option<u32>,
+
+    /// Also process the hidden files and directories (starting with a dot) when recursing
+    #[arg(long = "hidden", requires = "recurse")]
+    hidden: bool,
+
+    /// Skip the files and directories whose name matches this glob when recursing (repeatable)
+    #[arg(long = "prune", requires = "recurse")]
+    prune: Vec<Glob>,
+
+    /// Read each input as `path<TAB>mime<TAB>context` so that rules can match its fields
+    #[arg(long = "structured")]
+    structured: bool,
+
+    /// Read the inputs as text/uri-list payloads (e.g. from drag and drop), one URI per line
+    #[arg(long = "uri-list", conflicts_with = "structured")]
+    uri_list: bool,
+
+    /// Run the actions using %F once with all the inputs matching their rule, instead of once per
+    /// input
+    #[arg(long = "group", conflicts_with = "fallback")]
+    group: bool,
+
     /// Run action in a child process (fork + exec), instead of replacing the current process
-    #[arg(short = 'F', long = "fork")]
+    #[arg(short = 'F', long = "fork", global = true)]
     fork: bool,
 
+    /// Without fork mode, how several inputs are handled: the actions of all the inputs but the
+    /// last one are forked (the last one replaces the current process), or it is an error
+    #[arg(
+        long = "multiple",
+        global = true,
+        value_name = "MODE",
+        env = "RRR_MULTIPLE",
+        default_value = "fork-but-last"
+    )]
+    multiple: MultipleInputs,
+
+    /// Maximum number of actions running at the same time in fork mode
+    #[arg(short = 'j', long = "jobs", global = true, env = "RRR_JOBS")]
+    jobs: Option<usize>,
+
+    /// Kill the actions (and their children) still running after this number of seconds, the
+    /// actions are then waited for (or tracked in fork mode) and a timeout counts as a failure
+    #[arg(long = "timeout", global = true, env = "RRR_TIMEOUT")]
+    timeout: Option<u64>,
+
+    /// Process identical inputs only once, or only once within this number of seconds
+    #[arg(
+        long = "dedup",
+        global = true,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    dedup: Option<Option<u64>>,
+
     /// On execution failure, try the previous matching rule until one succeeds
     #[arg(
         short = 'f',
         long = "fallback",
+        global = true,
         env = "RRR_FALLBACK",
         default_value = "false"
     )]
     fallback: bool,
 
+    /// Before falling back, choose on the terminal which of the matching rules is tried first
+    #[arg(long = "choose", requires = "fallback")]
+    choose: bool,
+
     /// Change the default shell used to execute actions to another command
-    #[arg(long = "sh", env = "RRR_SHELL")]
+    #[arg(long = "sh", global = true, env = "RRR_SHELL")]
     sh: Option<String>,
 
-    /// Input arguments
-    #[arg(required_unless_present = "stdin")]
-    inputs: Vec<String>,
+    /// Ask for confirmation on the terminal before executing an action
+    #[arg(long = "confirm", global = true)]
+    confirm: bool,
+
+    /// Make the first matching rule of the config win instead of the last one (overrides
+    /// `:set match-order`)
+    #[arg(long = "reverse", global = true, env = "RRR_REVERSE")]
+    reverse: bool,
+
+    /// Edit the action in $VISUAL or $EDITOR before executing it (emptying it cancels it)
+    #[arg(long = "edit", global = true)]
+    edit: bool,
+
+    /// Execute the actions even for inputs that match the deny-list
+    #[arg(long = "force", global = true)]
+    force: bool,
+
+    /// Execute actions in a clean environment only containing the variables of the env-keep setting
+    #[arg(long = "env-clean", global = true)]
+    env_clean: bool,
+
+    /// Execute actions in their own transient scope unit with systemd-run
+    #[arg(long = "systemd-scope", global = true, env = "RRR_SYSTEMD_SCOPE")]
+    systemd_scope: bool,
+
+    /// Skip the invalid lines and rules of the configuration with a warning instead of failing
+    #[arg(long = "lenient", global = true, env = "RRR_LENIENT")]
+    lenient: bool,
+
+    /// Report the programs used by the rules of every profile that cannot be found in PATH
+    #[arg(long = "check-commands")]
+    check_commands: bool,
+
+    /// List the rules of the profile, in the order they are tried
+    #[arg(long = "list-rules")]
+    list_rules: bool,
+
+    /// List the configuration files that were read, in load order, with the profiles they
+    /// contributed to
+    #[arg(long = "show-config")]
+    show_config: bool,
+
+    /// Write the default configuration, used when no configuration file is found, and exit
+    #[arg(long = "dump-default-config")]
+    dump_default_config: bool,
+
+    /// Terminate the queried actions, and each field of the listed rules, with a NUL character
+    /// instead of a newline (e.g. for xargs -0), without colors
+    #[arg(long = "print0", global = true, conflicts_with = "output")]
+    print0: bool,
+
+    /// Format of the listed rules and of the queried actions
+    #[arg(
+        long = "output",
+        global = true,
+        value_name = "FORMAT",
+        default_value = "plain"
+    )]
+    output: OutputFormat,
+
+    /// Print a summary of the matches and executions on stderr once every input is processed
+    #[arg(long = "summary", global = true)]
+    summary: bool,
+
+    /// Color the output
+    #[arg(
+        long = "color",
+        global = true,
+        value_name = "WHEN",
+        default_value = "auto"
+    )]
+    color: ColorChoice,
+
+    /// Input arguments (not necessarily valid UTF-8)
+    #[arg(required_unless_present_any = ["stdin", "input_file", "stdin_data", "check_commands", "list_rules", "show_config", "dump_default_config"])]
+    inputs: Vec<OsString>,
+
+    /// Extra arguments given to the program after `--`, substituted with %@ or appended
+    #[arg(last = true, value_name = "ARGS")]
+    extra_args: Vec<OsString>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ColorChoice {
+    Never,
+    Auto, // when stdout is a terminal and NO_COLOR is not set
+    Always,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum MultipleInputs {
+    ForkButLast, // the actions of all the inputs but the last one are forked
+    Error,       // fail before executing an action when other inputs follow
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Plain, // one line per rule or action
+    Table, // aligned columns under a header, truncated to the width of the terminal
+    #[cfg(feature = "json")]
+    Json, // a JSON array of objects keyed by the columns of the table
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Choose the action to execute for an input from a menu (dmenu, rofi, fuzzel, ...)
+    Menu {
+        /// Menu command, it reads the candidates from stdin and prints the selection on stdout
+        #[arg(long = "menu-cmd", env = "RRR_MENU", default_value = "dmenu -l 10")]
+        menu_cmd: String,
+
+        /// Input argument
+        input: String,
+    },
+
+    /// Generate a .desktop launcher entry for each distinct action of the profile
+    DesktopEntries {
+        /// Directory where the entries are written [default: ~/.local/share/applications]
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Generate a shared-mime-info package with a MIME type for the globs of each distinct action
+    MimeInfo {
+        /// File where the package is written [default: stdout]
+        #[arg(short = 'o', long = "output-file")]
+        output_file: Option<PathBuf>,
+    },
+
+    /// Make rrr the default handler of MIME types and URL schemes (with xdg-mime and xdg-settings)
+    Register {
+        /// Directory where the rrr entry is written [default: ~/.local/share/applications]
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+
+        /// MIME types (e.g. application/pdf) and URL schemes (e.g. https) to handle
+        #[arg(required = true)]
+        types: Vec<String>,
+    },
+
+    /// Execute the matching rule on the files of a directory as they appear or change
+    Watch {
+        /// Events on which the files are processed
+        #[arg(
+            short,
+            long = "event",
+            value_enum,
+            value_delimiter = ',',
+            default_value = "create"
+        )]
+        events: Vec<WatchEvent>,
+
+        /// Also watch the files in the subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Directory to watch
+        dir: PathBuf,
+    },
+
+    /// Serve the POST /open requests of local integrations (browsers, editors, ...) over HTTP
+    #[cfg(feature = "server")]
+    Serve {
+        /// Address to listen on
+        #[arg(short, long, env = "RRR_LISTEN", default_value = "127.0.0.1:7677")]
+        listen: String,
+
+        /// Token the clients must give in their 'Authorization: Bearer' header
+        #[arg(short, long, env = "RRR_TOKEN")]
+        token: String,
+
+        /// Exit after this many seconds without any request (for socket activation)
+        #[arg(long = "idle-timeout", value_name = "SECONDS")]
+        idle_timeout: Option<u64>,
+    },
+
+    /// Register the org.rrr.Opener service on the D-Bus session bus to dispatch its Open calls
+    #[cfg(feature = "dbus")]
+    Dbus,
+
+    /// Print the candidates for the last word of a command line (used by shell completion)
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        /// Words of the command line being completed, the last one is the one to complete
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true)]
+        words: Vec<String>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchEvent {
+    /// A file appears in the directory (created or moved into it)
+    Create,
+    /// The content of a file is written
+    Modify,
 }
 
 /*
@@ -92,38 +437,384 @@ impl ExecutionResult {
     }
 }
 
-fn process_rule(
+/*
+  Children spawned in fork mode that may still be running.
+  They are reaped as soon as possible so that, when a maximum is set, a new action
+  is only spawned once a slot is free, without waiting for the previous inputs.
+*/
+struct Jobs<'r> {
+    max: Option<usize>,
+    timeout: Option<Duration>,
+    children: Vec<(Child, Option<Instant>, &'r Rule, usize)>, // running children, when they time out, their rule and its index in the summary
+    grouped: Vec<(String, InputFields)>, // inputs collected in group mode, matched once all are known
+    queried: Vec<TableRow>, // actions queried with table output, printed once all inputs are processed
+    last_input: bool,       // no other input follows, its action may replace the current process
+    processed: usize,       // number of inputs processed, reported on shutdown
+    seen: HashMap<String, Instant>, // when each input was processed (see --dedup)
+    summary: Summary,       // matches and executions, printed with --summary
+}
+
+impl<'r> Jobs<'r> {
+    fn new(max: Option<usize>, timeout: Option<Duration>) -> Self {
+        Jobs {
+            max: max.map(|max| max.max(1)),
+            timeout,
+            children: vec![],
+            grouped: vec![],
+            queried: vec![],
+            last_input: true,
+            processed: 0,
+            seen: HashMap::new(),
+            summary: Summary::new(),
+        }
+    }
+
+    /**
+      Check if the input was already processed, within the window if any, and otherwise remember
+      it. Inputs processed before the window are forgotten.
+    */
+    fn is_duplicate(&mut self, input: &str, window: Option<Duration>) -> bool {
+        let now = Instant::now();
+        if let Some(window) = window {
+            self.seen
+                .retain(|_, seen| now.duration_since(*seen) < window);
+        }
+        if self.seen.contains_key(input) {
+            return true;
+        }
+        self.seen.insert(input.to_string(), now);
+        false
+    }
+
+    /// Forget about the children that already exited, killing those that timed out.
+    fn reap(&mut self) {
+        let summary = &mut self.summary;
+        self.children.retain_mut(|(child, deadline, rule, index)| {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                warn!("child {} timed out, killing it", child.id());
+                rule_set::kill_process_group(child);
+                *deadline = None;
+            }
+            Self::is_running(child, rule, &mut summary.rules[*index].failed)
+        });
+    }
+
+    /// Block until every child exited (or timed out).
+    fn wait_all(&mut self) {
+        loop {
+            self.reap();
+            if self.children.is_empty() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /**
+      Block until every child exited after a shutdown signal. They are killed instead if another
+      signal is received meanwhile.
+    */
+    fn shut_down(&mut self) {
+        let signals = SHUTDOWN_SIGNALS.load(Ordering::SeqCst);
+        loop {
+            self.reap();
+            if self.children.is_empty() {
+                return;
+            }
+            if SHUTDOWN_SIGNALS.load(Ordering::SeqCst) > signals {
+                warn!("killing {} running action(s)", self.children.len());
+                for (child, _, _, index) in &mut self.children {
+                    self.summary.rules[*index].failed += 1;
+                    if self.timeout.is_some() {
+                        rule_set::kill_process_group(child); // spawned as the leader of its group
+                    } else if let Err(e) = child.kill() {
+                        warn!("cannot kill child {}: {}", child.id(), e);
+                    }
+                    let _ = child.wait();
+                }
+                self.children.clear();
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /**
+      Check if the child is still running, logging how it exited otherwise (counting failures
+      according to the success codes of its rule).
+    */
+    fn is_running(child: &mut Child, rule: &Rule, failed: &mut usize) -> bool {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                debug!("child {} exited with {}", child.id(), status);
+                if !rule.is_success(status) {
+                    *failed += 1;
+                }
+                false
+            }
+            Ok(None) => true,
+            Err(e) => {
+                warn!("cannot wait for child {}: {}", child.id(), e);
+                false
+            }
+        }
+    }
+
+    /// Block until less than the maximum number of children are running.
+    fn wait_for_slot(&mut self) {
+        let Some(max) = self.max else { return };
+        loop {
+            self.reap();
+            if self.children.len() < max {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn push(&mut self, child: Child, rule: &'r Rule, summarized: usize) {
+        self.reap();
+        // a timeout too far to be represented means no deadline
+        let deadline = self
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        self.children.push((child, deadline, rule, summarized));
+    }
+}
+
+/// Matches and executions of a batch of inputs.
+struct Summary {
+    start: Instant,
+    matched: usize,
+    unmatched: usize,
+    rules: Vec<RuleSummary>, // rules whose action was executed, in the order of their first execution
+}
+
+/// Executions of the action of a rule during a batch.
+#[cfg_attr(feature = "json", derive(Serialize))]
+struct RuleSummary {
+    pattern: String,
+    action: String,
+    origin: String,
+    executed: usize,
+    failed: usize, // execution errors, failures of the waited actions and forked actions exiting unsuccessfully
+}
+
+impl Summary {
+    fn new() -> Self {
+        Summary {
+            start: Instant::now(),
+            matched: 0,
+            unmatched: 0,
+            rules: vec![],
+        }
+    }
+
+    /// Count an execution of the action of the rule, return the index of the rule in the summary.
+    fn executed(&mut self, rule: &Rule) -> usize {
+        let pattern = pattern_display(rule);
+        let origin = origin_display(rule);
+        let index = match self
+            .rules
+            .iter()
+            .position(|r| r.pattern == pattern && r.origin == origin)
+        {
+            Some(index) => index,
+            None => {
+                self.rules.push(RuleSummary {
+                    pattern,
+                    action: rule.resolved_action().to_string(),
+                    origin,
+                    executed: 0,
+                    failed: 0,
+                });
+                self.rules.len() - 1
+            }
+        };
+        self.rules[index].executed += 1;
+        index
+    }
+
+    /**
+      Print the summary of the inputs on stderr (as a JSON object with the JSON output), so that
+      it does not mix with the queried actions on stdout. The forked actions still running are
+      not counted as failed.
+    */
+    #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+    fn print(&self, args: &Args, inputs: usize) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        #[cfg(feature = "json")]
+        if args.output == OutputFormat::Json {
+            let summary = JsonSummary {
+                inputs,
+                matched: self.matched,
+                unmatched: self.unmatched,
+                seconds: (seconds * 1000.0).round() / 1000.0,
+                rules: &self.rules,
+            };
+            let json = serde_json::to_string(&summary).expect("summary should serialize");
+            eprintln!("{}", json);
+            return;
+        }
+
+        eprintln!(
+            "{} input(s) in {:.3}s: {} matched, {} unmatched",
+            inputs, seconds, self.matched, self.unmatched
+        );
+        for rule in &self.rules {
+            eprintln!(
+                "  {} executed, {} failed: {}  {}  ({})",
+                rule.executed, rule.failed, rule.pattern, rule.action, rule.origin
+            );
+        }
+    }
+}
+
+fn process_rule<'r>(
     args: &Args,
     sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
     input: &str,
-    rule: &Rule,
+    rule: &Match<'r>,
 ) -> Result<ExecutionResult> {
-    debug!("matched rule for '{}': {:?}", input, rule);
-    rule.prepare(input)
+    log!(
+        rule.log_level(Level::Debug),
+        "matched rule for '{}': {:?}",
+        input,
+        rule.rule
+    );
+    let prepared = rule
+        .prepare_with_args(&[input], &extra_args(args))
         .context("preparing the rule for execution")?;
-    let executed_action = rule.get_executed_action()?;
+    process_prepared_action(args, sh, rrr, jobs, input, &prepared)
+}
+
+/// Extra arguments of the program (after `--`), not necessarily valid UTF-8.
+fn extra_args(args: &Args) -> Vec<String> {
+    args.extra_args
+        .iter()
+        .map(|arg| rule_set::input_from_bytes(arg.as_bytes()))
+        .collect()
+}
+
+fn process_prepared_action<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    input: &str,
+    prepared: &PreparedAction<'r>,
+) -> Result<ExecutionResult> {
+    let rule = prepared.rule();
+    let executed_action = prepared.action();
+    check_denied(args, rrr, prepared)?;
 
     if args.query {
-        println!("{}", executed_action);
+        match args.output {
+            OutputFormat::Plain => print_executed_action(args, prepared)?,
+            _ => jobs.queried.push(table_row(
+                &args.profile,
+                rule,
+                &executed_action_display(prepared)?,
+            )),
+        }
     } else {
         if !args.dry_run {
-            info!(
+            let edited;
+            let prepared = match args.edit {
+                true => {
+                    let Some(action) = edit_action(executed_action)? else {
+                        log!(
+                            rule.log_level(Level::Info),
+                            "execution of '{}' cancelled",
+                            executed_action
+                        );
+                        return Ok(ExecutionResult::no_execution());
+                    };
+                    let mut prepared = prepared.clone();
+                    prepared.set_action(action);
+                    edited = prepared;
+                    &edited
+                }
+                false => prepared,
+            };
+            let executed_action = prepared.action();
+
+            if (args.confirm || rule.options.confirm) && !confirm(executed_action)? {
+                log!(
+                    rule.log_level(Level::Info),
+                    "execution of '{}' declined",
+                    executed_action
+                );
+                return Ok(ExecutionResult::no_execution());
+            }
+
+            log!(
+                rule.log_level(Level::Info),
                 "{} '{}'",
                 if args.fork { "fork-exec" } else { "exec" },
                 executed_action
             );
 
-            let execution_type = if args.fallback {
-                ExecutionType::WaitSuccessSignalOk
-            } else if args.fork {
-                ExecutionType::Fork
-            } else {
-                ExecutionType::Exec
-            };
+            // the action must be waited for to fall back or retry when it fails, and to remove
+            // the local copies of the fetched inputs once it returns
+            let execution_type =
+                if args.fallback || rule.options.retry.retries > 0 || prepared.is_fetched() {
+                    ExecutionType::WaitSuccessSignalOk
+                } else if args.fork {
+                    ExecutionType::Fork
+                } else if args.timeout.is_some() || args.summary {
+                    // an executed action could not be timed out, nor the summary printed after it
+                    ExecutionType::WaitSuccessSignalOk
+                } else if !jobs.last_input {
+                    ensure!(
+                        args.multiple == MultipleInputs::ForkButLast,
+                        "cannot execute the action of '{}' while other inputs follow, use --fork",
+                        input
+                    );
+                    ExecutionType::Fork
+                } else {
+                    ExecutionType::Exec
+                };
+
+            if let Some(audit_log) = &rrr.settings().audit_log {
+                let settings = rrr.settings();
+                AuditLog::new(
+                    audit_log,
+                    settings.audit_log_max_size,
+                    settings.audit_log_rotate,
+                )
+                .record(&AuditEntry {
+                    input,
+                    profile: &args.profile,
+                    origin: rule.config_origin.to_string(),
+                    command: &executed_action_display(prepared)?,
+                })?;
+            }
+
+            let env_keep = (args.env_clean || rule.options.env_clean)
+                .then_some(rrr.settings().env_keep.as_slice());
 
-            let result = rule
-                .exec(execution_type, sh)
-                .with_context(|| format!("executing '{}'", executed_action));
+            let systemd_scope = args.systemd_scope || rule.options.systemd;
+
+            let summarized = jobs.summary.executed(rule);
+            let result = if let ExecutionType::Fork = execution_type {
+                // do not wait for the action, but keep the number of running children bounded
+                jobs.wait_for_slot();
+                prepared
+                    .spawn(sh, env_keep, systemd_scope, args.timeout.is_some())
+                    .map(|child| jobs.push(child, rule, summarized))
+            } else {
+                let timeout = args.timeout.map(Duration::from_secs);
+                prepared
+                    .exec(execution_type, sh, env_keep, systemd_scope, timeout)
+                    .map(|_| ()) // waited for, there is no handle
+            }
+            .with_context(|| format!("executing '{}'", executed_action));
+            if result.is_err() {
+                jobs.summary.rules[summarized].failed += 1;
+            }
 
             return Ok(ExecutionResult::with_execution(result));
         }
@@ -132,144 +823,1552 @@ fn process_rule(
     Ok(ExecutionResult::no_execution())
 }
 
-fn process_input(args: &Args, sh: &Option<Vec<&str>>, rrr: &Rrr, input: &str) -> Result<()> {
-    if args.fallback {
-        process_input_with_fallback(args, sh, rrr, input)
-    } else {
-        process_input_without_fallback(args, sh, rrr, input)
-    }
+/**
+  Open the action in $VISUAL (or $EDITOR, vi by default) on the controlling terminal and return
+  it once edited, None if it was emptied to cancel the execution.
+*/
+fn edit_action(executed_action: &str) -> Result<Option<String>> {
+    let tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("cannot edit the action without a controlling terminal")?;
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    let dir = rule_set::process_dir()?;
+    let path = dir.join("action.sh");
+    fs::write(&path, format!("{}\n", executed_action))
+        .with_context(|| format!("cannot create '{}'", path.display()))?;
+
+    // the editor may take arguments (e.g. "code --wait"), it is run by the shell
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .stdin(tty.try_clone()?)
+        .stdout(tty)
+        .status()
+        .with_context(|| format!("cannot execute the editor '{}'", editor));
+    let edited = status.and_then(|status| {
+        ensure!(
+            status.success(),
+            "editor '{}' failed with {}",
+            editor,
+            status
+        );
+        fs::read_to_string(&path).with_context(|| format!("cannot read '{}'", path.display()))
+    });
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir(&dir); // left when used by other files (e.g. --stdin-data)
+
+    let edited = edited?.trim().to_string();
+    Ok((!edited.is_empty()).then_some(edited))
 }
 
-fn process_input_without_fallback(
-    args: &Args,
-    sh: &Option<Vec<&str>>,
-    rrr: &Rrr,
-    input: &str,
-) -> Result<()> {
-    if let Some(rule) = rrr.profile(&args.profile)?.r#match(input) {
-        process_rule(args, sh, input, rule)?.execution_result()?;
-    } else {
-        warn!("no match for '{}'", input);
+/// Ask on the controlling terminal if the action should be executed (defaults to no).
+fn confirm(executed_action: &str) -> Result<bool> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("cannot ask for confirmation without a controlling terminal")?;
+
+    write!(tty, "execute '{}'? [y/N] ", executed_action)?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    io::BufReader::new(tty).read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes" | "YES"))
+}
+
+/**
+  List the matching rules with numbers on the controlling terminal and ask which one to try
+  first, return its index (the first one, which would be tried anyway, on an empty answer).
+*/
+fn choose(input: &str, matches: &[Match]) -> Result<usize> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("cannot ask for a choice without a controlling terminal")?;
+
+    writeln!(tty, "matching rules for '{}':", input)?;
+    for (i, rule) in matches.iter().enumerate() {
+        writeln!(
+            tty,
+            "{:>3}) {}  ({})",
+            i + 1,
+            rule.resolved_action(),
+            rule.config_origin
+        )?;
     }
 
-    Ok(())
+    let mut reader = io::BufReader::new(tty.try_clone()?);
+    loop {
+        write!(tty, "try first [1-{}, default 1]: ", matches.len())?;
+        tty.flush()?;
+
+        let mut answer = String::new();
+        if reader.read_line(&mut answer)? == 0 {
+            return Ok(0); // end of input, keep the usual order
+        }
+        match answer.trim() {
+            "" => return Ok(0),
+            answer => match answer.parse::<usize>() {
+                Ok(n) if (1..=matches.len()).contains(&n) => return Ok(n - 1),
+                _ => writeln!(tty, "invalid choice '{}'", answer)?,
+            },
+        }
+    }
 }
 
-fn process_input_with_fallback(
-    args: &Args,
-    sh: &Option<Vec<&str>>,
-    rrr: &Rrr,
-    input: &str,
-) -> Result<()> {
-    let matches = rrr.profile(&args.profile)?.matches(input);
+/// ANSI styles of the printed output, they are empty when colors are disabled.
+struct Style {
+    enabled: bool,
+}
 
-    let mut match_found = false;
-    for rule in matches {
-        match_found = true;
-        match process_rule(args, sh, input, rule)?.0 {
-            Some(Ok(())) => return Ok(()), // match found and executed correctly
-            Some(Err(e)) => {
-                // match found but execution resulted in an error
-                info!("execution failed (continuing with next match): {:?}", e);
+impl Style {
+    fn new(color: ColorChoice) -> Self {
+        let enabled = match color {
+            ColorChoice::Never => false,
+            ColorChoice::Always => true,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
             }
-            None => {} // nothing executed (dry-run or query) => proceed with other matches
+        };
+        Self { enabled }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
         }
     }
 
-    if !match_found {
-        warn!("no match for '{}'", input);
+    fn pattern(&self, text: &str) -> String {
+        self.paint("36", text) // cyan
+    }
+
+    fn action(&self, text: &str) -> String {
+        self.paint("1;32", text) // bold green
+    }
+
+    fn origin(&self, text: &str) -> String {
+        self.paint("2", text) // dim
+    }
+
+    fn header(&self, text: &str) -> String {
+        self.paint("1", text) // bold
     }
+}
 
+/// Print the executed action, followed by the arguments given to the shell if any.
+fn print_executed_action(args: &Args, prepared: &PreparedAction) -> Result<()> {
+    let style = Style::new(if args.print0 {
+        ColorChoice::Never
+    } else {
+        args.color
+    });
+    let executed_action = prepared.action();
+    let executed_args = prepared.args();
+    let line = if executed_args.is_empty() {
+        style.action(executed_action)
+    } else {
+        let executed_args = shlex::try_join(executed_args.iter().map(|a| a.as_str()))?;
+        format!("{} -- {}", style.action(executed_action), executed_args)
+    };
+    // the inputs that are not valid UTF-8 are printed with their original bytes
+    let terminator = if args.print0 { '\0' } else { '\n' };
+    let line = rule_set::input_to_bytes(&format!("{}{}", line, terminator));
+    io::stdout().write_all(&line)?;
     Ok(())
 }
 
-fn try_main() -> Result<()> {
-    let args = Args::parse();
+/// Executed action followed by the arguments given to the shell if any, as printed on query.
+fn executed_action_display(prepared: &PreparedAction) -> Result<String> {
+    let executed_args = prepared.args();
+    if executed_args.is_empty() {
+        Ok(prepared.action().to_string())
+    } else {
+        let executed_args = shlex::try_join(executed_args.iter().map(|a| a.as_str()))?;
+        Ok(format!("{} -- {}", prepared.action(), executed_args))
+    }
+}
 
-    // configure logger
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(args.verbose as usize)
-        .timestamp(stderrlog::Timestamp::Microsecond)
-        .init()
-        .unwrap();
-    debug!("log operational");
+/// Row of a table, its cells are those of `TABLE_HEADER`.
+type TableRow = [String; 5];
 
-    // load configuration
-    let mut builder = RrrBuilder::new(!args.case_sensitive, Some(vec![args.profile.to_string()]));
+/// Columns of the rules and actions printed as a table.
+const TABLE_HEADER: [&str; 5] = ["PROFILE", "PATTERN", "TYPE", "ACTION", "ORIGIN"];
 
-    if let Some(config_path) = &args.config {
-        debug!("loading config '{}'", config_path.display());
-        builder = builder.config(&config_path).with_context(|| {
-            format!("cannot load configuration file '{}'", config_path.display())
-        })?;
-    } else {
-        let mut main_config_path: PathBuf = match env::consts::OS {
-            "freebsd" => "/usr/local/etc".into(),
-            _ => "/etc".into(),
-        };
-        main_config_path.push("rrr.conf");
+/// Columns are not truncated below this width, the lines may then be wider than the terminal.
+const MIN_COLUMN_WIDTH: usize = 8;
 
-        let home_dir = env::var("HOME").context("cannot read HOME env")?;
-        let home_config_path = Path::new(&home_dir).join(".config").join("rrr.conf");
+/// Row describing the rule along with the action (resolved or prepared from it).
+fn table_row(profile: &str, rule: &Rule, action: &str) -> TableRow {
+    [
+        profile.to_string(),
+        pattern_display(rule),
+        pattern_type(rule).to_string(),
+        action.to_string(),
+        origin_display(rule),
+    ]
+}
 
-        let mut config_loaded = false;
-        if main_config_path.is_file() {
-            debug!("loading config '{}'", main_config_path.display());
-            builder = builder.config(&main_config_path).with_context(|| {
-                format!(
-                    "cannot load configuration file '{}'",
-                    main_config_path.display()
-                )
-            })?;
-            config_loaded = true;
+/**
+  Print the rows in aligned columns under a header. On a terminal, the widest columns are
+  truncated (ending with an ellipsis) until the lines fit in its width.
+*/
+fn print_table(args: &Args, rows: &[TableRow]) {
+    let style = Style::new(args.color);
+    let mut widths = TABLE_HEADER.map(|header| header.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    if let Some(max_width) = terminal_width() {
+        let separators = 2 * (widths.len() - 1);
+        while widths.iter().sum::<usize>() + separators > max_width {
+            let widest = widths.iter_mut().max().expect("table should have columns");
+            if *widest <= MIN_COLUMN_WIDTH {
+                break;
+            }
+            *widest -= 1;
         }
+    }
 
-        if home_config_path.is_file() {
-            debug!("loading config '{}'", home_config_path.display());
-            builder = builder.config(&home_config_path).with_context(|| {
-                format!(
-                    "cannot load configuration file '{}'",
-                    home_config_path.display()
-                )
-            })?;
-            config_loaded = true;
+    // pad before painting, the escape sequences would count in the width
+    let print_line = |cells: [&str; 5], header: bool| {
+        let last = cells.len() - 1;
+        let line: Vec<String> = cells
+            .iter()
+            .enumerate()
+            .map(|(column, cell)| {
+                let cell = truncate(cell, widths[column]);
+                let cell = if column == last {
+                    cell // no trailing spaces
+                } else {
+                    format!("{:<width$}", cell, width = widths[column])
+                };
+                match column {
+                    _ if header => style.header(&cell),
+                    1 => style.pattern(&cell),
+                    3 => style.action(&cell),
+                    4 => style.origin(&cell),
+                    _ => cell,
+                }
+            })
+            .collect();
+        println!("{}", line.join("  "));
+    };
+    print_line(TABLE_HEADER, true);
+    for row in rows {
+        print_line(row.each_ref().map(|cell| cell.as_str()), false);
+    }
+}
+
+/// Row of a table with the JSON output, keyed by the columns of `TABLE_HEADER`.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonRow<'a> {
+    profile: &'a str,
+    pattern: &'a str,
+    r#type: &'a str,
+    action: &'a str,
+    origin: &'a str,
+}
+
+#[cfg(feature = "json")]
+impl<'a> From<&'a TableRow> for JsonRow<'a> {
+    fn from([profile, pattern, r#type, action, origin]: &'a TableRow) -> Self {
+        JsonRow {
+            profile,
+            pattern,
+            r#type,
+            action,
+            origin,
         }
+    }
+}
 
-        ensure!(
-            config_loaded,
-            "none of the configuration files '{}' nor '{}' could be loaded",
-            main_config_path.display(),
-            home_config_path.display()
+/// Summary of the inputs with the JSON output, the time is in seconds.
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    inputs: usize,
+    matched: usize,
+    unmatched: usize,
+    seconds: f64,
+    rules: &'a [RuleSummary],
+}
+
+/// Print the rows as a table, or as a JSON array with the JSON output.
+fn print_rows(args: &Args, rows: &[TableRow]) {
+    #[cfg(feature = "json")]
+    if args.output == OutputFormat::Json {
+        let rows: Vec<JsonRow> = rows.iter().map(JsonRow::from).collect();
+        println!(
+            "{}",
+            serde_json::to_string(&rows).expect("rows should serialize")
         );
+        return;
     }
+    print_table(args, rows);
+}
 
-    // some preparation for the execution
-    let rrr = builder.build()?;
-    // live and let (the Vec<&str>) live
-    let sh = args
-        .sh
-        .as_ref()
-        .map(|s| shlex::split(&s).context("invalid SH substitute"))
-        .transpose()?;
-    let sh_str: Option<Vec<&str>> = sh.as_ref().map(|v| v.iter().map(|s| s.as_str()).collect());
+/// Truncate the text to the width (in characters), ending it with an ellipsis when it is cut.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
 
-    // match the inputs
-    if args.stdin {
-        debug!("process inputs from stdin");
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            let input = line.context("reading from stdin")?;
-            process_input(&args, &sh_str, &rrr, &input)?;
-        }
-    } else {
-        debug!("process inputs from arguments");
-        for input in &args.inputs {
-            process_input(&args, &sh_str, &rrr, input)?;
-        }
+/// Width of the terminal (or $COLUMNS), None when stdout is not a terminal.
+fn terminal_width() -> Option<usize> {
+    if let Some(columns) = env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return Some(columns);
+    }
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes the size of the terminal into the given winsize
+    let result = unsafe { libc::ioctl(io::stdout().as_raw_fd(), libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+/// Pattern of the rule written as in the configuration.
+fn pattern_display(rule: &Rule) -> String {
+    match &rule.pattern {
+        Pattern::Glob(glob) => glob.to_string(),
+        Pattern::Regex(regex) => format!("~{}", regex),
+        Pattern::Matcher(command) => format!(":matcher-cmd {}", command),
+        Pattern::Plugin(plugin) => format!(":matcher-plugin {}", plugin.name()),
+        Pattern::Field(field, glob) => format!("{}:{}", field.name(), glob),
+        Pattern::Fuzzy(text) => format!("fuzzy:{}", text),
+        Pattern::Content(regex) => format!("content:{}", regex),
+        Pattern::Binary(formats) => format!("binary:{}", formats),
+        Pattern::Exact(literal) => format!("={}", literal),
+        Pattern::Prefix(literal) => format!("prefix:{}", literal),
+        Pattern::Suffix(literal) => format!("suffix:{}", literal),
+    }
+}
+
+/// Kind of pattern of the rule.
+fn pattern_type(rule: &Rule) -> &'static str {
+    match &rule.pattern {
+        Pattern::Glob(_) => "glob",
+        Pattern::Regex(_) => "regex",
+        Pattern::Matcher(_) => "matcher",
+        Pattern::Plugin(_) => "plugin",
+        Pattern::Field(..) => "field",
+        Pattern::Fuzzy(_) => "fuzzy",
+        Pattern::Content(_) => "content",
+        Pattern::Binary(_) => "binary",
+        Pattern::Exact(_) => "exact",
+        Pattern::Prefix(_) => "prefix",
+        Pattern::Suffix(_) => "suffix",
+    }
+}
+
+/// Place in the config where the rule was declared, along with the imported file if any.
+fn origin_display(rule: &Rule) -> String {
+    match &rule.rule_origin {
+        RuleOrigin::Explicit => rule.config_origin.to_string(),
+        RuleOrigin::Imported(path) => format!("{} ({})", rule.config_origin, path),
+    }
+}
+
+/// Print the pattern, action and origin of each rule of the profile in aligned columns.
+fn process_list_rules(args: &Args, rrr: &Rrr) -> Result<()> {
+    let rules = rrr.profile(&args.profile)?.rules();
+    if args.output != OutputFormat::Plain {
+        let rows: Vec<TableRow> = rules
+            .map(|rule| table_row(&args.profile, rule, rule.resolved_action()))
+            .collect();
+        print_rows(args, &rows);
+        return Ok(());
+    }
+
+    if args.print0 {
+        for rule in rules {
+            print!(
+                "{}\0{}\0{}\0",
+                pattern_display(rule),
+                rule.resolved_action(),
+                origin_display(rule)
+            );
+        }
+        return Ok(());
+    }
+
+    let style = Style::new(args.color);
+    let rows: Vec<(String, &str, String)> = rules
+        .map(|rule| {
+            (
+                pattern_display(rule),
+                rule.resolved_action(),
+                origin_display(rule),
+            )
+        })
+        .collect();
+
+    // pad before painting, the escape sequences would count in the width
+    let pattern_width = rows.iter().map(|r| r.0.chars().count()).max().unwrap_or(0);
+    let action_width = rows.iter().map(|r| r.1.chars().count()).max().unwrap_or(0);
+    for (pattern, action, origin) in &rows {
+        println!(
+            "{}  {}  {}",
+            style.pattern(&format!("{:<pattern_width$}", pattern)),
+            style.action(&format!("{:<action_width$}", action)),
+            style.origin(origin)
+        );
+    }
+    Ok(())
+}
+
+/**
+  Refuse the actions whose substituted command contains denied content (unless forced), as the
+  inputs, environment variables or captures it was substituted with could have altered it.
+*/
+fn check_denied(args: &Args, rrr: &Rrr, prepared: &PreparedAction) -> Result<()> {
+    if args.force {
+        return Ok(());
+    }
+    let command = iter::once(prepared.action()).chain(prepared.args().iter().map(String::as_str));
+    for part in command {
+        if let Some(pattern) = rrr.settings().denied_pattern(part) {
+            bail!(
+                "refusing to run '{}' as it contains denied pattern '{}' (use --force to override)",
+                part.escape_debug(),
+                pattern
+            );
+        }
+    }
+    Ok(())
+}
+
+fn process_input<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    input: &str,
+    fields: &InputFields,
+) -> Result<()> {
+    let input = rrr.settings().normalize_input(input);
+    if args.recurse && rule_set::input_path(input).is_dir() {
+        let mut prune = GlobSetBuilder::new();
+        for glob in &args.prune {
+            prune.add(glob.clone());
+        }
+        let prune = prune.build()?;
+        return process_dir(args, sh, rrr, jobs, &rule_set::input_path(input), &prune);
+    }
+    if let Some(window) = args.dedup
+        && jobs.is_duplicate(input, window.map(Duration::from_secs))
+    {
+        debug!("skipping duplicate input '{}'", input);
+        return Ok(());
+    }
+    if args.group && args.command.is_none() {
+        jobs.grouped.push((input.to_string(), fields.clone()));
+        return Ok(());
+    }
+    // the fallbacks are not executed when querying, every matching rule is queried instead
+    if args.fallback || args.query_all {
+        process_input_with_fallback(args, sh, rrr, jobs, input, fields)
+    } else {
+        process_input_without_fallback(args, sh, rrr, jobs, input, fields)
+    }
+}
+
+/**
+  Process each file under the directory, the errors of a file do not stop the others.
+  The files are listed first, so that only the action of the last one may replace the process.
+*/
+fn process_dir<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    dir: &Path,
+    prune: &GlobSet,
+) -> Result<()> {
+    let mut files = Vec::new();
+    list_files(args, dir, prune, 1, &mut files)?;
+
+    let last_input = jobs.last_input;
+    for (i, path) in files.iter().enumerate() {
+        jobs.last_input = last_input && i + 1 == files.len();
+        let input = rule_set::input_from_bytes(path.as_os_str().as_bytes());
+        if let Err(e) = process_input(args, sh, rrr, jobs, &input, &InputFields::default()) {
+            error!("{:#}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+  List the files under the directory sorted by name, the errors of a subdirectory do not stop the
+  others. The depth of its entries is given to stop at --max-depth.
+*/
+fn list_files(
+    args: &Args,
+    dir: &Path,
+    prune: &GlobSet,
+    depth: u32,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    debug!("list files under '{}'", dir.display());
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("cannot read directory '{}'", dir.display()))?
+        .flatten()
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        if (!args.hidden && name.as_encoded_bytes().starts_with(b".")) || prune.is_match(&name) {
+            continue;
+        }
+
+        let path = entry.path();
+        // symbolic links to directories are not followed to avoid walking in circles
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            if args.max_depth.is_none_or(|max_depth| depth < max_depth)
+                && let Err(e) = list_files(args, &path, prune, depth + 1, files)
+            {
+                error!("{:#}", e);
+            }
+            continue;
+        }
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Last signal (SIGINT or SIGTERM) asking to shut down, 0 until one is received.
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// Number of signals asking to shut down received so far.
+static SHUTDOWN_SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn request_shutdown(signal: libc::c_int) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::SeqCst);
+    SHUTDOWN_SIGNALS.fetch_add(1, Ordering::SeqCst);
+}
+
+/**
+  Record SIGINT and SIGTERM instead of dying on them, so that streaming modes stop processing
+  inputs and shut down gracefully (see `shutdown_signal`). The handlers are installed without
+  SA_RESTART, so that a blocking read of the inputs is interrupted. Executed actions get back the
+  default handlers.
+*/
+fn handle_shutdown_signals() -> Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the action is fully initialized and the handler only stores into atomics
+        let result = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = request_shutdown as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error()).context("installing the signal handlers");
+        }
+    }
+    Ok(())
+}
+
+/// Signal received asking to shut down, if any.
+fn shutdown_signal() -> Option<i32> {
+    let signal = SHUTDOWN_SIGNAL.load(Ordering::SeqCst);
+    (signal != 0).then_some(signal)
+}
+
+/// Reader ending (as if the end of file was reached) once interrupted by a shutdown signal.
+struct Interruptible<R>(R);
+
+impl<R: io::Read> io::Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    if shutdown_signal().is_some() {
+                        return Ok(0);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Reader waiting for more data at the end of file instead of ending, until a shutdown signal.
+struct Follow<R>(R);
+
+impl<R: io::Read> io::Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf)? {
+                0 if !buf.is_empty() && shutdown_signal().is_none() => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+/**
+  Save the data from stdin in a temporary file named after the given name, in a directory only
+  readable by the user. The file is left for the executed action, which may outlive rrr.
+*/
+fn save_stdin_data(name: &str) -> Result<PathBuf> {
+    let file_name = Path::new(name)
+        .file_name()
+        .with_context(|| format!("invalid name for the data from stdin '{}'", name))?;
+    let path = rule_set::process_dir()?.join(file_name);
+    let mut file =
+        fs::File::create(&path).with_context(|| format!("cannot create '{}'", path.display()))?;
+    io::copy(&mut io::stdin().lock(), &mut file).context("saving the data from stdin")?;
+    Ok(path)
+}
+
+/// Process an input given on the command line or stdin, split into fields in structured mode.
+fn process_line<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    line: &str,
+) -> Result<()> {
+    if args.structured {
+        let (input, fields) = InputFields::split(line);
+        process_input(args, sh, rrr, jobs, input, &fields)
+    } else if args.uri_list {
+        let last_input = jobs.last_input;
+        let inputs = rule_set::uri_list(line);
+        for (i, input) in inputs.iter().enumerate() {
+            jobs.last_input = last_input && i + 1 == inputs.len();
+            process_input(args, sh, rrr, jobs, input, &InputFields::default())?;
+        }
+        Ok(())
+    } else {
+        process_input(args, sh, rrr, jobs, line, &InputFields::default())
+    }
+}
+
+/**
+  Process the inputs read from stdin or a file, one per line or separated by the chosen delimiter.
+  The descriptor the reader reads from tells if the next input is there without waiting for it.
+*/
+fn process_input_list<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    reader: impl BufRead,
+    fd: RawFd,
+    source: &str,
+) -> Result<()> {
+    let is_ready = || is_readable(fd);
+    let delimiter = if args.null {
+        Some('\0')
+    } else {
+        args.delimiter
+    };
+    let Some(delimiter) = delimiter.filter(|d| *d != '\n') else {
+        let lines = reader.split(b'\n').map(|line| {
+            let mut line = line.with_context(|| format!("reading from {}", source))?;
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+            Ok(rule_set::input_from_bytes(&line))
+        });
+        return process_lines(args, sh, rrr, jobs, lines, is_ready);
+    };
+
+    ensure!(
+        delimiter.is_ascii(),
+        "the delimiter '{}' is not an ASCII character",
+        delimiter.escape_debug()
+    );
+    let items = reader.split(delimiter as u8).filter_map(|item| {
+        let item = item
+            .with_context(|| format!("reading from {}", source))
+            .map(|item| rule_set::input_from_bytes(&item));
+        match item {
+            // the last input is usually followed by a newline, unless separated by NUL characters
+            Ok(mut input) => {
+                if delimiter != '\0' && input.ends_with('\n') {
+                    input.pop();
+                }
+                (!input.is_empty()).then_some(Ok(input))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    });
+    process_lines(args, sh, rrr, jobs, items, is_ready)
+}
+
+/// Check if reading from the descriptor would not block (some data or the end of file is there).
+fn is_readable(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: poll only writes the returned events into the single pollfd it is given
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 }
+}
+
+/**
+  Process the inputs one after the other. Unless in fork mode, the next input is read before
+  processing one when it is ready, so that only the action of the last input may replace the
+  current process. An input that is not followed yet (e.g. streamed on stdin) is processed at
+  once, as if others followed.
+*/
+fn process_lines<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    lines: impl Iterator<Item = Result<String>>,
+    is_ready: impl Fn() -> bool,
+) -> Result<()> {
+    let may_exec = !args.fork && !args.query && !args.dry_run;
+    let mut lines = lines.peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        jobs.last_input = !may_exec || (is_ready() && lines.peek().is_none());
+        if shutdown_signal().is_some() {
+            break;
+        }
+        process_line(args, sh, rrr, jobs, &line)?;
+        jobs.processed += 1;
+    }
+
+    Ok(())
+}
+
+/**
+  Process the inputs collected in group mode. The inputs matching the same rule whose action
+  takes all the inputs at once (%F) are given together, the others are processed one by one.
+*/
+fn process_groups<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+) -> Result<()> {
+    let grouped = mem::take(&mut jobs.grouped);
+    let rule_set = rrr.profile(&args.profile)?;
+
+    let mut singles: Vec<(Match, &str)> = vec![];
+    let mut groups: Vec<(Match, Vec<&str>)> = vec![];
+    for (input, fields) in &grouped {
+        let Some(rule) = rule_set.match_with_fields(input, fields) else {
+            jobs.summary.unmatched += 1;
+            warn!("no match for '{}'", input);
+            continue;
+        };
+        jobs.summary.matched += 1;
+        if !rule.is_groupable() {
+            singles.push((rule, input));
+            continue;
+        }
+        match groups.iter_mut().find(|(r, _)| ptr::eq(r.rule, rule.rule)) {
+            Some((_, inputs)) => inputs.push(input),
+            None => groups.push((rule, vec![input])),
+        }
+    }
+
+    // only the last action may replace the current process
+    let mut remaining = singles.len() + groups.len();
+    for (rule, input) in singles {
+        remaining -= 1;
+        jobs.last_input = remaining == 0;
+        process_rule(args, sh, rrr, jobs, input, &rule)?.execution_result()?;
+    }
+    for (rule, inputs) in groups {
+        remaining -= 1;
+        jobs.last_input = remaining == 0;
+        log!(
+            rule.log_level(Level::Debug),
+            "matched rule for {} input(s): {:?}",
+            inputs.len(),
+            rule.rule
+        );
+        let prepared = rule
+            .prepare_with_args(&inputs, &extra_args(args))
+            .context("preparing the rule for execution")?;
+        process_prepared_action(args, sh, rrr, jobs, &inputs.join(" "), &prepared)?
+            .execution_result()?;
+    }
+
+    Ok(())
+}
+
+fn process_input_without_fallback<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    input: &str,
+    fields: &InputFields,
+) -> Result<()> {
+    if let Some(rule) = rrr.profile(&args.profile)?.match_with_fields(input, fields) {
+        jobs.summary.matched += 1;
+        process_rule(args, sh, rrr, jobs, input, &rule)?.execution_result()?;
+    } else {
+        jobs.summary.unmatched += 1;
+        warn!("no match for '{}'", input);
+    }
+
+    Ok(())
+}
+
+fn process_input_with_fallback<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    input: &str,
+    fields: &InputFields,
+) -> Result<()> {
+    let mut matches: Vec<Match> = rrr
+        .profile(&args.profile)?
+        .matches_with_fields(input, fields)
+        .collect();
+
+    // the chosen rule is tried first, then the others in their usual order
+    if args.choose && matches.len() > 1 {
+        let choice = choose(input, &matches)?;
+        let rule = matches.remove(choice);
+        matches.insert(0, rule);
+    }
+
+    let match_found = !matches.is_empty();
+    if match_found {
+        jobs.summary.matched += 1;
+    } else {
+        jobs.summary.unmatched += 1;
+    }
+    for rule in matches {
+        match process_rule(args, sh, rrr, jobs, input, &rule)?.0 {
+            Some(Ok(())) => return Ok(()), // match found and executed correctly
+            Some(Err(e)) => {
+                // match found but execution resulted in an error
+                log!(
+                    rule.log_level(Level::Info),
+                    "execution failed (continuing with next match): {:?}",
+                    e
+                );
+            }
+            None => {} // nothing executed (dry-run or query) => proceed with other matches
+        }
+    }
+
+    if !match_found {
+        warn!("no match for '{}'", input);
+    }
+
+    Ok(())
+}
+
+/// Feed the candidate actions for the input into the menu command and execute the selected one.
+fn process_menu<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    menu_cmd: &str,
+    input: &str,
+) -> Result<()> {
+    let input = rrr.settings().normalize_input(input);
+    // prepare every matching rule, skipping those that end up with an already proposed action
+    let mut candidates: Vec<PreparedAction> = vec![];
+    for rule in rrr.profile(&args.profile)?.matches(input) {
+        let prepared = rule
+            .prepare_with_args(&[input], &extra_args(args))
+            .context("preparing the rule for execution")?;
+        if !candidates.iter().any(|c| c.action() == prepared.action()) {
+            candidates.push(prepared);
+        }
+    }
+
+    if candidates.is_empty() {
+        warn!("no match for '{}'", input);
+        return Ok(());
+    }
+
+    let labels: Vec<&str> = candidates.iter().map(|c| c.action()).collect();
+    let Some(selection) = run_menu(menu_cmd, &labels)? else {
+        info!("menu selection cancelled");
+        return Ok(());
+    };
+
+    let prepared = candidates
+        .iter()
+        .find(|c| c.action() == selection)
+        .with_context(|| format!("selection '{}' is not one of the candidates", selection))?;
+    process_prepared_action(args, sh, rrr, jobs, input, prepared)?.execution_result()
+}
+
+/// Run the menu command with one label per line and return the selected label (if any).
+fn run_menu(menu_cmd: &str, labels: &[&str]) -> Result<Option<String>> {
+    let menu = shlex::split(menu_cmd).context("invalid menu command")?;
+    ensure!(
+        !menu.is_empty(),
+        "menu command should have at least one argument"
+    );
+
+    let mut child = process::Command::new(&menu[0])
+        .args(&menu[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("spawning menu '{}'", menu_cmd))?;
+
+    let mut stdin = child.stdin.take().expect("menu stdin should be piped");
+    for label in labels {
+        writeln!(stdin, "{}", label).context("writing candidates to the menu")?;
+    }
+    drop(stdin); // close the pipe so that the menu knows there are no more candidates
+
+    let output = child.wait_with_output().context("reading menu selection")?;
+    if !output.status.success() {
+        // menus usually exit with an error when the selection is cancelled
+        return Ok(None);
+    }
+
+    let selection = String::from_utf8(output.stdout).context("menu selection is not UTF-8")?;
+    let selection = selection.trim_end_matches('\n');
+    Ok((!selection.is_empty()).then(|| selection.to_string()))
+}
+
+/// Directory of the desktop entries, the given one or the applications of the XDG data home.
+fn applications_dir(output_dir: &Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(output_dir) = output_dir {
+        return Ok(output_dir.clone());
+    }
+    let data_home = match env::var("XDG_DATA_HOME") {
+        Ok(data_home) if !data_home.is_empty() => PathBuf::from(data_home),
+        _ => Path::new(&env::var("HOME").context("cannot read HOME env")?)
+            .join(".local")
+            .join("share"),
+    };
+    Ok(data_home.join("applications"))
+}
+
+/// Write the desktop entries generated from the profile into the output directory.
+fn process_desktop_entries(args: &Args, rrr: &Rrr, output_dir: &Option<PathBuf>) -> Result<()> {
+    let output_dir = applications_dir(output_dir)?;
+
+    for entry in desktop::desktop_entries(rrr.profile(&args.profile)?)? {
+        let path = output_dir.join(&entry.file_name);
+        if args.query {
+            println!("{}", path.display());
+        } else if !args.dry_run {
+            info!("writing '{}'", path.display());
+            fs::create_dir_all(&output_dir)
+                .with_context(|| format!("creating '{}'", output_dir.display()))?;
+            fs::write(&path, entry.contents)
+                .with_context(|| format!("writing '{}'", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the shared-mime-info package generated from the profile into the output file (or stdout).
+fn process_mime_info(args: &Args, rrr: &Rrr, output_file: &Option<PathBuf>) -> Result<()> {
+    let xml = desktop::mime_info(rrr.profile(&args.profile)?);
+    match output_file {
+        Some(path) if !args.dry_run => {
+            info!("writing '{}'", path.display());
+            fs::write(path, xml).with_context(|| format!("writing '{}'", path.display()))?;
+        }
+        Some(_) => (),
+        None => print!("{}", xml),
+    }
+    Ok(())
+}
+
+/**
+  Write the desktop entry of rrr and make it the default handler of the given MIME types and URL
+  schemes, with `xdg-mime default` and `xdg-settings set default-url-scheme-handler` respectively.
+*/
+fn process_register(args: &Args, output_dir: &Option<PathBuf>, types: &[String]) -> Result<()> {
+    let output_dir = applications_dir(output_dir)?;
+
+    // a type without '/' is a scheme, handled by x-scheme-handler/<scheme>
+    let mut mime_types = vec![];
+    for r#type in types {
+        let mime_type = if r#type.contains('/') {
+            r#type.to_string()
+        } else {
+            format!("x-scheme-handler/{}", r#type.to_lowercase())
+        };
+        let is_valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.!#$&^_".contains(c))
+        };
+        match mime_type.split_once('/') {
+            Some((media, subtype)) if is_valid(media) && is_valid(subtype) => {}
+            _ => bail!("Invalid MIME type or scheme '{}'", r#type),
+        }
+        mime_types.push(mime_type);
+    }
+
+    let entry = desktop::handler_entry(&args.profile, &mime_types)?;
+    let path = output_dir.join(&entry.file_name);
+    let commands: Vec<Vec<&str>> = mime_types
+        .iter()
+        .map(
+            |mime_type| match mime_type.strip_prefix("x-scheme-handler/") {
+                Some(scheme) => vec![
+                    "xdg-settings",
+                    "set",
+                    "default-url-scheme-handler",
+                    scheme,
+                    &entry.file_name,
+                ],
+                None => vec!["xdg-mime", "default", &entry.file_name, mime_type],
+            },
+        )
+        .collect();
+
+    if args.query {
+        println!("{}", path.display());
+        for command in &commands {
+            println!("{}", shlex::try_join(command.iter().copied())?);
+        }
+        return Ok(());
+    } else if args.dry_run {
+        return Ok(());
+    }
+
+    info!("writing '{}'", path.display());
+    fs::create_dir_all(&output_dir)
+        .with_context(|| format!("creating '{}'", output_dir.display()))?;
+    fs::write(&path, &entry.contents).with_context(|| format!("writing '{}'", path.display()))?;
+
+    for command in &commands {
+        info!("running '{}'", command.join(" "));
+        let status = process::Command::new(command[0])
+            .args(&command[1..])
+            .status()
+            .with_context(|| format!("cannot execute '{}'", command[0]))?;
+        ensure!(
+            status.success(),
+            "'{}' failed ({})",
+            command.join(" "),
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/**
+  Process the files of the directory as the watched events happen, until interrupted.
+  Actions are always forked (the watcher must survive them), and a failing input is
+  reported without stopping the watch.
+*/
+fn process_watch<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    dir: &Path,
+    events: &[WatchEvent],
+    recursive: bool,
+) -> Result<()> {
+    let args = &Args {
+        fork: true,
+        ..args.clone()
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("creating the watcher")?;
+    let mode = if recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher
+        .watch(dir, mode)
+        .with_context(|| format!("watching '{}'", dir.display()))?;
+    info!("watching '{}'", dir.display());
+
+    while shutdown_signal().is_none() {
+        let event = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(event) => event.context("receiving watch events")?,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let watch_event = match event.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                WatchEvent::Create
+            }
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => WatchEvent::Modify,
+            _ => continue,
+        };
+        if !events.contains(&watch_event) {
+            continue;
+        }
+
+        for path in event.paths.iter().filter(|p| p.is_file()) {
+            let input = rule_set::input_from_bytes(path.as_os_str().as_bytes());
+            debug!("{:?} event on '{}'", watch_event, path.display());
+            if let Err(e) = process_input(args, sh, rrr, jobs, &input, &InputFields::default()) {
+                error!("{:#}", e);
+            }
+            jobs.processed += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+  Resolve the action matching the input of a request (HTTP or D-Bus), and execute it unless
+  the request is query-only. As for watch mode, the actions are always forked.
+  The action is returned along with the rule it comes from.
+*/
+#[cfg(any(feature = "server", feature = "dbus"))]
+fn process_request<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    input: &str,
+    profile: Option<&str>,
+    query: bool,
+) -> Result<Option<(String, &'r Rule)>> {
+    let args = &Args {
+        fork: true,
+        profile: profile.unwrap_or(&args.profile).to_string(),
+        ..args.clone()
+    };
+    let input = rrr.settings().normalize_input(input);
+    let Some(rule) = rrr.profile(&args.profile)?.r#match(input) else {
+        return Ok(None);
+    };
+    let prepared = rule
+        .prepare(input)
+        .context("preparing the rule for execution")?;
+    let action = prepared.action().to_string();
+
+    if !query {
+        process_prepared_action(args, sh, rrr, jobs, input, &prepared)?.execution_result()?;
+    }
+    Ok(Some((action, rule.rule)))
+}
+
+/**
+  Answer the HTTP requests until interrupted (or idle for too long). The listening socket
+  is the one passed by the service manager if rrr was socket-activated.
+*/
+#[cfg(feature = "server")]
+fn process_serve<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+    listen: &str,
+    token: &str,
+    idle_timeout: Option<u64>,
+) -> Result<()> {
+    let server = match server::activation_listener()? {
+        Some(listener) => Server::from_listener(listener, token),
+        None => {
+            let server = Server::bind(listen, token)?;
+            info!("listening on '{}'", listen);
+            server
+        }
+    }
+    .with_idle_timeout(idle_timeout.map(Duration::from_secs));
+
+    server.serve(|request| -> Result<_> {
+        let resolved = process_request(
+            args,
+            sh,
+            rrr,
+            jobs,
+            &request.input,
+            request.profile.as_deref(),
+            request.query,
+        )?;
+        Ok(resolved.map(|(action, rule)| Opened {
+            action,
+            origin: rule.config_origin.to_string(),
+            pattern: pattern_display(rule),
+        }))
+    })?;
+    Ok(())
+}
+
+/// Answer the D-Bus method calls until interrupted.
+#[cfg(feature = "dbus")]
+fn process_dbus<'r>(
+    args: &Args,
+    sh: &Option<Vec<&str>>,
+    rrr: &'r Rrr,
+    jobs: &mut Jobs<'r>,
+) -> Result<()> {
+    let service = DbusService::register()?;
+    info!("serving '{}' on the session bus", dbus::SERVICE_NAME);
+
+    service.serve(|input, profile| -> Result<_> {
+        let profile = (!profile.is_empty()).then_some(profile);
+        let resolved = process_request(args, sh, rrr, jobs, input, profile, false)?;
+        Ok(resolved.map(|(action, _)| action))
+    })?;
+    Ok(())
+}
+
+/**
+  Print the dynamic completion candidates (one per line) for the last word of the command line.
+  Only what cannot be known statically is completed here: the profiles for `-p`, the aliases
+  (e.g. `[browser]`) for a word starting with `[` and the tags (e.g. `%x`) for one with `%`.
+*/
+fn process_complete(rrr: &Rrr, words: &[String]) {
+    let current = words.last().map(|w| w.as_str()).unwrap_or("");
+    let previous = words.len().checked_sub(2).map(|i| words[i].as_str());
+
+    let profiles = || rrr.profiles().iter().map(|p| p.to_string()).collect();
+    let (prefix, current, candidates) = if let Some(value) = current.strip_prefix("--profile=") {
+        ("--profile=", value, profiles())
+    } else if matches!(previous, Some("-p" | "--profile")) {
+        ("", current, profiles())
+    } else if current.starts_with('[') {
+        let aliases = rrr.aliases().iter().map(|a| format!("[{}]", a)).collect();
+        ("", current, aliases)
+    } else if current.starts_with('%') {
+        ("", current, rrr.tags())
+    } else {
+        return;
+    };
+
+    for candidate in candidates {
+        if candidate.starts_with(current) {
+            println!("{}{}", prefix, candidate);
+        }
+    }
+}
+
+/// List the config files in load order, the included ones indented under their includer.
+fn process_show_config(rrr: &Rrr) {
+    for config_file in rrr.config_files() {
+        let indent = "  ".repeat(config_file.depth);
+        if config_file.duplicate {
+            println!("{}{}  (already loaded, skipped)", indent, config_file.file);
+        } else if config_file.profiles.is_empty() {
+            println!("{}{}  (no rules)", indent, config_file.file);
+        } else {
+            let profiles = config_file.profiles.join(", ");
+            println!("{}{}  ({})", indent, config_file.file, profiles);
+        }
+    }
+}
+
+/// Report missing programs grouped by profile, fails if any is missing.
+fn process_check_commands(rrr: &Rrr) -> Result<()> {
+    let mut missing_count = 0;
+    for profile in rrr.profiles() {
+        // imported rules share the same origin, report each program only once per origin
+        let mut missing: Vec<(String, &ConfigOrigin)> = vec![];
+        for rule in rrr.profile(profile)?.rules() {
+            if let Some(program) = rule.missing_program() {
+                let origin = &rule.config_origin;
+                if !missing.iter().any(|(p, o)| *p == program && o == &origin) {
+                    missing.push((program, origin));
+                }
+            }
+        }
+        missing.sort_by_key(|(_, o)| (&o.file, o.line, o.column));
+
+        if !missing.is_empty() {
+            println!("profile '{}':", profile);
+            for (program, origin) in &missing {
+                println!("  {} ({})", program, origin);
+            }
+            missing_count += missing.len();
+        }
+    }
+
+    ensure!(missing_count == 0, "{} missing command(s)", missing_count);
+    Ok(())
+}
+
+fn try_main() -> Result<()> {
+    let mut args = Args::parse();
+    args.query |= args.query_all;
+
+    // configure logger
+    stderrlog::new()
+        .module(module_path!())
+        .module("runrunrun")
+        .verbosity(args.verbose as usize)
+        .timestamp(stderrlog::Timestamp::Microsecond)
+        .init()
+        .unwrap();
+    debug!("log operational");
+
+    if args.dump_default_config {
+        print!("{}", DEFAULT_CONFIG);
+        return Ok(());
+    }
+
+    // load configuration (completion and services need to know about every profile)
+    let only_profiles = match args.command {
+        Some(Command::Complete { .. }) => None,
+        #[cfg(feature = "server")]
+        Some(Command::Serve { .. }) => None,
+        #[cfg(feature = "dbus")]
+        Some(Command::Dbus) => None,
+        _ if args.check_commands || args.show_config => None,
+        _ => Some(vec![args.profile.to_string()]),
+    };
+    let mut builder = RrrBuilder::new(!args.case_sensitive, only_profiles).lenient(args.lenient);
+
+    if let Some(config_path) = &args.config {
+        debug!("loading config '{}'", config_path.display());
+        builder.load_config(config_path).with_context(|| {
+            format!("cannot load configuration file '{}'", config_path.display())
+        })?;
+    } else {
+        // --isolated never falls back to another configuration than the one given
+        if args.isolated {
+            bail!("--isolated requires a configuration file given with --config");
+        }
+
+        let mut config_paths = vec![];
+        if !args.no_system_config {
+            let mut main_config_path: PathBuf = match env::consts::OS {
+                "freebsd" => "/usr/local/etc".into(),
+                _ => "/etc".into(),
+            };
+            main_config_path.push("rrr.conf");
+            config_paths.push(main_config_path);
+        }
+        if !args.no_user_config {
+            let home_dir = env::var("HOME").context("cannot read HOME env")?;
+            config_paths.push(Path::new(&home_dir).join(".config").join("rrr.conf"));
+        }
+        let mut config_loaded = false;
+        for config_path in &config_paths {
+            if config_path.is_file() {
+                debug!("loading config '{}'", config_path.display());
+                builder.load_config(config_path).with_context(|| {
+                    format!("cannot load configuration file '{}'", config_path.display())
+                })?;
+                config_loaded = true;
+            }
+        }
+
+        if !config_loaded {
+            info!("no configuration file found, using the default configuration");
+            builder
+                .load_default_config()
+                .context("cannot load the default configuration")?;
+        }
+    }
+
+    if args.reverse {
+        builder.set_first_match(true);
+    }
+
+    // some preparation for the execution
+    let rrr = builder.build()?;
+    // live and let (the Vec<&str>) live
+    let sh = args
+        .sh
+        .as_ref()
+        .map(|s| shlex::split(s).context("invalid SH substitute"))
+        .transpose()?;
+    let sh_str: Option<Vec<&str>> = sh.as_ref().map(|v| v.iter().map(|s| s.as_str()).collect());
+    let mut jobs = Jobs::new(args.jobs, args.timeout.map(Duration::from_secs));
+
+    if args.check_commands {
+        return process_check_commands(&rrr);
+    }
+    if args.list_rules {
+        return process_list_rules(&args, &rrr);
+    }
+    if args.show_config {
+        process_show_config(&rrr);
+        return Ok(());
+    }
+
+    // match the inputs
+    match &args.command {
+        Some(Command::Menu { menu_cmd, input }) => {
+            debug!("process input from menu");
+            process_menu(&args, &sh_str, &rrr, &mut jobs, menu_cmd, input)?;
+        }
+        Some(Command::DesktopEntries { output_dir }) => {
+            debug!("generate desktop entries");
+            process_desktop_entries(&args, &rrr, output_dir)?;
+        }
+        Some(Command::MimeInfo { output_file }) => {
+            debug!("generate shared-mime-info package");
+            process_mime_info(&args, &rrr, output_file)?;
+        }
+        Some(Command::Register { output_dir, types }) => {
+            debug!("register as default handler");
+            process_register(&args, output_dir, types)?;
+        }
+        Some(Command::Watch {
+            events,
+            recursive,
+            dir,
+        }) => {
+            debug!("process inputs from watched directory");
+            handle_shutdown_signals()?;
+            process_watch(&args, &sh_str, &rrr, &mut jobs, dir, events, *recursive)?;
+        }
+        #[cfg(feature = "server")]
+        Some(Command::Serve {
+            listen,
+            token,
+            idle_timeout,
+        }) => {
+            debug!("process inputs from HTTP requests");
+            process_serve(
+                &args,
+                &sh_str,
+                &rrr,
+                &mut jobs,
+                listen,
+                token,
+                *idle_timeout,
+            )?;
+        }
+        #[cfg(feature = "dbus")]
+        Some(Command::Dbus) => {
+            debug!("process inputs from D-Bus calls");
+            process_dbus(&args, &sh_str, &rrr, &mut jobs)?;
+        }
+        Some(Command::Complete { words }) => process_complete(&rrr, words),
+        None if args.stdin => {
+            debug!("process inputs from stdin");
+            handle_shutdown_signals()?;
+            let stdin = Interruptible(io::stdin().lock());
+            if args.follow {
+                // partial lines are completed once more data arrives, the actions are forked
+                // so that the next inputs are not waited for (as in watch mode)
+                let args = Args {
+                    fork: true,
+                    ..args.clone()
+                };
+                let stdin = io::BufReader::new(Follow(stdin));
+                let fd = io::stdin().as_raw_fd();
+                process_input_list(&args, &sh_str, &rrr, &mut jobs, stdin, fd, "stdin")?;
+            } else {
+                let stdin = io::BufReader::new(stdin);
+                let fd = io::stdin().as_raw_fd();
+                process_input_list(&args, &sh_str, &rrr, &mut jobs, stdin, fd, "stdin")?;
+            }
+        }
+        None if let Some(name) = &args.stdin_data => {
+            debug!("process data from stdin as '{}'", name);
+            let path = save_stdin_data(name)?;
+            let input = path.to_str().context("temporary path is not UTF-8")?;
+            process_input(
+                &args,
+                &sh_str,
+                &rrr,
+                &mut jobs,
+                input,
+                &InputFields::default(),
+            )?;
+        }
+        None if let Some(input_file) = &args.input_file => {
+            debug!("process inputs from '{}'", input_file.display());
+            let file = fs::File::open(input_file)
+                .with_context(|| format!("cannot open '{}'", input_file.display()))?;
+            let source = input_file.display().to_string();
+            let fd = file.as_raw_fd();
+            process_input_list(
+                &args,
+                &sh_str,
+                &rrr,
+                &mut jobs,
+                io::BufReader::new(file),
+                fd,
+                &source,
+            )?;
+        }
+        None => {
+            debug!("process inputs from arguments");
+            let inputs = args
+                .inputs
+                .iter()
+                .map(|input| Ok(rule_set::input_from_bytes(input.as_bytes())));
+            process_lines(&args, &sh_str, &rrr, &mut jobs, inputs, || true)?;
+        }
+    }
+
+    if args.group && shutdown_signal().is_none() {
+        debug!("process grouped inputs");
+        process_groups(&args, &sh_str, &rrr, &mut jobs)?;
+    }
+    if !jobs.queried.is_empty() {
+        print_rows(&args, &jobs.queried);
+    }
+
+    if let Some(signal) = shutdown_signal() {
+        jobs.reap();
+        // the summary is printed whatever the verbosity
+        eprintln!(
+            "shutting down on {} after processing {} input(s), waiting for {} running action(s)",
+            if signal == libc::SIGINT {
+                "SIGINT"
+            } else {
+                "SIGTERM"
+            },
+            jobs.processed,
+            jobs.children.len()
+        );
+        jobs.shut_down();
+        if args.summary {
+            jobs.summary.print(&args, jobs.processed);
+        }
+        exit(128 + signal);
     }
 
     debug!("all inputs processed");
+    // stay around to enforce the timeout of the forked actions, or to count their failures
+    if args.timeout.is_some() || args.summary {
+        jobs.wait_all();
+    }
+    if args.summary {
+        jobs.summary.print(&args, jobs.processed);
+    }
 
     Ok(())
 }
@@ -0,0 +1,167 @@
+/*!
+  Matchers and transformers loaded as WebAssembly modules (`<plugin-dir>/<name>.wasm`).
+
+  Plugins are sandboxed: they cannot import anything from rrr or the system, a fresh instance
+  is created for each call and their execution is bounded. The ABI they expose is:
+
+  - `memory`: the exported linear memory,
+  - `rrr_alloc(len: i32) -> i32`: allocate `len` bytes, where rrr writes the strings it passes,
+  - `rrr_match(ptr: i32, len: i32) -> i32` (optional): non-zero when the UTF-8 input matches,
+  - `rrr_substitute(action_ptr: i32, action_len: i32, input_ptr: i32, input_len: i32) -> i64`
+    (optional): the command to execute for the action and input, returned as `ptr << 32 | len`.
+*/
+
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Context, Result, RrrError};
+#[cfg(feature = "wasm")]
+use wasmi::{Config, Engine, Instance, Linker, Module, Store};
+
+/// Number of instructions (roughly) a plugin can execute in a call.
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 10_000_000;
+
+/// Largest string a plugin can return.
+#[cfg(feature = "wasm")]
+const MAX_RESULT_SIZE: usize = 1024 * 1024;
+
+pub struct Plugin {
+    name: String,
+    #[cfg(feature = "wasm")]
+    engine: Engine,
+    #[cfg(feature = "wasm")]
+    module: Module,
+    #[cfg(not(feature = "wasm"))]
+    never: std::convert::Infallible, // plugins cannot be loaded without the wasm feature
+}
+
+/// Plugins are serialized as their name.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Plugin {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Plugin({})", self.name)
+    }
+}
+
+/// Directory of the plugins when not set, $XDG_CONFIG_HOME/rrr/plugins (or ~/.config/rrr/plugins).
+pub fn default_dir() -> Result<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+        _ => PathBuf::from(env::var_os("HOME").context("cannot read HOME env")?).join(".config"),
+    };
+    Ok(config_home.join("rrr").join("plugins"))
+}
+
+impl Plugin {
+    /// Load the plugin `<dir>/<name>.wasm`.
+    pub fn load(dir: &Path, name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains('/') {
+            return Err(RrrError::invalid(format!("invalid plugin name '{}'", name)));
+        }
+        let path = dir.join(format!("{}.wasm", name));
+        Self::load_path(&path, name).with_context(|| format!("loading plugin '{}'", path.display()))
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn load_path(_path: &Path, _name: &str) -> Result<Self> {
+        Err(RrrError::NotCompiled("wasm"))
+    }
+
+    #[cfg(feature = "wasm")]
+    fn load_path(path: &Path, name: &str) -> Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, std::fs::read(path)?)?;
+        Ok(Plugin {
+            name: name.to_string(),
+            engine,
+            module,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn matches(&self, _input: &str) -> Result<bool> {
+        match self.never {}
+    }
+
+    /// Call `rrr_match` with the input.
+    #[cfg(feature = "wasm")]
+    pub fn matches(&self, input: &str) -> Result<bool> {
+        let (mut store, instance) = self.instantiate()?;
+        let (ptr, len) = write_string(&mut store, &instance, input)?;
+        let r#match = instance.get_typed_func::<(i32, i32), i32>(&store, "rrr_match")?;
+        Ok(r#match.call(&mut store, (ptr, len))? != 0)
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn substitute(&self, _action: &str, _input: &str) -> Result<String> {
+        match self.never {}
+    }
+
+    /// Call `rrr_substitute` with the action and the input, it returns the command to execute.
+    #[cfg(feature = "wasm")]
+    pub fn substitute(&self, action: &str, input: &str) -> Result<String> {
+        let (mut store, instance) = self.instantiate()?;
+        let (action_ptr, action_len) = write_string(&mut store, &instance, action)?;
+        let (input_ptr, input_len) = write_string(&mut store, &instance, input)?;
+        let substitute =
+            instance.get_typed_func::<(i32, i32, i32, i32), i64>(&store, "rrr_substitute")?;
+        let result =
+            substitute.call(&mut store, (action_ptr, action_len, input_ptr, input_len))? as u64;
+
+        let (ptr, len) = ((result >> 32) as usize, (result & 0xffff_ffff) as usize);
+        if len > MAX_RESULT_SIZE {
+            return Err(RrrError::invalid("substitution result too large"));
+        }
+        let mut buffer = vec![0; len];
+        memory(&store, &instance)?
+            .read(&store, ptr, &mut buffer)
+            .map_err(wasmi::Error::from)?;
+        String::from_utf8(buffer)
+            .ok()
+            .context("substitution result is not UTF-8")
+    }
+
+    #[cfg(feature = "wasm")]
+    fn instantiate(&self) -> Result<(Store<()>, Instance)> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL)?;
+        let instance = Linker::new(&self.engine).instantiate_and_start(&mut store, &self.module)?;
+        Ok((store, instance))
+    }
+}
+
+#[cfg(feature = "wasm")]
+fn memory(store: &Store<()>, instance: &Instance) -> Result<wasmi::Memory> {
+    instance
+        .get_memory(store, "memory")
+        .context("plugin does not export its memory")
+}
+
+/// Copy a string into memory allocated by the plugin.
+#[cfg(feature = "wasm")]
+fn write_string(store: &mut Store<()>, instance: &Instance, s: &str) -> Result<(i32, i32)> {
+    let len = i32::try_from(s.len())
+        .ok()
+        .context("string too large for the plugin")?;
+    let alloc = instance.get_typed_func::<i32, i32>(&*store, "rrr_alloc")?;
+    let ptr = alloc.call(&mut *store, len)?;
+    memory(store, instance)?
+        .write(&mut *store, ptr as u32 as usize, s.as_bytes())
+        .map_err(wasmi::Error::from)?;
+    Ok((ptr, len))
+}
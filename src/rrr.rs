@@ -1,46 +1,278 @@
 use std::{
     cell::{RefCell, RefMut},
     collections::{HashMap, HashSet},
-    fs,
+    env, fs,
+    io::{self, BufRead},
     path::{Path, PathBuf},
+    process::{self, Stdio},
+    sync::{Arc, LazyLock, Mutex, OnceLock, PoisonError},
+    time::Duration,
 };
 
-use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
 
-use pest::{Parser, iterators::Pair};
+use globset::GlobBuilder;
+use pest::{
+    Parser, Span,
+    error::{Error as PestError, ErrorVariant, LineColLocation},
+    iterators::Pair,
+};
 use pest_derive::Parser;
+use regex::Regex;
 
+#[cfg(feature = "import")]
+use crate::import_cache;
+#[cfg(all(target_os = "macos", feature = "launchservices"))]
+use crate::launchservices;
 use crate::{
-    rule_set::{ConfigOrigin, Pattern, RuleSet, RuleSetBuilder},
+    error::{Context, Result, RrrError},
+    plugin::{self, Plugin},
+    rule_set::{
+        self, Action, ConfigOrigin, InputField, Pattern, RetryPolicy, RuleOptions, RuleSet,
+        RuleSetBuilder, Substitutions,
+    },
+    settings::{self, Settings},
     types::ProfileIdentifier,
     utils::{self, expand},
 };
 
 pub struct RrrBuilder {
     loaded_config_files: HashSet<PathBuf>,
+    loaded_config_commands: HashSet<String>, // commands whose output was included (`:include-cmd`)
+    config_files: RefCell<Vec<LoadedConfig>>, // every config file read (or skipped), in load order
+    config_stack: Vec<usize>, // index in config_files of the files being parsed, the current one last
     profiles: RefCell<HashMap<ProfileIdentifier, RuleSetBuilder>>,
     current_profile: ProfileIdentifier,
+    profile_skipped: bool, // the condition of the last `:profile-if` is not met, its lines are skipped
     case_insensitive: bool,
     only_profiles: Option<Vec<String>>,
+    settings: Settings,
+    guards: Vec<bool>, // conditions of the enclosing blocks (e.g. `:require`), false if not met
+    plugins: RefCell<HashMap<String, Arc<Plugin>>>, // loaded plugins, shared by their rules
+    substitutions: Substitutions,
+    macros: HashMap<String, Macro>,
+    macro_depth: usize,          // number of macro calls being expanded
+    retry: RetryPolicy,          // retry policy of the following rules (`:retry`)
+    success_codes: Vec<i32>,     // exit codes of a successful action for the following rules
+    config_version: Option<u32>, // syntax version declared by the file being parsed (`:version`)
+    version_warned: bool,        // a file without `:version` was already reported
+    line_offset: usize,          // number of lines before the one being parsed in its file
+}
+
+/// Config file read while loading the configuration, see `Rrr::config_files`.
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    pub file: String, // path of the file, `<command>` for the output of `:include-cmd`
+    pub depth: usize, // number of includes leading to this file, 0 for a main config
+    pub duplicate: bool, // the file was already loaded, it was skipped this time
+    pub profiles: Vec<String>, // profiles the file added rules or aliases to, in order
+}
+
+/// A macro defined with `:macro`, its body is a config line where `%(param)` are substituted.
+struct Macro {
+    params: Vec<String>,
+    body: String,
 }
 
 pub struct Rrr {
-    profiles: HashMap<ProfileIdentifier, RuleSet>,
+    profiles: HashMap<ProfileIdentifier, LazyRuleSet>,
+    settings: Settings,
+    substitutions: Arc<Substitutions>, // custom tokens, given to the rules of each built profile
+    config_files: Vec<LoadedConfig>,
 }
 
+/// Rule set that is only built (i.e. its patterns compiled) the first time it is needed.
+struct LazyRuleSet {
+    builder: Mutex<Option<RuleSetBuilder>>, // locked while building, so it is only built once
+    rule_set: OnceLock<RuleSet>,
+}
+
+// Rrr is shared between threads when embedded (e.g. behind an Arc in a server)
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Rrr>();
+};
+
+/// Configuration compiled into rrr, used when no configuration file is found.
+pub const DEFAULT_CONFIG: &str = include_str!("../docs/default.conf");
+
+/// Latest syntax version of the config files.
+const CONFIG_VERSION: u32 = 2;
+
+/**
+  Syntax version of the config files not declaring any, kept to the oldest one until the files
+  had time to declare theirs (a warning asks for it).
+*/
+const UNDECLARED_CONFIG_VERSION: u32 = 1;
+
+/// Number of lines between two progress reports while parsing a config.
+const PROGRESS_LINES: usize = 10_000;
+
+/// Maximum depth of macros calling other macros.
+const MAX_MACRO_DEPTH: usize = 16;
+
+/// Reference to a parameter in the body of a macro, e.g. `%(ext)`.
+static MACRO_PARAM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"%\(([A-Za-z0-9_-]+)\)").unwrap());
+
+/// Directives, used to suggest the intended one for an invalid meta.
+const DIRECTIVES: [&str; 19] = [
+    ":include",
+    ":include-cmd",
+    ":import",
+    ":import-launchservices",
+    ":profile",
+    ":profile-if",
+    ":set",
+    ":require",
+    ":os",
+    ":host",
+    ":end",
+    ":deny",
+    ":default-action",
+    ":retry",
+    ":success-codes",
+    ":macro",
+    ":matcher-cmd",
+    ":matcher-plugin",
+    ":version",
+];
+
+/// Operating systems that `:os` accepts, as named by `std::env::consts::OS`.
+const OPERATING_SYSTEMS: [&str; 10] = [
+    "linux",
+    "macos",
+    "freebsd",
+    "openbsd",
+    "netbsd",
+    "dragonfly",
+    "solaris",
+    "illumos",
+    "android",
+    "windows",
+];
+
+/// Rule options, used to suggest the intended one for an unknown option.
+const RULE_OPTIONS: [&str; 15] = [
+    "expand",
+    "env-clean",
+    "raw",
+    "argv",
+    "confirm",
+    "systemd",
+    "quiet",
+    "nocase",
+    "hours",
+    "days",
+    "fetch",
+    "score",
+    "if",
+    "script",
+    "plugin",
+];
+
 #[derive(Parser)]
 #[grammar = "config.pest"]
 struct ConfigParser;
 
 impl Rrr {
+    /// Settings as they were at the end of the configuration.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /**
+    Config files read while loading the configuration, in load order, including the ones
+    skipped as they were already loaded (e.g. included twice).
+    */
+    pub fn config_files(&self) -> &[LoadedConfig] {
+        &self.config_files
+    }
+
+    /// Identifiers of the loaded profiles, sorted alphabetically.
+    pub fn profiles(&self) -> Vec<&str> {
+        let mut profiles: Vec<&str> = self.profiles.keys().map(|p| p.as_str()).collect();
+        profiles.sort();
+        profiles
+    }
+
+    /// Identifiers of the aliases of the loaded profiles (e.g. `browser` for `[browser]`), sorted.
+    pub fn aliases(&self) -> Vec<String> {
+        let mut aliases: Vec<String> = vec![];
+        for lazy_rule_set in self.profiles.values() {
+            aliases.extend(lazy_rule_set.aliases());
+        }
+        aliases.sort();
+        aliases.dedup();
+        aliases
+    }
+
+    /**
+      Tags of the tokens the actions can be substituted with, sorted: the built-in ones and the
+      custom ones (e.g. `%{git-root}`). The captures, environment variables and URL components
+      (e.g. `%1`, `%{ENV:HOME}` or `%{host}`) depend on the rule or the input, they are left out.
+    */
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = ["%s", "%F", "%@", "%x", "%profile", "%origin"]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect();
+        let custom_tags = self
+            .substitutions
+            .names()
+            .map(|name| format!("%{{{}}}", name));
+        tags.extend(custom_tags);
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
     pub fn profile(&self, profile_identifier: &str) -> Result<&RuleSet> {
         /* todo: we have a mismatch here between ProfileIdentifier, &ProfileIdentifier (=&String)
            and &str -> we should get our story straight
         */
-        Ok(self
-            .profiles
+        self.profiles
             .get(profile_identifier)
-            .ok_or_else(|| anyhow!("Profile '{}' does not exist", profile_identifier))?)
+            .ok_or_else(|| RrrError::UnknownProfile(profile_identifier.to_string()))?
+            .get(&self.settings, &self.substitutions)
+    }
+}
+
+impl LazyRuleSet {
+    fn new(builder: RuleSetBuilder) -> Self {
+        Self {
+            builder: Mutex::new(Some(builder)),
+            rule_set: OnceLock::new(),
+        }
+    }
+
+    fn get(&self, settings: &Settings, substitutions: &Arc<Substitutions>) -> Result<&RuleSet> {
+        if let Some(rule_set) = self.rule_set.get() {
+            return Ok(rule_set);
+        }
+
+        let mut builder = self.builder.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(rule_set) = self.rule_set.get() {
+            return Ok(rule_set); // built by another thread while waiting for the lock
+        }
+        let builder = builder
+            .take()
+            .ok_or_else(|| RrrError::invalid("Profile could not be built previously"))?;
+        let rule_set = builder.build(settings, substitutions)?;
+        Ok(self.rule_set.get_or_init(|| rule_set))
+    }
+
+    /// Identifiers of the aliases of the profile, without building it.
+    fn aliases(&self) -> Vec<String> {
+        if let Some(rule_set) = self.rule_set.get() {
+            return rule_set.aliases().map(str::to_string).collect();
+        }
+        let builder = self.builder.lock().unwrap_or_else(PoisonError::into_inner);
+        match (&*builder, self.rule_set.get()) {
+            (Some(builder), _) => builder.aliases().map(str::to_string).collect(),
+            (None, Some(rule_set)) => rule_set.aliases().map(str::to_string).collect(),
+            (None, None) => vec![], // failed to build
+        }
     }
 }
 
@@ -55,76 +287,306 @@ impl RrrBuilder {
         profiles outside this list will be ignored. Specifying `None` here will load all profiles.
     */
     pub fn new(case_insensitive: bool, only_profiles: Option<Vec<String>>) -> Self {
-        let profiles = RefCell::new(HashMap::from([(
-            "default".to_string(),
-            RuleSetBuilder::new("default".to_string(), case_insensitive),
-        )]));
+        let mut profiles = HashMap::new();
+        if only_profiles
+            .as_ref()
+            .is_none_or(|p| p.iter().any(|p| p == "default"))
+        {
+            profiles.insert(
+                "default".to_string(),
+                RuleSetBuilder::new("default".to_string(), case_insensitive),
+            );
+        }
         Self {
-            profiles,
+            profiles: RefCell::new(profiles),
             current_profile: "default".to_string(),
+            profile_skipped: false,
             loaded_config_files: HashSet::new(),
+            loaded_config_commands: HashSet::new(),
+            config_files: RefCell::new(vec![]),
+            config_stack: vec![],
             case_insensitive,
             only_profiles,
+            settings: Settings::default(),
+            guards: vec![],
+            plugins: RefCell::new(HashMap::new()),
+            substitutions: Substitutions::default(),
+            macros: HashMap::new(),
+            macro_depth: 0,
+            retry: RetryPolicy::default(),
+            config_version: None,
+            version_warned: false,
+            line_offset: 0,
+            success_codes: vec![],
+        }
+    }
+
+    /**
+    Register a custom substitution token `%{name}` whose value is computed from the input
+    by the given function, e.g. `%{git-root}`. It is substituted in the actions like the
+    captures (i.e. quoted, or as a separate argument with the raw and argv options).
+    */
+    pub fn substitution<F>(mut self, name: &str, substitution: F) -> Self
+    where
+        F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+    {
+        self.add_substitution(name, substitution);
+        self
+    }
+
+    /// Same as `substitution`, without consuming the builder.
+    pub fn add_substitution<F>(&mut self, name: &str, substitution: F)
+    where
+        F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+    {
+        self.substitutions.insert(name, Arc::new(substitution));
+    }
+
+    /**
+    Add a profile whose rule set was built in code, without any config file (see
+    `RuleSetBuilder::add_rule`). It replaces the profile of the same name, if any.
+    */
+    pub fn with_profile(mut self, rule_set_builder: RuleSetBuilder) -> Self {
+        self.add_profile(rule_set_builder);
+        self
+    }
+
+    /// Same as `with_profile`, without consuming the builder.
+    pub fn add_profile(&mut self, rule_set_builder: RuleSetBuilder) {
+        let profile = rule_set_builder.profile().to_string();
+        if let Some(only_profiles) = &self.only_profiles
+            && !only_profiles.contains(&profile)
+        {
+            return;
         }
+        self.profiles.get_mut().insert(profile, rule_set_builder);
     }
 
     /// Parse a config file. Include are loaded recursively.
     pub fn config(mut self, file_path: &Path) -> Result<Self> {
+        self.load_config(file_path)?;
+        Ok(self)
+    }
+
+    /**
+    Same as `config`, without consuming the builder, e.g. to load configs conditionally in a loop.
+    On error, the builder keeps what was loaded before the invalid line.
+    */
+    pub fn load_config(&mut self, file_path: &Path) -> Result<()> {
         // ensure we always talk about the same absolute path
         let file_path = file_path.canonicalize()?;
 
         // avoid loading the same path twice
         if self.loaded_config_files.contains(&file_path) {
-            return Ok(self);
+            self.record_config_file(&file_path, true);
+            return Ok(());
         }
 
         // mark config file as visited
         self.loaded_config_files.insert(file_path.clone());
 
         // load config file
-        let input = fs::read_to_string(&file_path)?;
-        let file = ConfigParser::parse(Rule::file, &input)?.next().unwrap();
+        let file = fs::File::open(&file_path)?;
+        self.parse_config(&file_path, io::BufReader::new(file))
+    }
+
+    /// Load the configuration compiled into rrr (`DEFAULT_CONFIG`), its rules refer to `<default>`.
+    pub fn load_default_config(&mut self) -> Result<()> {
+        self.parse_config(Path::new("<default>"), DEFAULT_CONFIG.as_bytes())
+    }
+
+    /**
+    Parse a config file (or the output of a command) line by line, so that large configs (e.g.
+    generated ones) are never held in memory as a whole.
+    */
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(file = %file_path.display()), err)
+    )]
+    fn parse_config(&mut self, file_path: &Path, reader: impl BufRead) -> Result<()> {
+        let index = self.record_config_file(file_path, false);
+        self.config_stack.push(index);
+        let guards_depth = self.guards.len();
+        let config_version = self.config_version.take();
+        let line_offset = self.line_offset;
+        let mut result = Ok(());
+        for (index, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    result = Err(e).with_context(|| format!("cannot read line {}", index + 1));
+                    break;
+                }
+            };
+            self.line_offset = index;
+            if let Err(e) = self.parse_config_line(file_path, &line) {
+                if !self.settings.lenient {
+                    result = Err(e);
+                    break;
+                }
+                warn!("skipping invalid line: {}", e.report());
+            }
+            if (index + 1) % PROGRESS_LINES == 0 {
+                info!("parsed {} lines of '{}'", index + 1, file_path.display());
+            }
+        }
+
+        if self.config_version.is_none() && !self.version_warned {
+            warn!(
+                "'{}' does not declare its config version, it is parsed as version {}: add ':version {}' to it once its actions are written for the current syntax",
+                file_path.display(),
+                UNDECLARED_CONFIG_VERSION,
+                CONFIG_VERSION
+            );
+            self.version_warned = true;
+        }
+
+        // blocks left open are closed at the end of the file
+        self.guards.truncate(guards_depth);
+        self.config_version = config_version;
+        self.line_offset = line_offset;
+        self.config_stack.pop();
+
+        result
+    }
+
+    /// Add a config file to those read, return its index.
+    fn record_config_file(&mut self, file_path: &Path, duplicate: bool) -> usize {
+        let config_files = self.config_files.get_mut();
+        config_files.push(LoadedConfig {
+            file: file_path.display().to_string(),
+            depth: self.config_stack.len(),
+            duplicate,
+            profiles: vec![],
+        });
+        config_files.len() - 1
+    }
+
+    /// Parse a single config line, `line_offset` being the number of lines before it.
+    fn parse_config_line(&mut self, file_path: &Path, line: &str) -> Result<()> {
+        let file = ConfigParser::parse(Rule::file, line)
+            .map_err(|e| self.syntax_error(e, file_path))?
+            .next()
+            .unwrap();
         for inner in file.into_inner() {
             if inner.as_rule() == Rule::line {
-                self = self.parse_line(&file_path, inner)?;
+                let span = inner.as_span();
+                self.parse_line(file_path, inner)
+                    .map_err(|e| self.locate(e, file_path, span))?;
             }
         }
+        Ok(())
+    }
 
-        Ok(self)
+    /// Enable the lenient mode from the start, as with `:set lenient`.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.set_lenient(lenient);
+        self
     }
 
-    fn parse_line(self, file: &Path, line: Pair<Rule>) -> Result<Self> {
+    /// Same as `lenient`, without consuming the builder.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.settings.lenient = lenient;
+    }
+
+    /// Make the first matching rule win instead of the last one, as with `:set match-order first`.
+    pub fn first_match(mut self, first_match: bool) -> Self {
+        self.set_first_match(first_match);
+        self
+    }
+
+    /// Same as `first_match`, without consuming the builder.
+    pub fn set_first_match(&mut self, first_match: bool) {
+        self.settings.first_match = first_match;
+    }
+
+    fn parse_line(&mut self, file: &Path, line: Pair<Rule>) -> Result<()> {
         let inner = line.into_inner().next().unwrap(); // meta, alias, invalid, match
+
+        // inside a block whose condition is not met only the block structure matters
+        if !self.is_block_active() && !is_block_directive(&inner) {
+            return Ok(());
+        }
+
+        // aliases and rules of the profiles that are not loaded can be skipped right away
+        if matches!(inner.as_rule(), Rule::alias | Rule::r#match | Rule::matcher)
+            && !self.is_profile_loadable()
+        {
+            return Ok(());
+        }
+
         match inner.as_rule() {
             Rule::meta => {
                 let mut inners = inner.into_inner();
                 let meta = inners.next().unwrap();
-                let target = meta.clone().into_inner().next().unwrap();
+                let target = || meta.clone().into_inner().next().unwrap();
                 match meta.as_rule() {
-                    Rule::include => self.parse_meta_include(file, target),
-                    Rule::import => self.parse_meta_import(file, meta, target),
-                    Rule::profile => self.parse_meta_profile(file, target),
+                    Rule::include => self.parse_meta_include(file, target()),
+                    Rule::include_cmd => self.parse_meta_include_cmd(file, target()),
+                    Rule::import => self.parse_meta_import(file, meta.clone(), target()),
+                    Rule::import_launchservices => {
+                        self.parse_meta_import_launchservices(file, meta)
+                    }
+                    Rule::profile => self.parse_meta_profile(file, target()),
+                    Rule::profile_if => self.parse_meta_profile_if(file, meta),
+                    Rule::set => self.parse_meta_set(file, meta),
+                    Rule::require => self.parse_meta_require(file, target()),
+                    Rule::os => self.parse_meta_os(file, target()),
+                    Rule::host => self.parse_meta_host(file, target()),
+                    Rule::end => self.parse_meta_end(file, meta),
+                    Rule::deny => self.parse_meta_deny(file, target()),
+                    Rule::default_action => self.parse_meta_default_action(file, meta),
+                    Rule::retry => self.parse_meta_retry(file, meta),
+                    Rule::success_codes => self.parse_meta_success_codes(file, meta),
+                    Rule::macro_definition => self.parse_meta_macro(file, meta),
+                    Rule::version => self.parse_meta_version(file, target()),
                     _ => unreachable!(),
                 }
             }
+            Rule::macro_call => self.parse_macro_call(file, inner),
             Rule::alias => {
                 let mut inners = inner.into_inner();
                 let (identifier, target) = (inners.next().unwrap(), inners.next().unwrap());
                 self.parse_alias(file, identifier, target)
             }
-            Rule::r#match => {
+            Rule::r#match | Rule::matcher => {
                 let mut inners = inner.into_inner();
-                let (r#match, target) = (inners.next().unwrap(), inners.next().unwrap());
+                let r#match = inners.next().unwrap();
+                let mut target = inners.next().unwrap();
+                let mut options = None;
+                if target.as_rule() == Rule::rule_options {
+                    options = Some(target);
+                    target = inners.next().unwrap();
+                }
                 if target.as_rule() == Rule::invalid_alias {
-                    return Err(anyhow!("Invalid alias in match '{}'", target.as_str()));
+                    return Err(RrrError::invalid(format!(
+                        "Invalid alias in match '{}'",
+                        target.as_str()
+                    )));
                 }
-                self.parse_match(file, r#match, target)
+                self.parse_match(file, r#match, options, target)
             }
             Rule::invalid => {
                 let inner = inner.into_inner().next().unwrap();
                 match inner.as_rule() {
-                    Rule::invalid_meta => Err(anyhow!("Invalid meta '{}'", inner.as_str())),
-                    Rule::invalid_alias => Err(anyhow!("Invalid alias '{}'", inner.as_str())),
+                    Rule::invalid_meta => {
+                        let directive = inner.as_str().split_whitespace().next().unwrap_or("");
+                        if DIRECTIVES.contains(&directive) {
+                            return Err(RrrError::invalid(format!(
+                                "Invalid arguments for '{}'",
+                                directive
+                            )));
+                        }
+                        Err(RrrError::invalid(format!(
+                            "Invalid meta '{}'{}",
+                            inner.as_str(),
+                            hint(directive, &DIRECTIVES)
+                        )))
+                    }
+                    Rule::invalid_alias => Err(RrrError::invalid(format!(
+                        "Invalid alias '{}'",
+                        inner.as_str()
+                    ))),
                     _ => unreachable!(),
                 }
             }
@@ -132,66 +594,131 @@ impl RrrBuilder {
         }
     }
 
-    fn parse_meta_include(self, file: &Path, target: Pair<Rule>) -> Result<Self> {
+    fn parse_meta_include(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
         let target = parse_string(target)?;
         let path = expand(&target)?;
-        self.parse_meta_include_rec(file, &path)
+        self.parse_meta_include_rec(&path)
     }
 
-    fn parse_meta_include_rec(
-        mut self,
-        orig_config_file: &Path,
-        target_path: &Path,
-    ) -> Result<Self> {
+    fn parse_meta_include_rec(&mut self, target_path: &Path) -> Result<()> {
         let context = || format!("including '{}'", target_path.display());
 
         let metadata = target_path.metadata().with_context(context)?;
         if metadata.is_file() {
-            self = self.config(target_path).with_context(context)?;
-        } else if metadata.is_dir() {
-            if let Ok(entries) = fs::read_dir(target_path) {
-                for entry in entries.flatten() {
-                    self = self.parse_meta_include_rec(orig_config_file, &entry.path())?;
-                }
+            self.load_config(target_path).with_context(context)?;
+        } else if metadata.is_dir()
+            && let Ok(entries) = fs::read_dir(target_path)
+        {
+            for entry in entries.flatten() {
+                self.parse_meta_include_rec(&entry.path())?;
             }
         }
 
-        Ok(self)
+        Ok(())
+    }
+
+    /// Run a command with the shell and parse its output as config.
+    fn parse_meta_include_cmd(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let command = parse_string(target)?;
+        let context = || format!("including the output of '{}'", command);
+
+        // rules created from the output refer to the command as their file
+        let file = PathBuf::from(format!("<{}>", command));
+
+        // avoid running the same command twice (and looping on commands including themselves)
+        if !self.loaded_config_commands.insert(command.clone()) {
+            self.record_config_file(&file, true);
+            return Ok(());
+        }
+
+        let output = process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(context)?;
+        if !output.status.success() {
+            let error = RrrError::invalid(format!("command failed with {}", output.status));
+            return Err(error).with_context(context);
+        }
+        let input = String::from_utf8(output.stdout)
+            .map_err(|_| RrrError::invalid("output is not UTF-8"))
+            .with_context(context)?;
+
+        self.parse_config(&file, input.as_bytes())
+            .with_context(context)
     }
 
     #[cfg(not(feature = "import"))]
-    fn parse_meta_import(self, file: &Path, target: Pair<Rule>) -> Result<Self> {
-        Err(anyhow!("not compiled with 'import' feature"))
+    fn parse_meta_import(
+        &mut self,
+        _config_file: &Path,
+        _import: Pair<Rule>,
+        _target: Pair<Rule>,
+    ) -> Result<()> {
+        Err(RrrError::NotCompiled("import"))
     }
 
     #[cfg(feature = "import")]
     fn parse_meta_import(
-        self,
+        &mut self,
         config_file: &Path,
         import: Pair<Rule>,
         target: Pair<Rule>,
-    ) -> Result<Self> {
+    ) -> Result<()> {
         if !self.is_profile_loadable() {
-            return Ok(self);
+            return Ok(());
         }
 
-        let mut rule_set_builder = self.current_profile();
-        let config_origin = token_to_config_origin(config_file, &import);
+        let config_origin = self.token_to_config_origin(config_file, &import);
+        let options = self.parse_rule_options(config_file, None)?;
 
         let target = parse_string(target)?;
         let path = expand(&target)?;
-        self.parse_meta_import_rec(&mut rule_set_builder, &config_origin, config_file, &path)?;
+
+        // scanning large directories of desktop files is slow, reuse the previous scan if possible
+        let path = path.canonicalize().unwrap_or(path);
+        let fingerprint = self
+            .settings
+            .import_cache
+            .then(|| import_cache::fingerprint(&path));
+        let cached = fingerprint.and_then(|f| import_cache::load(&path, f));
+        let imported_rules = match cached {
+            Some(imported_rules) => imported_rules,
+            None => {
+                let mut imported_rules = vec![];
+                Self::parse_meta_import_rec(&mut imported_rules, &path)?;
+                if let Some(fingerprint) = fingerprint {
+                    import_cache::store(&path, fingerprint, &imported_rules);
+                }
+                imported_rules
+            }
+        };
+        info!(
+            "imported {} rule(s) from '{}'",
+            imported_rules.len(),
+            path.display()
+        );
+
+        let mut rule_set_builder = self.current_profile();
+        for imported_rule in imported_rules {
+            rule_set_builder.rule_with_imported_command(
+                &config_origin,
+                &imported_rule.desktop_file,
+                Pattern::Glob(imported_rule.glob),
+                imported_rule.command,
+                options.clone(),
+            );
+        }
         drop(rule_set_builder);
 
-        Ok(self)
+        Ok(())
     }
 
     #[cfg(feature = "import")]
     fn parse_meta_import_rec(
-        &self,
-        rule_set_builder: &mut RefMut<'_, RuleSetBuilder>,
-        config_origin: &ConfigOrigin,
-        config_file: &Path,
+        imported_rules: &mut Vec<import_cache::ImportedRule>,
         target_path: &Path,
     ) -> Result<()> {
         let context = || format!("importing '{}'", target_path.display());
@@ -199,75 +726,517 @@ impl RrrBuilder {
         let metadata = target_path.metadata().with_context(context)?;
         if metadata.is_file() && target_path.extension().and_then(|s| s.to_str()) == Some("desktop")
         {
-            rule_set_builder
-                .rule_with_import(&config_origin, target_path, true)
-                .with_context(|| format!("importing '{}'", target_path.display()))?;
-        } else if metadata.is_dir() {
-            if let Ok(entries) = fs::read_dir(target_path) {
-                for entry in entries.flatten() {
-                    self.parse_meta_import_rec(
-                        rule_set_builder,
-                        config_origin,
-                        config_file,
-                        &entry.path(),
-                    )?;
-                }
+            let desktop_file = target_path.to_string_lossy();
+            let rules = rule_set::desktop_file_rules(target_path, true).with_context(context)?;
+            for (glob, command) in rules {
+                imported_rules.push(import_cache::ImportedRule {
+                    desktop_file: desktop_file.to_string(),
+                    glob,
+                    command,
+                });
+            }
+        } else if metadata.is_dir()
+            && let Ok(entries) = fs::read_dir(target_path)
+        {
+            for entry in entries.flatten() {
+                Self::parse_meta_import_rec(imported_rules, &entry.path())?;
             }
         }
 
         Ok(())
     }
 
-    fn parse_meta_profile(mut self, _file: &Path, target: Pair<Rule>) -> Result<Self> {
+    #[cfg(not(target_os = "macos"))]
+    fn parse_meta_import_launchservices(
+        &mut self,
+        config_file: &Path,
+        import: Pair<Rule>,
+    ) -> Result<()> {
+        // a no-op outside of macOS, so that the same configuration can be shared
+        let config_origin = self.token_to_config_origin(config_file, &import);
+        debug!(
+            "ignoring ':import-launchservices' at {} (not on macOS)",
+            config_origin
+        );
+        Ok(())
+    }
+
+    #[cfg(all(target_os = "macos", not(feature = "launchservices")))]
+    fn parse_meta_import_launchservices(
+        &mut self,
+        _config_file: &Path,
+        _import: Pair<Rule>,
+    ) -> Result<()> {
+        Err(RrrError::NotCompiled("launchservices"))
+    }
+
+    #[cfg(all(target_os = "macos", feature = "launchservices"))]
+    fn parse_meta_import_launchservices(
+        &mut self,
+        config_file: &Path,
+        import: Pair<Rule>,
+    ) -> Result<()> {
+        if !self.is_profile_loadable() {
+            return Ok(());
+        }
+
+        let config_origin = self.token_to_config_origin(config_file, &import);
+        let options = self.parse_rule_options(config_file, None)?;
+        let launchservices_rules =
+            launchservices::launchservices_rules().context("importing LaunchServices")?;
+
+        let mut rule_set_builder = self.current_profile();
+        for launchservices_rule in launchservices_rules {
+            rule_set_builder.rule_with_imported_command(
+                &config_origin,
+                &launchservices_rule.app,
+                Pattern::Glob(launchservices_rule.glob),
+                launchservices_rule.command,
+                options.clone(),
+            );
+        }
+        drop(rule_set_builder);
+
+        Ok(())
+    }
+
+    fn parse_meta_profile(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
         let target = parse_string(target)?;
-        self.profiles
-            .borrow_mut()
-            .entry(target.clone())
-            .or_insert(RuleSetBuilder::new(
-                target.to_string(),
-                self.case_insensitive,
-            ));
-        self.current_profile = target;
-        Ok(self)
+        self.current_profile = target.clone();
+        self.profile_skipped = false;
+
+        // nothing will be stored for the profiles that are not loaded
+        if self.is_profile_loadable() {
+            self.profiles
+                .borrow_mut()
+                .entry(target.clone())
+                .or_insert(RuleSetBuilder::new(target, self.case_insensitive));
+        }
+        Ok(())
+    }
+
+    fn parse_meta_profile_if(&mut self, file: &Path, profile_if: Pair<Rule>) -> Result<()> {
+        let mut inners = profile_if.into_inner();
+        let condition = parse_string(inners.next().unwrap())?;
+        let target = inners.next().unwrap();
+        if is_requirement_met(&condition) {
+            return self.parse_meta_profile(file, target);
+        }
+
+        // the profile is not created, unless some lines are declared for it elsewhere
+        self.current_profile = parse_string(target)?;
+        self.profile_skipped = true;
+        debug!(
+            "skipping profile '{}': '{}' not met",
+            self.current_profile, condition
+        );
+        Ok(())
+    }
+
+    fn parse_meta_set(&mut self, _file: &Path, set: Pair<Rule>) -> Result<()> {
+        let mut inners = set.into_inner();
+        let name = inners.next().unwrap().as_str();
+        let value = inners.next().map(parse_string).transpose()?;
+        self.settings.set(name, value.as_deref())?;
+        Ok(())
+    }
+
+    fn parse_meta_require(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let requirement = parse_string(target)?;
+        self.guards.push(is_requirement_met(&requirement));
+        Ok(())
+    }
+
+    fn parse_meta_os(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let systems = parse_string(target)?;
+        let mut met = false;
+        for os in systems.split('|').map(str::trim) {
+            if !OPERATING_SYSTEMS.contains(&os) {
+                return Err(RrrError::invalid(format!(
+                    "Unknown operating system '{}'{}",
+                    os,
+                    hint(os, &OPERATING_SYSTEMS)
+                )));
+            }
+            met |= os == env::consts::OS;
+        }
+        self.guards.push(met);
+        Ok(())
+    }
+
+    fn parse_meta_host(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let pattern = parse_string(target)?;
+        let glob = GlobBuilder::new(&pattern)
+            .case_insensitive(true) // hostnames are case insensitive
+            .build()
+            .map_err(|e| RrrError::InvalidPattern {
+                pattern: pattern.clone(),
+                reason: e.to_string(),
+            })?
+            .compile_matcher();
+
+        // the glob can match either the full hostname or its first label (e.g. 'laptop.local')
+        let hostname = utils::hostname()?;
+        let short_name = hostname.split('.').next().unwrap_or_default();
+        self.guards
+            .push(glob.is_match(&hostname) || glob.is_match(short_name));
+        Ok(())
+    }
+
+    fn parse_meta_end(&mut self, file: &Path, end: Pair<Rule>) -> Result<()> {
+        if self.guards.pop().is_none() {
+            let origin = self.token_to_config_origin(file, &end);
+            return Err(RrrError::invalid(format!(
+                "':end' without any opened block at {}",
+                origin
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_meta_deny(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let pattern = parse_string(target)?;
+        let re = Regex::new(&pattern).map_err(|e| RrrError::InvalidPattern {
+            pattern: pattern.clone(),
+            reason: e.to_string(),
+        })?;
+        self.settings.deny_patterns.push(re);
+        Ok(())
+    }
+
+    fn parse_meta_default_action(&mut self, file: &Path, default_action: Pair<Rule>) -> Result<()> {
+        if !self.is_profile_loadable() {
+            return Ok(());
+        }
+
+        let mut options = self.parse_rule_options(file, None)?;
+        let config_origin = self.token_to_config_origin(file, &default_action);
+        let target = default_action.into_inner().next().unwrap();
+        let action = if target.as_rule() == Rule::alias_identifier {
+            Action::Alias(target.as_str().to_string())
+        } else {
+            let mut action = parse_string(target)?;
+            // '@' is a shorthand for the raw option
+            if let Some(raw_action) = action.strip_prefix('@') {
+                action = raw_action.to_string();
+                options.raw = true;
+            }
+            Action::Command(self.migrate_action(&config_origin, action))
+        };
+
+        self.current_profile()
+            .default_action(config_origin, action, options);
+        Ok(())
+    }
+
+    fn parse_meta_retry(&mut self, _file: &Path, retry: Pair<Rule>) -> Result<()> {
+        let mut inners = retry.into_inner();
+        let retries = settings::parse_number(":retry", Some(inners.next().unwrap().as_str()))?;
+        let backoff = inners
+            .next()
+            .map(|backoff| settings::parse_duration("backoff", Some(backoff.as_str())))
+            .transpose()?
+            .unwrap_or(Duration::from_secs(1));
+        self.retry = RetryPolicy { retries, backoff };
+        Ok(())
+    }
+
+    fn parse_meta_success_codes(&mut self, _file: &Path, success_codes: Pair<Rule>) -> Result<()> {
+        let codes = success_codes
+            .into_inner()
+            .next()
+            .map(parse_string)
+            .transpose()?;
+        self.success_codes = settings::parse_list(codes.as_deref())
+            .iter()
+            .map(|code| settings::parse_number(":success-codes", Some(code)))
+            .collect::<Result<_>>()?;
+        Ok(())
     }
 
-    fn parse_alias(self, _file: &Path, identifier: Pair<Rule>, target: Pair<Rule>) -> Result<Self> {
+    fn parse_meta_macro(&mut self, _file: &Path, definition: Pair<Rule>) -> Result<()> {
+        let mut inners = definition.into_inner();
+        let name = inners.next().unwrap().as_str().to_string();
+        let mut params = vec![];
+        let mut body = String::new();
+        for inner in inners {
+            match inner.as_rule() {
+                Rule::macro_param => params.push(inner.as_str().to_string()),
+                _ => body = inner.as_str().to_string(),
+            }
+        }
+
+        // report the unknown parameters now rather than on each call
+        for captures in MACRO_PARAM_RE.captures_iter(&body) {
+            if !params.iter().any(|p| *p == captures[1]) {
+                return Err(RrrError::invalid(format!(
+                    "Unknown parameter '{}' in macro '{}'",
+                    &captures[1], name
+                )));
+            }
+        }
+
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    fn parse_meta_version(&mut self, _file: &Path, target: Pair<Rule>) -> Result<()> {
+        let version = target.as_str().parse().unwrap_or(u32::MAX);
+        if version == 0 || version > CONFIG_VERSION {
+            return Err(RrrError::invalid(format!(
+                "Unsupported config version {} (latest is {}), rrr should be upgraded",
+                target.as_str(),
+                CONFIG_VERSION
+            )));
+        }
+        self.config_version = Some(version);
+        Ok(())
+    }
+
+    /**
+      Action written in the syntax of the config version of the file, in the current syntax.
+      Before version 2 only `%s` and `%1` to `%9` were tokens, so every other percent (e.g. in
+      `%F`, `%%` or `%{ENV:HOME}`) is escaped. A capture followed by
+      a digit (e.g. `%10`) cannot be migrated, it is now a capture with several digits.
+    */
+    fn migrate_action(&self, config_origin: &ConfigOrigin, action: String) -> String {
+        if self.config_version.unwrap_or(UNDECLARED_CONFIG_VERSION) >= 2 {
+            return action;
+        }
+
+        let mut migrated = String::with_capacity(action.len());
+        let mut chars = action.chars().peekable();
+        while let Some(c) = chars.next() {
+            migrated.push(c);
+            if c != '%' {
+                continue;
+            }
+            match chars.next_if(|c| matches!(c, 's' | '1'..='9')) {
+                Some(digit @ '1'..='9') => {
+                    migrated.push(digit);
+                    if let Some(next) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                        warn!(
+                            "'%{}{}' at {} was capture {} followed by '{}' before config version 2, it is now capture {}{}",
+                            digit, next, config_origin, digit, next, digit, next
+                        );
+                    }
+                }
+                Some(token) => migrated.push(token),
+                None => migrated.push('%'), // literal percent
+            }
+        }
+        if migrated != action {
+            debug!(
+                "migrated action '{}' at {} to '{}'",
+                action, config_origin, migrated
+            );
+        }
+        migrated
+    }
+
+    fn parse_macro_call(&mut self, file: &Path, call: Pair<Rule>) -> Result<()> {
+        let mut inners = call.into_inner();
+        let name = inners.next().unwrap().as_str().to_string();
+        let args = inners
+            .map(|arg| match arg.as_rule() {
+                Rule::macro_plain_arg => Ok(arg.as_str().trim_end().to_string()),
+                _ => parse_string(arg),
+            })
+            .collect::<Result<Vec<String>>>()?;
+
+        let r#macro = self.macros.get(&name).ok_or_else(|| {
+            let names: Vec<&str> = self.macros.keys().map(|n| n.as_str()).collect();
+            RrrError::invalid(format!("Unknown macro '{}'{}", name, hint(&name, &names)))
+        })?;
+        if args.len() != r#macro.params.len() {
+            return Err(RrrError::invalid(format!(
+                "Macro '{}' expects {} arguments, got {}",
+                name,
+                r#macro.params.len(),
+                args.len()
+            )));
+        }
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            return Err(RrrError::invalid(format!(
+                "Too many nested macro calls in '{}'",
+                name
+            )));
+        }
+
+        // the expansion is parsed at the line of the call so that its rules report this line
+        let expansion = MACRO_PARAM_RE
+            .replace_all(&r#macro.body, |captures: &regex::Captures| {
+                let index = r#macro.params.iter().position(|p| *p == captures[1]);
+                args[index.expect("macro parameters are checked on definition")].clone()
+            })
+            .into_owned();
+
+        let context = || format!("expanding macro '{}'", name);
+        self.macro_depth += 1;
+        let result = self
+            .parse_config_line(file, &expansion)
+            .with_context(context);
+        self.macro_depth -= 1;
+
+        result
+    }
+
+    fn parse_alias(
+        &mut self,
+        file: &Path,
+        identifier: Pair<Rule>,
+        target: Pair<Rule>,
+    ) -> Result<()> {
         if !self.is_profile_loadable() {
-            return Ok(self);
+            return Ok(());
         }
 
+        let config_origin = self.token_to_config_origin(file, &identifier);
+        let action = self.migrate_action(&config_origin, parse_string(target)?);
         let mut rule_set_builder = self.current_profile();
-        let action = parse_string(target)?;
 
         rule_set_builder.alias(identifier.as_str().to_string(), action);
         drop(rule_set_builder);
 
-        Ok(self)
+        Ok(())
     }
 
-    fn parse_match(self, file: &Path, r#match: Pair<Rule>, target: Pair<Rule>) -> Result<Self> {
+    fn parse_match(
+        &mut self,
+        file: &Path,
+        r#match: Pair<Rule>,
+        options: Option<Pair<Rule>>,
+        target: Pair<Rule>,
+    ) -> Result<()> {
         if !self.is_profile_loadable() {
-            return Ok(self);
+            return Ok(());
         }
 
+        let mut options = self.parse_rule_options(file, options)?;
+        let config_origin = self.token_to_config_origin(file, &r#match);
+        let pattern = if r#match.as_rule() == Rule::matcher_plugin_match {
+            let name = parse_string(r#match.clone().into_inner().next().unwrap())?;
+            Pattern::Plugin(self.plugin(&name)?)
+        } else {
+            match match_token_to_pattern(&r#match)? {
+                Pattern::Glob(glob) if options.extended_glob => {
+                    Pattern::Glob(rule_set::extended_glob(&glob)?)
+                }
+                pattern => pattern,
+            }
+        };
         let mut rule_set_builder = self.current_profile();
-        let config_origin = token_to_config_origin(file, &r#match);
-        let pattern = match_token_to_pattern(&r#match);
 
         if target.as_rule() == Rule::alias_identifier {
             let alias_identifier = target.as_str().to_string();
-            rule_set_builder.rule_with_alias(config_origin, pattern, alias_identifier)?;
+            rule_set_builder.rule_with_alias(config_origin, pattern, alias_identifier, options)?;
         } else {
-            let action = parse_string(target)?;
-            rule_set_builder.rule_with_command(config_origin, pattern, action);
+            let mut action = parse_string(target)?;
+            // '@' is a shorthand for the raw option
+            if let Some(raw_action) = action.strip_prefix('@') {
+                action = raw_action.to_string();
+                options.raw = true;
+            }
+            let action = self.migrate_action(&config_origin, action);
+            rule_set_builder.rule_with_command(config_origin, pattern, action, options);
         }
         drop(rule_set_builder);
 
-        Ok(self)
+        Ok(())
+    }
+
+    /// Rule options defaulting to the current settings, overridden by those specified in the rule.
+    fn parse_rule_options(&self, file: &Path, options: Option<Pair<Rule>>) -> Result<RuleOptions> {
+        let mut rule_options = RuleOptions {
+            expand: self.settings.expand_actions,
+            argv: self.settings.argv_input,
+            extended_glob: self.settings.extended_glob,
+            fancy_regex: self.settings.fancy_regex,
+            retry: self.retry,
+            success_codes: self.success_codes.clone(),
+            fuzzy_score: self.settings.fuzzy_score,
+            content_head_size: self.settings.content_head_size,
+            ..Default::default()
+        };
+
+        for option in options.into_iter().flat_map(|o| o.into_inner()) {
+            let span = option.as_span();
+            let mut inners = option.into_inner();
+            let name = inners.next().unwrap().as_str();
+            let value = inners
+                .next()
+                .map(|v| parse_string(v.into_inner().next().unwrap()))
+                .transpose()?;
+            let value = value.as_deref();
+            match name {
+                "expand" => rule_options.expand = settings::parse_bool(name, value)?,
+                "env-clean" => rule_options.env_clean = settings::parse_bool(name, value)?,
+                "raw" => rule_options.raw = settings::parse_bool(name, value)?,
+                "argv" => rule_options.argv = settings::parse_bool(name, value)?,
+                "confirm" => rule_options.confirm = settings::parse_bool(name, value)?,
+                "systemd" => rule_options.systemd = settings::parse_bool(name, value)?,
+                "quiet" => rule_options.quiet = settings::parse_bool(name, value)?,
+                "nocase" => rule_options.nocase = Some(settings::parse_bool(name, value)?),
+                "hours" => rule_options.schedule.hours = settings::parse_hours(name, value)?,
+                "days" => rule_options.schedule.days = settings::parse_days(name, value)?,
+                "fetch" => rule_options.fetch = settings::parse_bool(name, value)?,
+                "score" => rule_options.fuzzy_score = settings::parse_score(name, value)?,
+                "if" if cfg!(feature = "script") => {
+                    let condition =
+                        value.with_context(|| format!("Missing value for '{}'", name))?;
+                    rule_options.condition = Some(condition.to_string());
+                }
+                "script" if cfg!(feature = "script") => {
+                    rule_options.script = settings::parse_bool(name, value)?
+                }
+                "if" | "script" => {
+                    return Err(RrrError::NotCompiled("script"));
+                }
+                "plugin" => {
+                    let plugin = value.with_context(|| format!("Missing value for '{}'", name))?;
+                    rule_options.plugin = Some(self.plugin(plugin)?);
+                }
+                _ => {
+                    let message = format!(
+                        "Unknown rule option '{}'{}",
+                        name,
+                        hint(name, &RULE_OPTIONS)
+                    );
+                    return Err(self.token_error(file, span, message));
+                }
+            }
+        }
+
+        Ok(rule_options)
+    }
+
+    /// Plugin from the plugin directory, loaded only once.
+    fn plugin(&self, name: &str) -> Result<Arc<Plugin>> {
+        if let Some(plugin) = self.plugins.borrow().get(name) {
+            return Ok(plugin.clone());
+        }
+
+        let plugin_dir = match &self.settings.plugin_dir {
+            Some(plugin_dir) => plugin_dir.clone(),
+            None => plugin::default_dir()?,
+        };
+        let plugin = Arc::new(Plugin::load(&plugin_dir, name)?);
+        self.plugins
+            .borrow_mut()
+            .insert(name.to_string(), plugin.clone());
+        Ok(plugin)
+    }
+
+    /// Check if the conditions of all the enclosing blocks are met.
+    fn is_block_active(&self) -> bool {
+        self.guards.iter().all(|met| *met)
     }
 
     /// Check if we should process the line according to only_profiles.
     fn is_profile_loadable(&self) -> bool {
+        if self.profile_skipped {
+            return false;
+        }
         if let Some(only_profiles) = &self.only_profiles {
             only_profiles.contains(&self.current_profile)
         } else {
@@ -276,59 +1245,175 @@ impl RrrBuilder {
     }
 
     fn current_profile(&self) -> RefMut<'_, RuleSetBuilder> {
+        // the file being parsed contributes to this profile
+        if let Some(&index) = self.config_stack.last() {
+            let profiles = &mut self.config_files.borrow_mut()[index].profiles;
+            if !profiles.contains(&self.current_profile) {
+                profiles.push(self.current_profile.clone());
+            }
+        }
         RefMut::map(self.profiles.borrow_mut(), |m| {
             m.get_mut(&self.current_profile)
                 .expect("Current profile should exist in the list of profiles")
         })
     }
 
+    /// Create the Rrr, the rule set of each profile is only built when it is first used.
     pub fn build(self) -> Result<Rrr> {
-        let rule_sets: Result<HashMap<ProfileIdentifier, RuleSet>> = self
+        let profiles = self
             .profiles
             .into_inner()
             .into_iter()
             .map(|(profile_identifier, rule_set_builder)| {
-                // Result<V> -> Result<(K, V)> otherwise we end up with (K, Result<V>)
-                rule_set_builder
-                    .build()
-                    .map(|rule_set| (profile_identifier, rule_set))
+                (profile_identifier, LazyRuleSet::new(rule_set_builder))
             })
             .collect();
 
         Ok(Rrr {
-            profiles: rule_sets?,
+            profiles,
+            settings: self.settings,
+            substitutions: Arc::new(self.substitutions),
+            config_files: self.config_files.into_inner(),
         })
     }
+
+    /// Syntax error of a config file, with friendlier names for what was expected.
+    fn syntax_error(&self, mut error: PestError<Rule>, file: &Path) -> RrrError {
+        // rules only there to report invalid lines are not worth mentioning
+        if let ErrorVariant::ParsingError { positives, .. } = &mut error.variant {
+            positives.retain(|rule| !matches!(rule, Rule::invalid_alias | Rule::invalid_meta));
+        }
+        let error = error.renamed_rules(|rule| {
+            match rule {
+                Rule::alias_identifier => "an alias (e.g. [browser])",
+                Rule::rule_options => "rule options (e.g. [expand])",
+                Rule::space_string | Rule::nospace_string => "a string",
+                Rule::quoted_string => "a quoted string",
+                Rule::macro_name | Rule::macro_param => "a name",
+                Rule::macro_body => "the body of the macro",
+                Rule::EOI => "the end of the file",
+                _ => return format!("{:?}", rule),
+            }
+            .to_string()
+        });
+        self.parse_error(error, file)
+    }
+
+    /// Error pointing at a token of a config line.
+    fn token_error(&self, file: &Path, span: Span, message: String) -> RrrError {
+        let error = PestError::new_from_span(ErrorVariant::CustomError { message }, span);
+        self.parse_error(error, file)
+    }
+
+    /// Error of a config file, its message shows the line where it happened.
+    fn parse_error(&self, mut error: PestError<Rule>, file: &Path) -> RrrError {
+        // lines are parsed one by one, they are moved back to their place in the file
+        let offset = |(line, column): (usize, usize)| (line + self.line_offset, column);
+        error.line_col = match error.line_col {
+            LineColLocation::Pos(pos) => LineColLocation::Pos(offset(pos)),
+            LineColLocation::Span(start, end) => LineColLocation::Span(offset(start), offset(end)),
+        };
+        let (LineColLocation::Pos((line, column)) | LineColLocation::Span((line, column), _)) =
+            error.line_col;
+        let file = file.display().to_string();
+        RrrError::ParseError {
+            message: error.with_path(&file).to_string(),
+            file,
+            line,
+            column,
+        }
+    }
+
+    /// Show the config line where an error happened, unless it was already located (e.g. in an include).
+    fn locate(&self, error: RrrError, file: &Path, span: Span) -> RrrError {
+        if let RrrError::ParseError { .. } = error.kind() {
+            return error;
+        }
+        self.token_error(file, span, error.report())
+    }
+
+    /// Origin of a token, i.e. its position in the config file.
+    fn token_to_config_origin(&self, file: &Path, r#match: &Pair<Rule>) -> ConfigOrigin {
+        let (line, column) = r#match.as_span().start_pos().line_col();
+        ConfigOrigin {
+            file: file.display().to_string(),
+            line: line + self.line_offset,
+            column,
+        }
+    }
+}
+
+/// Check a requirement (`:require`, `:profile-if`): a non-empty env variable or a program in PATH.
+fn is_requirement_met(requirement: &str) -> bool {
+    match requirement.strip_prefix('$') {
+        Some(var) => env::var_os(var).is_some_and(|value| !value.is_empty()),
+        None => utils::find_executable(requirement).is_some(),
+    }
+}
+
+/// Directives opening or closing a block, they must be processed even in inactive blocks.
+fn is_block_directive(line: &Pair<Rule>) -> bool {
+    line.as_rule() == Rule::meta
+        && line.clone().into_inner().next().is_some_and(|meta| {
+            matches!(
+                meta.as_rule(),
+                Rule::require | Rule::os | Rule::host | Rule::end
+            )
+        })
+}
+
+/// Suggestion for a misspelled name, e.g. " (did you mean ':include'?)".
+fn hint(name: &str, candidates: &[&str]) -> String {
+    match utils::closest(name, candidates) {
+        Some(candidate) => format!(" (did you mean '{}'?)", candidate),
+        None => String::new(),
+    }
 }
 
 fn parse_string(target: Pair<Rule>) -> Result<String> {
     match target.as_rule() {
-        Rule::space_string | Rule::nospace_string => Ok(target.as_str().to_string()),
+        Rule::space_string | Rule::nospace_string | Rule::plain_option_value => {
+            Ok(target.as_str().to_string())
+        }
         Rule::quoted_string => utils::unquote(target.as_str()),
         _ => unreachable!(),
     }
 }
 
-fn match_token_to_pattern(r#match: &Pair<Rule>) -> Pattern {
+fn match_token_to_pattern(r#match: &Pair<Rule>) -> Result<Pattern> {
     // fixme: try to avoid the clone() here, into_inner() forces us to own r#match
     let pattern = r#match.clone().into_inner().next().unwrap();
 
     match r#match.as_rule() {
-        Rule::glob_match => Pattern::Glob(pattern.as_str().to_string()),
-        Rule::regex_match => Pattern::Regex(pattern.as_str().to_string()),
+        Rule::glob_match => Ok(Pattern::Glob(pattern.as_str().to_string())),
+        Rule::regex_match => Ok(Pattern::Regex(pattern.as_str().to_string())),
+        Rule::field_match => {
+            let field = match pattern.as_str() {
+                "mime" => InputField::Mime,
+                "context" => InputField::Context,
+                _ => unreachable!(),
+            };
+            let glob = r#match.clone().into_inner().nth(1).unwrap();
+            Ok(Pattern::Field(field, glob.as_str().to_string()))
+        }
+        Rule::exact_match => Ok(Pattern::Exact(pattern.as_str().to_string())),
+        Rule::affix_match => {
+            let literal = r#match.clone().into_inner().nth(1).unwrap();
+            let literal = literal.as_str().to_string();
+            match pattern.as_str() {
+                "prefix" => Ok(Pattern::Prefix(literal)),
+                "suffix" => Ok(Pattern::Suffix(literal)),
+                _ => unreachable!(),
+            }
+        }
+        Rule::content_match => Ok(Pattern::Content(pattern.as_str().to_string())),
+        Rule::binary_match => Ok(Pattern::Binary(pattern.as_str().to_string())),
+        Rule::fuzzy_match => Ok(Pattern::Fuzzy(pattern.as_str().to_string())),
+        Rule::matcher_cmd_match => Ok(Pattern::Matcher(parse_string(pattern)?)),
         _ => unreachable!(),
     }
 }
 
-fn token_to_config_origin(file: &Path, r#match: &Pair<Rule>) -> ConfigOrigin {
-    let (line, column) = r#match.as_span().start_pos().line_col();
-    ConfigOrigin {
-        file: file.display().to_string(),
-        line,
-        column,
-    }
-}
-
 /*
 todo: add tests for parsing with static conf file
 - full config with 3 different profiles in addition to default, test all individual ConfigParser
@@ -336,3 +1421,150 @@ todo: add tests for parsing with static conf file
 - test that syntax error are reported
 - test invalid states are reported
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builder with the config parsed from memory, as if read from `test.conf`.
+    fn parse(config: &str) -> Result<RrrBuilder> {
+        let mut builder = RrrBuilder::new(false, None);
+        builder.parse_config(Path::new("test.conf"), config.as_bytes())?;
+        Ok(builder)
+    }
+
+    /// Pattern, action and line of each rule of the default profile, in declaration order.
+    fn rules(config: &str) -> Vec<(String, String, usize)> {
+        let rrr = parse(config).unwrap().build().unwrap();
+        let mut rules: Vec<_> = rrr
+            .profile("default")
+            .unwrap()
+            .rules()
+            .map(|rule| {
+                let action = match &rule.action {
+                    Action::Command(command) => command.clone(),
+                    Action::Alias(alias) => alias.clone(),
+                };
+                let pattern = rule.pattern_as_str().to_string();
+                (pattern, action, rule.config_origin.line)
+            })
+            .collect();
+        rules.sort_by_key(|(_, _, line)| *line);
+        rules
+    }
+
+    /// Line, message and report (with the context of the macro expansions) of the config error.
+    fn error(config: &str) -> (usize, String, String) {
+        let error = parse(config).err().expect("the config should be invalid");
+        let RrrError::ParseError { file, line, .. } = error.kind() else {
+            panic!("'{}' should be located, got {:?}", config, error);
+        };
+        assert_eq!(file, "test.conf");
+        (*line, error.to_string(), error.report())
+    }
+
+    #[test]
+    fn macro_calls_substitute_the_arguments() {
+        let config = r#"
+:macro viewer(ext, prog) = *.%(ext) %(prog) --fullscreen %s
+:viewer(jpg, feh)
+:viewer( png ,"feh -Z")
+:macro twice(name) = =%(name) echo %(name) %(name)-copy
+:twice(a.txt)
+:macro image(ext) = :viewer(%(ext), sxiv)
+:image(gif)
+"#;
+        let expected = [
+            ("*.jpg", "feh --fullscreen %s", 3),
+            ("*.png", "feh -Z --fullscreen %s", 4),
+            ("a.txt", "echo a.txt a.txt-copy", 6),
+            ("*.gif", "sxiv --fullscreen %s", 8),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(pattern, action, line)| (pattern.to_string(), action.to_string(), line))
+            .collect();
+        assert_eq!(rules(config), expected);
+    }
+
+    #[test]
+    fn macro_calls_are_nested_up_to_the_limit() {
+        // each macro calls the previous one, the last one being called at depth 0
+        let nested = |calls: usize| -> String {
+            let mut config = String::from(":macro m1(x) = *.%(x) open\n");
+            for i in 2..=calls {
+                config.push_str(&format!(":macro m{}(x) = :m{}(%(x))\n", i, i - 1));
+            }
+            config.push_str(&format!(":m{}(txt)\n", calls));
+            config
+        };
+
+        let config = nested(MAX_MACRO_DEPTH);
+        let line = MAX_MACRO_DEPTH + 1;
+        assert_eq!(
+            rules(&config),
+            [("*.txt".to_string(), "open".to_string(), line)]
+        );
+
+        let (line, _, report) = error(&nested(MAX_MACRO_DEPTH + 1));
+        assert_eq!(line, MAX_MACRO_DEPTH + 2);
+        assert!(
+            report.ends_with("= Too many nested macro calls in 'm1'"),
+            "{}",
+            report
+        );
+        let expansions = report.matches("expanding macro").count();
+        assert_eq!(expansions, MAX_MACRO_DEPTH);
+
+        let (line, _, report) = error("\n:macro loop(x) = :loop(%(x))\n:loop(a)\n");
+        assert_eq!(line, 3);
+        assert!(report.starts_with("expanding macro 'loop': "), "{}", report);
+        assert!(
+            report.ends_with("= Too many nested macro calls in 'loop'"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn errors_in_macro_expansions_are_located_at_the_call() {
+        let (line, message, report) =
+            error("*.txt cat %s\n:macro bad(x) = :set %(x)\n\n:bad(unknown-setting)\n");
+        assert_eq!(line, 4);
+        assert_eq!(message, "expanding macro 'bad'");
+        // the line shown is the expansion
+        assert!(report.contains("test.conf:4:1"), "{}", report);
+        assert!(report.contains("4 | :set unknown-setting"), "{}", report);
+        assert!(
+            report.ends_with("= Unknown setting 'unknown-setting'"),
+            "{}",
+            report
+        );
+
+        let (line, _, report) = error(":macro bad(x) = *.%(x) [\n:bad(pdf)\n");
+        assert_eq!(line, 2);
+        assert!(report.contains("2 | *.pdf ["), "{}", report);
+
+        // the call itself is wrong
+        let (line, message, report) = error(":macro v(a, b) = *.%(a) %(b)\n\n:v(pdf)\n");
+        assert_eq!(line, 3);
+        assert!(!message.contains("expanding macro"), "{}", message);
+        assert!(
+            report.ends_with("= Macro 'v' expects 2 arguments, got 1"),
+            "{}",
+            report
+        );
+
+        let (line, _, report) = error(":macro v(a) = *.%(a) open\n:w(pdf)\n");
+        assert_eq!(line, 2);
+        assert!(report.contains("Unknown macro 'w'"), "{}", report);
+
+        let (line, _, report) = error("*.txt cat %s\n:macro v(a) = *.%(a) %(b)\n");
+        assert_eq!(line, 2);
+        assert!(
+            report.ends_with("= Unknown parameter 'b' in macro 'v'"),
+            "{}",
+            report
+        );
+    }
+}
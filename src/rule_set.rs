@@ -1,16 +1,37 @@
 use std::{
-    cell::OnceCell,
+    borrow::Cow,
     collections::HashMap,
+    env, fmt, fs,
+    io::{self, Read, Write},
+    iter, mem, ops,
     os::unix::process::{CommandExt, ExitStatusExt},
-    path::Path,
-    process::Command,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, OnceLock},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Result, anyhow, ensure};
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
-use regex::{RegexBuilder, RegexSet, RegexSetBuilder};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use log::{Level, log, warn};
+use regex::{
+    Regex, RegexBuilder, RegexSet, RegexSetBuilder,
+    bytes::{Regex as BytesRegex, RegexBuilder as BytesRegexBuilder},
+};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, ser::SerializeStruct};
 
+#[cfg(feature = "script")]
+use crate::script;
+pub use crate::utils::{
+    input_from_bytes, input_path, input_to_bytes, input_to_os_string, process_dir,
+};
 use crate::{
+    error::{Context, Result, RrrError},
+    plugin::Plugin,
+    settings::Settings,
     types::{ActionCommand, AliasIdentifier, ProfileIdentifier},
     utils,
 };
@@ -22,28 +43,63 @@ pub struct RuleSetBuilder {
 
     alias: HashMap<AliasIdentifier, ActionCommand>,
 
+    exact_rules: Vec<Rule>,
+    affix_rules: Vec<Rule>,
+    matcher_rules: Vec<Rule>,
     regex_rules: Vec<Rule>,
     glob_rules: Vec<Rule>,
+    default_rule: Option<Rule>, // rule of the inputs matching no other rule (`:default-action`)
+    declarations: usize,        // number of rules added, the declaration index of the next one
 }
 
 /// Contains set of resolved rules that can be matched against an input.
 pub struct RuleSet {
+    exact_literals: HashMap<String, Vec<usize>>, // index in exact_rules by literal (lowercase when case insensitive)
+    affix_literals: Vec<String>, // literal of each affix rule (lowercase when case insensitive)
     regex_set: RegexSet,
+    regex_set_rules: Vec<usize>, // index in regex_rules of each regex in regex_set
+    fancy_regex_rules: Vec<usize>, // index in regex_rules of the regexes compiled with fancy-regex
     glob_set: GlobSet,
+    glob_set_rules: Vec<usize>, // index in glob_rules of each glob in glob_set
+    glob_suffixes: HashMap<String, Vec<usize>>, // index in glob_rules of `*<literal>` globs by extension
+    conditional: bool, // some rules have a condition that must be checked when matching
 
     builder: RuleSetBuilder,
+    settings: Settings,
 }
 
 /// Origin of the rule creation in the config.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct ConfigOrigin {
     pub file: String,
     pub line: usize,
     pub column: usize,
 }
 
+impl ConfigOrigin {
+    /// Origin of a rule added in code, the place of the call in the source.
+    #[track_caller]
+    pub fn caller() -> Self {
+        let location = std::panic::Location::caller();
+        Self {
+            file: location.file().to_string(),
+            line: location.line() as usize,
+            column: location.column() as usize,
+        }
+    }
+}
+
+impl fmt::Display for ConfigOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
 /// Specify if the rule was explicitely stated in config or created from an import.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum RuleOrigin {
     Explicit,         // comes directly from the config
     Imported(String), // created from an imported .desktop file
@@ -51,18 +107,367 @@ pub enum RuleOrigin {
 
 /// Pattern that this rule should match (left part of the rule).
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Pattern {
     Regex(String),
     Glob(String),
+    Matcher(String), // external command deciding if the input matches (`:matcher-cmd`)
+    Plugin(Arc<Plugin>), // WASM plugin deciding if the input matches (`:matcher-plugin`)
+    Field(InputField, String), // glob matching a field of a structured input (e.g. `mime:image/*`)
+    Fuzzy(String),   // text that the input fuzzily contains (e.g. `fuzzy:runrunrun`)
+    Exact(String),   // literal equal to the input (e.g. `=mailto:admin@example.com`)
+    Prefix(String),  // literal starting the input (e.g. `prefix:https://`)
+    Suffix(String),  // literal ending the input (e.g. `suffix:.pdf`)
+    Content(String), // regex on the first bytes of the input file (e.g. `content:^%PDF`)
+    Binary(String),  // executable binaries of these formats, any when empty (e.g. `binary:elf|pe`)
+}
+
+/// Field of a structured input that a rule can target instead of the input itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum InputField {
+    Mime,
+    Context,
+}
+
+/**
+  Fields given along with the input, for callers that already know them. A structured input line
+  is `path<TAB>mime<TAB>context`, where empty or missing fields are unknown.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct InputFields {
+    pub mime: Option<String>,
+    pub context: Option<String>,
+}
+
+/// Fields of an unstructured input.
+static NO_FIELDS: InputFields = InputFields {
+    mime: None,
+    context: None,
+};
+
+/// Compiled regex of a rule, fancy-regex supports lookarounds and backreferences.
+#[derive(Debug)]
+pub enum RuleRegex {
+    Standard(Regex),
+    #[cfg(feature = "fancy")]
+    Fancy(fancy_regex::Regex),
+}
+
+impl InputField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mime => "mime",
+            Self::Context => "context",
+        }
+    }
+}
+
+impl InputFields {
+    /// Split a structured input line into the input itself and its fields.
+    pub fn split(line: &str) -> (&str, Self) {
+        let mut parts = line.split('\t');
+        let input = parts.next().unwrap_or_default();
+        let mut field = || {
+            parts
+                .next()
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+        };
+        let (mime, context) = (field(), field());
+        (input, Self { mime, context })
+    }
+
+    fn get(&self, field: InputField) -> Option<&str> {
+        match field {
+            InputField::Mime => self.mime.as_deref(),
+            InputField::Context => self.context.as_deref(),
+        }
+    }
+}
+
+/**
+  Split a text/uri-list payload (e.g. from drag and drop) into inputs: one URI per line (CRLF
+  or LF separated) and # comments skipped. Local file:// URIs are decoded into their path, the
+  other URIs are left as is.
+*/
+pub fn uri_list(payload: &str) -> Vec<String> {
+    payload
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|uri| {
+            match uri
+                .strip_prefix("file://")
+                .and_then(|rest| rest.strip_prefix("localhost").or(Some(rest)))
+                .filter(|path| path.starts_with('/'))
+            {
+                Some(path) => utils::percent_decode(path),
+                None => uri.to_string(), // remote host or another scheme
+            }
+        })
+        .collect()
 }
 
 /// Type of action associated to the rule (right part of the rule).
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Action {
     Alias(AliasIdentifier), // rule action references an alias
     Command(ActionCommand), // rule action directly reference a command to execute
 }
 
+/// Options of a rule, set in the config between the pattern and the action, e.g. `[expand]`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RuleOptions {
+    pub expand: bool,    // expand tilde and environment variables in the resolved action
+    pub env_clean: bool, // execute the action with a clean environment
+    pub raw: bool,       // execute the action directly, split into arguments, without any shell
+    pub argv: bool,      // pass the input and captures to the shell as positional parameters
+    pub confirm: bool,   // ask for confirmation before executing the action
+    pub systemd: bool,   // execute the action in a transient systemd scope
+    pub condition: Option<String>, // script that must evaluate to true for the rule to match
+    pub script: bool,    // the action is a script evaluating to the command to execute
+    pub quiet: bool,     // log the matches and executions of the rule at a lower level
+    pub extended_glob: bool, // the glob was written with the extended syntax (see `extended_glob`)
+    pub nocase: Option<bool>, // override the case sensitivity of the profile for this rule
+    pub fancy_regex: bool, // compile the regex with fancy-regex instead of matching it in the set
+    pub plugin: Option<Arc<Plugin>>, // WASM plugin substituting the input in the action
+    pub retry: RetryPolicy, // retries of the action when it fails (see `:retry`)
+    pub success_codes: Vec<i32>, // exit codes of a successful action, only 0 when empty
+    pub schedule: Schedule, // hours and days during which the rule can match
+    pub fetch: bool,     // download the remote inputs and run the action on the local copies
+    pub fuzzy_score: f64, // minimum score of the input for a fuzzy pattern
+    pub content_head_size: usize, // bytes of the input file checked by a content pattern
+}
+
+/// Options of a rule that sets none, according to the default settings.
+impl Default for RuleOptions {
+    fn default() -> Self {
+        let settings = Settings::default();
+        Self {
+            expand: settings.expand_actions,
+            env_clean: false,
+            raw: false,
+            argv: settings.argv_input,
+            confirm: false,
+            systemd: false,
+            condition: None,
+            script: false,
+            quiet: false,
+            extended_glob: settings.extended_glob,
+            nocase: None,
+            fancy_regex: settings.fancy_regex,
+            plugin: None,
+            retry: RetryPolicy::default(),
+            success_codes: vec![],
+            schedule: Schedule::default(),
+            fetch: false,
+            fuzzy_score: settings.fuzzy_score,
+            content_head_size: settings.content_head_size,
+        }
+    }
+}
+
+/// Number of times a failed action is retried, waiting `backoff` (doubled each time) in between.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+/// Time of the week during which a rule can match (`hours` and `days` options).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Schedule {
+    pub hours: Vec<(u32, u32)>, // hours from start to end (excluded), possibly over midnight
+    pub days: Vec<(u32, u32)>,  // days from start to end (included) numbered from monday (0)
+}
+
+/// Function computing the value of a custom substitution token (e.g. `%{git-root}`) from the input.
+pub type SubstitutionFn = Arc<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+/// Custom substitution tokens registered through the library API, by name.
+#[derive(Clone, Default)]
+pub struct Substitutions(HashMap<String, SubstitutionFn>);
+
+impl fmt::Debug for Substitutions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+impl Substitutions {
+    /// Register (or replace) the token `%{name}`.
+    pub fn insert(&mut self, name: &str, substitution: SubstitutionFn) {
+        self.0.insert(name.to_string(), substitution);
+    }
+
+    /// Names of the registered tokens, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.0.keys().map(|name| name.as_str())
+    }
+
+    /**
+      Values of the tokens that appear in the action, along with their tag. Besides the custom
+      tokens, these are the lowercase extension of the input (%x), the environment variables
+      (%{ENV:VAR}, or %{ENV:VAR:-default} when unset or empty) and the URL components of the
+      input (%{host}, %{path}, %{query.v}, ...).
+    */
+    fn values(&self, segments: &[Segment], input: &str) -> Result<Vec<(String, String)>> {
+        let mut values: Vec<(String, String)> = vec![];
+        for segment in segments {
+            let Segment::Token(tag) = *segment else {
+                continue;
+            };
+            if values.iter().any(|(t, _)| t == tag) {
+                continue;
+            }
+            let name = tag.strip_prefix("%{").and_then(|tag| tag.strip_suffix('}'));
+            let value = if tag == "%x" {
+                let extension = Path::new(input).extension();
+                Some(extension.map_or(String::new(), |extension| {
+                    extension.to_string_lossy().to_lowercase()
+                }))
+            } else if let Some(substitution) = name.and_then(|name| self.0.get(name)) {
+                // custom tokens take precedence
+                Some(substitution(input).with_context(|| format!("substituting {}", tag))?)
+            } else if let Some(variable) = name.and_then(|name| name.strip_prefix("ENV:")) {
+                let (variable, default) = variable.split_once(":-").unwrap_or((variable, ""));
+                let value = env::var(variable).unwrap_or_default();
+                Some(if value.is_empty() {
+                    default.to_string()
+                } else {
+                    value
+                })
+            } else {
+                name.and_then(|name| utils::url_component(input, name))
+            };
+            if let Some(value) = value {
+                values.push((tag.to_string(), value));
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Part of an action, either literal text or a token substituted with a value of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),    // literal text, an escaped percent (%%) is a single "%"
+    Input,            // %s
+    Files,            // %F
+    Extra,            // %@, the extra arguments given after the inputs
+    Capture(&'a str), // %1, %2, ..., %10, ... (by tag)
+    Token(&'a str),   // %x, %profile, %origin or %{name} (by tag)
+}
+
+impl<'a> Segment<'a> {
+    /// Text of the segment in the action.
+    fn as_str(&self) -> &'a str {
+        match self {
+            Segment::Text(text) | Segment::Capture(text) | Segment::Token(text) => text,
+            Segment::Input => "%s",
+            Segment::Files => "%F",
+            Segment::Extra => "%@",
+        }
+    }
+}
+
+/**
+  Split an action into literal text and tokens, in a single pass so that a value is never
+  substituted again and `%10` is the tenth capture rather than the first one followed by a 0.
+  A percent that does not start a token is kept as literal text.
+*/
+fn segments(action: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut rest = action;
+    while let Some(start) = rest.find('%') {
+        if start > 0 {
+            segments.push(Segment::Text(&rest[..start]));
+        }
+        let tag = &rest[start..];
+        let digits = tag[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(tag.len() - 1);
+        let (segment, len) = match tag.as_bytes().get(1) {
+            Some(b'%') => (Segment::Text("%"), 2),
+            Some(b's') => (Segment::Input, 2),
+            Some(b'F') => (Segment::Files, 2),
+            Some(b'@') => (Segment::Extra, 2),
+            Some(b'x') => (Segment::Token(&tag[..2]), 2),
+            Some(b'p') if tag.starts_with("%profile") => (Segment::Token(&tag[..8]), 8),
+            Some(b'o') if tag.starts_with("%origin") => (Segment::Token(&tag[..7]), 7),
+            Some(b'1'..=b'9') => (Segment::Capture(&tag[..digits + 1]), digits + 1),
+            Some(b'{') => match tag.find('}') {
+                Some(end) => (Segment::Token(&tag[..end + 1]), end + 1),
+                None => (Segment::Text("%"), 1),
+            },
+            _ => (Segment::Text("%"), 1),
+        };
+        segments.push(segment);
+        rest = &tag[len..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    segments
+}
+
+/// Values quoted for the shell and separated by spaces (e.g. the inputs of %F).
+fn quote_all<S: AsRef<str>>(values: &[S]) -> Result<String> {
+    let quoted = values
+        .iter()
+        .map(|value| utils::quote(value.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(quoted.join(" "))
+}
+
+/// Check if the input is substituted somewhere in the action (%s or %F).
+fn has_file_tag(segments: &[Segment]) -> bool {
+    segments
+        .iter()
+        .any(|segment| matches!(segment, Segment::Input | Segment::Files))
+}
+
+/// Join the segments back, substituting the tokens with their value (unchanged without one).
+fn render<'a>(
+    segments: &[Segment<'a>],
+    mut value: impl FnMut(&Segment<'a>) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut rendered = String::new();
+    for segment in segments {
+        match value(segment)? {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(segment.as_str()),
+        }
+    }
+    Ok(rendered)
+}
+
+/// Index in the captures of a capture tag (%1 is the first one).
+fn capture_index(tag: &str) -> Option<usize> {
+    tag[1..].parse::<usize>().ok().map(|i| i - 1)
+}
+
+/// Capture of a capture tag, if the regex has that many.
+fn capture<'c>(captures: &'c [String], tag: &str) -> Option<&'c String> {
+    captures.get(capture_index(tag)?)
+}
+
+/// Value of a custom token or URL component tag.
+fn token<'t>(tokens: &'t [(String, String)], tag: &str) -> Option<&'t String> {
+    tokens
+        .iter()
+        .find(|(t, _)| t == tag)
+        .map(|(_, value)| value)
+}
+
 /**
   A rule that map a matching pattern to an action.
   If this action is an alias they must be resolved into an actual command.
@@ -74,9 +479,16 @@ pub enum Action {
 pub struct Rule {
     pub pattern: Pattern, // pattern that should be matched (left side in config)
     pub action: Action,   // action as specified in the config (right side in config)
-    pub resolved: OnceCell<ActionCommand>, // action with eventual alias resolved
-    pub execution: OnceCell<ActionCommand>, // action substituted and ready for execution
+    pub resolved: OnceLock<ActionCommand>, // action with eventual alias resolved
+    pub regex: Option<RuleRegex>, // compiled regex pattern to extract the captures (regex rules only)
+    pub field_glob: Option<GlobMatcher>, // compiled glob of the field (field rules only)
+    pub content_regex: Option<BytesRegex>, // compiled regex of the file content (content rules only)
     pub case_insensitive: bool,
+    pub options: RuleOptions,
+    pub substitutions: Arc<Substitutions>, // custom tokens substituted in the action
+    pub profile: ProfileIdentifier,        // profile of the rule (%profile in the action)
+    pub declaration: usize, // index of the rule in the order of declaration of its profile
+    pub rank: usize, // index of the rule in the precedence order of its rule set (see `RuleSet::rules`)
 
     pub rule_origin: RuleOrigin, // where that rule was declared (explicit in config or created from import)
     pub config_origin: ConfigOrigin, // which line in the config was at the origin of this rule
@@ -95,6 +507,70 @@ pub enum ExecutionType {
     WaitSuccessSignalOk, // same as WaitSuccess but being killed by a singal is considered success
 }
 
+/**
+  Rule matching an input, along with the captures of its matcher command (matcher rules only), so
+  that the command is not run again to prepare the action. It dereferences to the rule.
+*/
+#[derive(Debug, Clone)]
+pub struct Match<'r> {
+    pub rule: &'r Rule,
+    pub captures: Option<Vec<String>>, // captures of the matcher that accepted the input
+}
+
+/// Action of a rule substituted against an input and ready for execution (see `Rule::prepare`).
+#[derive(Debug, Clone)]
+pub struct PreparedAction<'r> {
+    rule: &'r Rule,
+    action: ActionCommand, // action substituted and ready for execution
+    args: Vec<String>,     // positional parameters given to the shell
+    fetched: Vec<(String, PathBuf)>, // remote inputs and their local copies (see the fetch option)
+}
+
+/**
+  Handle on an action executed in fork mode, to supervise it: wait for it, kill it or use its
+  standard streams (when piped, see `PreparedAction::command`).
+*/
+#[derive(Debug)]
+pub struct ExecHandle {
+    child: Child,
+}
+
+impl ExecHandle {
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Wait for the action to exit.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        Ok(self.child.wait()?)
+    }
+
+    /// Exit status of the action if it already exited, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        Ok(self.child.try_wait()?)
+    }
+
+    /// Kill the action (but not the processes it spawned, see `kill_process_group`).
+    pub fn kill(&mut self) -> Result<()> {
+        Ok(self.child.kill()?)
+    }
+
+    /// Child process of the action, e.g. to take its stdin or stdout.
+    pub fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    pub fn into_child(self) -> Child {
+        self.child
+    }
+}
+
+impl From<Child> for ExecHandle {
+    fn from(child: Child) -> Self {
+        Self { child }
+    }
+}
+
 impl RuleSetBuilder {
     pub fn new(profile: ProfileIdentifier, case_insensitive: bool) -> Self {
         // todo: accept &ProfileIdentifier instead
@@ -102,28 +578,90 @@ impl RuleSetBuilder {
             profile,
             case_insensitive,
             alias: HashMap::new(),
+            exact_rules: vec![],
+            affix_rules: vec![],
+            matcher_rules: vec![],
             regex_rules: vec![],
             glob_rules: vec![],
+            default_rule: None,
+            declarations: 0,
         }
     }
 
+    /// Profile of the rule set being built.
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Identifiers of the aliases added so far, in no particular order.
+    pub fn aliases(&self) -> impl Iterator<Item = &str> + '_ {
+        self.alias.keys().map(|alias| alias.as_str())
+    }
+
     /// Add an alias to the rule set. It can be recalled when you add a rule.
     pub fn alias(&mut self, identifier: AliasIdentifier, action_command: ActionCommand) {
         // todo: accept &AliasIdentifier, &Action
         self.alias.insert(identifier, action_command);
     }
 
+    /**
+      Add an alias built in code, named as in the config (e.g. `[browser]`). Rules reference it
+      with `Action::Alias`.
+    */
+    pub fn add_alias(&mut self, identifier: &str, command: &str) -> &mut Self {
+        self.alias(identifier.to_string(), command.to_string());
+        self
+    }
+
+    /**
+      Add a rule built in code, without any config file, executing the command with the default
+      options. Its origin is the place of the call.
+    */
+    #[track_caller]
+    pub fn add_rule(&mut self, pattern: Pattern, command: &str) -> &mut Self {
+        let action = Action::Command(command.to_string());
+        self.add_rule_with_options(pattern, action, RuleOptions::default())
+    }
+
+    /// Same as `add_rule`, with an alias or a command and the options of the rule.
+    #[track_caller]
+    pub fn add_rule_with_options(
+        &mut self,
+        pattern: Pattern,
+        action: Action,
+        options: RuleOptions,
+    ) -> &mut Self {
+        self.rule(
+            pattern,
+            action,
+            self.case_insensitive,
+            options,
+            RuleOrigin::Explicit,
+            ConfigOrigin::caller(),
+        );
+        self
+    }
+
+    /// Set the default action of the rule set in code (see `default_action`).
+    #[track_caller]
+    pub fn set_default_action(&mut self, action: Action, options: RuleOptions) -> &mut Self {
+        self.default_action(ConfigOrigin::caller(), action, options);
+        self
+    }
+
     /// Add a rule that comes from the config file directly with an action.
     pub fn rule_with_command(
         &mut self,
         config_origin: ConfigOrigin,
         pattern: Pattern,
         action_command: ActionCommand,
-    ) -> () {
+        options: RuleOptions,
+    ) {
         self.rule(
             pattern,
             Action::Command(action_command),
             self.case_insensitive,
+            options,
             RuleOrigin::Explicit,
             config_origin,
         );
@@ -135,11 +673,13 @@ impl RuleSetBuilder {
         config_origin: ConfigOrigin,
         pattern: Pattern,
         alias_identifier: AliasIdentifier,
+        options: RuleOptions,
     ) -> Result<()> {
         self.rule(
             pattern,
             Action::Alias(alias_identifier),
             self.case_insensitive,
+            options,
             RuleOrigin::Explicit,
             config_origin,
         );
@@ -147,129 +687,518 @@ impl RuleSetBuilder {
         Ok(())
     }
 
+    /**
+      Set the action of the inputs that match no other rule of the profile. It is a rule matching
+      anything, only tried last (and replaced by the next default action of the profile).
+    */
+    pub fn default_action(
+        &mut self,
+        config_origin: ConfigOrigin,
+        action: Action,
+        options: RuleOptions,
+    ) {
+        self.default_rule = Some(Rule::new(
+            Pattern::Glob("*".to_string()),
+            action,
+            self.case_insensitive,
+            options,
+            self.profile.clone(),
+            RuleOrigin::Explicit,
+            config_origin,
+        ));
+    }
+
     #[cfg(feature = "import")]
-    /// Add a rule that comes from an imported desktop file.
+    /// Add the rules that come from an imported desktop file.
     pub fn rule_with_import(
         &mut self,
         config_origin: &ConfigOrigin,
         imported_path: &Path,
         ignore_missing_attrs: bool,
+        options: RuleOptions,
     ) -> Result<()> {
-        use anyhow::Context;
-
-        let desktop_entry = freedesktop_entry_parser::parse_entry(imported_path)?;
-        let desktop_section = desktop_entry
-            .section("Desktop Entry")
-            .context("missing 'Desktop Entry' section")?;
-
-        let get_attr = |name: &str| -> Result<Option<&str>> {
-            match desktop_section.attr(name).get(0) {
-                Some(val) => Ok(Some(val)),
-                None if ignore_missing_attrs => Ok(None),
-                None => anyhow::bail!("missing '{}' attribute", name),
-            }
-        };
-
-        let Some(exec_cmd) = get_attr("Exec")?.map(|s| {
-            // Handle most common desktop flags. We still don't handle %i, %c, %k.
-            ["%U", "%u", "%F", "%f"]
-                .iter()
-                .fold(s.to_string(), |acc, format_specifier| {
-                    acc.replace(format_specifier, "%s")
-                })
-        }) else {
-            return Ok(());
-        };
-        let Some(mime_types) = get_attr("MimeType")?.map(|s| s.to_string()) else {
-            return Ok(());
-        };
-
-        for mime_type in mime_types.split(";").filter(|s| !s.is_empty()) {
-            if let Some(extensions) = mime_guess::get_mime_extensions_str(mime_type) {
-                for extension in extensions {
-                    let pattern = Pattern::Glob(format!("*.{}", extension));
-
-                    self.rule(
-                        pattern,
-                        Action::Command(exec_cmd.to_string()),
-                        self.case_insensitive,
-                        RuleOrigin::Imported(imported_path.to_string_lossy().to_string()),
-                        config_origin.clone(),
-                    )
-                }
-            }
+        let imported_path_str = imported_path.to_string_lossy();
+        for (glob, exec_cmd) in desktop_file_rules(imported_path, ignore_missing_attrs)? {
+            self.rule_with_imported_command(
+                config_origin,
+                &imported_path_str,
+                Pattern::Glob(glob),
+                exec_cmd,
+                options.clone(),
+            );
         }
 
         Ok(())
     }
 
+    /// Add a rule that was created from an imported desktop file (e.g. with `desktop_file_rules`).
+    pub fn rule_with_imported_command(
+        &mut self,
+        config_origin: &ConfigOrigin,
+        imported_path: &str,
+        pattern: Pattern,
+        action_command: ActionCommand,
+        options: RuleOptions,
+    ) {
+        self.rule(
+            pattern,
+            Action::Command(action_command),
+            self.case_insensitive,
+            options,
+            RuleOrigin::Imported(imported_path.to_string()),
+            config_origin.clone(),
+        )
+    }
+
     fn rule(
         &mut self,
         pattern: Pattern,
         action: Action,
         case_insensitive: bool,
+        options: RuleOptions,
         rule_origin: RuleOrigin,
         config_origin: ConfigOrigin,
     ) {
-        let rule = Rule {
+        let mut rule = Rule::new(
             pattern,
             action,
-            resolved: OnceCell::new(),
-            execution: OnceCell::new(),
             case_insensitive,
+            options,
+            self.profile.clone(),
             rule_origin,
             config_origin,
-        };
+        );
+        rule.declaration = self.declarations;
+        self.declarations += 1;
 
         match rule.pattern {
+            Pattern::Exact(_) => self.exact_rules.push(rule),
+            Pattern::Prefix(_) | Pattern::Suffix(_) => self.affix_rules.push(rule),
             Pattern::Regex(_) => self.regex_rules.push(rule),
             Pattern::Glob(_) => self.glob_rules.push(rule),
+            // field, fuzzy, content and binary rules are checked one by one along with the matchers
+            Pattern::Matcher(_)
+            | Pattern::Plugin(_)
+            | Pattern::Field(..)
+            | Pattern::Fuzzy(_)
+            | Pattern::Content(_)
+            | Pattern::Binary(_) => self.matcher_rules.push(rule),
         }
     }
 
-    fn resolve(&self, rules: &[Rule]) -> Result<()> {
-        for rule in rules {
-            rule.resolve(self)?;
-        }
-        Ok(())
-    }
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(profile = %self.profile), err)
+    )]
+    pub fn build(
+        mut self,
+        settings: &Settings,
+        substitutions: &Arc<Substitutions>,
+    ) -> Result<RuleSet> {
+        // invalid rules are all reported at once (or skipped in lenient mode)
+        let lenient = settings.lenient;
+        let mut errors = vec![];
 
-    pub fn build(mut self) -> Result<RuleSet> {
         // resolve each rule (map alias to action)
-        self.resolve(&self.regex_rules)?;
-        self.resolve(&self.glob_rules)?;
+        let mut rules = [
+            mem::take(&mut self.exact_rules),
+            mem::take(&mut self.affix_rules),
+            mem::take(&mut self.matcher_rules),
+            mem::take(&mut self.regex_rules),
+            mem::take(&mut self.glob_rules),
+        ];
+        for rules in &mut rules {
+            retain_valid(rules, lenient, &mut errors, |rule| rule.resolve(&self));
+        }
+        [
+            self.exact_rules,
+            self.affix_rules,
+            self.matcher_rules,
+            self.regex_rules,
+            self.glob_rules,
+        ] = rules;
+        let mut default_rules: Vec<Rule> = self.default_rule.take().into_iter().collect();
+        retain_valid(&mut default_rules, lenient, &mut errors, |rule| {
+            rule.resolve(&self)
+        });
+
+        // reverse the patterns to match the last one first (unless the first one wins)
+        if !settings.first_match {
+            self.exact_rules.reverse();
+            self.affix_rules.reverse();
+            self.matcher_rules.reverse();
+            self.regex_rules.reverse();
+            self.glob_rules.reverse();
+        }
+
+        // compile each regex once, it is needed to extract the captures when a rule is prepared
+        // and it reports invalid regexes with their origin before building the whole set
+        retain_valid(&mut self.regex_rules, lenient, &mut errors, |rule| {
+            rule.regex = Some(RuleRegex::new(rule)?);
+            Ok(())
+        });
+
+        // field globs and content regexes are compiled on their own as they are checked one by one
+        retain_valid(&mut self.matcher_rules, lenient, &mut errors, |rule| {
+            if let Pattern::Field(_, glob) = &rule.pattern {
+                let glob = GlobBuilder::new(glob)
+                    .case_insensitive(rule.case_insensitive)
+                    .build()
+                    .map_err(invalid_pattern(glob))?;
+                rule.field_glob = Some(glob.compile_matcher());
+            }
+            if let Pattern::Content(regex) = &rule.pattern {
+                // files are not necessarily text, \xNN is a byte rather than a character
+                let regex = BytesRegexBuilder::new(regex)
+                    .unicode(false)
+                    .case_insensitive(rule.case_insensitive)
+                    .build()
+                    .map_err(invalid_pattern(regex))?;
+                rule.content_regex = Some(regex);
+            }
+            Ok(())
+        });
 
-        // reverse the patterns to match the last one first
-        self.regex_rules.reverse();
-        self.glob_rules.reverse();
+        // check the globs on their own, the glob set would not tell which one is invalid
+        retain_valid(&mut self.glob_rules, lenient, &mut errors, |rule| {
+            if glob_literal_suffix(rule.pattern_as_str()).is_none() {
+                GlobBuilder::new(rule.pattern_as_str())
+                    .empty_alternates(rule.options.extended_glob)
+                    .build()
+                    .map_err(invalid_pattern(rule.pattern_as_str()))?;
+            }
+            Ok(())
+        });
+
+        // scripts are checked before the indices of the rules are taken by the sets below
+        #[cfg(feature = "script")]
+        for rules in [
+            &mut self.exact_rules,
+            &mut self.affix_rules,
+            &mut self.matcher_rules,
+            &mut self.regex_rules,
+            &mut self.glob_rules,
+            &mut default_rules,
+        ] {
+            retain_valid(rules, lenient, &mut errors, |rule| {
+                let scripts = rule.options.condition.as_deref().into_iter();
+                let scripts = scripts.chain(rule.options.script.then(|| rule.resolved_action()));
+                for script in scripts {
+                    script::check(script)?;
+                }
+                Ok(())
+            });
+        }
+
+        // exact rules are only looked up, they are never compiled
+        let mut exact_literals: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, rule) in self.exact_rules.iter().enumerate() {
+            let literal = match rule.case_insensitive {
+                true => rule.pattern_as_str().to_lowercase(),
+                false => rule.pattern_as_str().to_string(),
+            };
+            exact_literals.entry(literal).or_default().push(index);
+        }
+        let affix_literals = self
+            .affix_rules
+            .iter()
+            .map(|rule| match rule.case_insensitive {
+                true => rule.pattern_as_str().to_lowercase(),
+                false => rule.pattern_as_str().to_string(),
+            })
+            .collect();
 
-        let regex_patterns: Vec<&str> = self
-            .regex_rules
+        // fancy regexes cannot be part of the regex set, they are checked one by one
+        let (fancy_regex_rules, regex_set_rules): (Vec<usize>, Vec<usize>) =
+            (0..self.regex_rules.len()).partition(|&i| self.regex_rules[i].options.fancy_regex);
+        let regex_patterns: Vec<Cow<str>> = regex_set_rules
             .iter()
-            .map(|r| r.pattern_as_str())
+            .map(|&i| {
+                let rule = &self.regex_rules[i];
+                match rule.options.nocase {
+                    Some(true) => format!("(?i:{})", rule.pattern_as_str()).into(),
+                    Some(false) => format!("(?-i:{})", rule.pattern_as_str()).into(),
+                    None => rule.pattern_as_str().into(),
+                }
+            })
             .collect();
         let regex_set = RegexSetBuilder::new(&regex_patterns)
             .case_insensitive(self.case_insensitive)
-            .build()?;
+            .build()
+            .map_err(|e| RrrError::invalid(e.to_string()))?;
 
+        // globs that are only a literal suffix (e.g. `*.pdf`, typically from imports) are bucketed
+        // by extension so that they don't need to be compiled and checked in the glob set
         let mut glob_set_builder = GlobSetBuilder::new();
-        for rule in &self.glob_rules {
+        let mut glob_set_rules = vec![];
+        let mut glob_suffixes: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, rule) in self.glob_rules.iter().enumerate() {
+            if let Some(suffix) = glob_literal_suffix(rule.pattern_as_str())
+                && let Some(key) = suffix_key(suffix)
+            {
+                glob_suffixes.entry(key).or_default().push(index);
+                continue;
+            }
+
             glob_set_builder.add(
                 GlobBuilder::new(rule.pattern_as_str())
-                    .case_insensitive(self.case_insensitive)
-                    .build()?,
+                    .case_insensitive(rule.case_insensitive)
+                    .empty_alternates(rule.options.extended_glob)
+                    .build()
+                    .map_err(invalid_pattern(rule.pattern_as_str()))?,
             );
+            glob_set_rules.push(index);
+        }
+        let glob_set = glob_set_builder
+            .build()
+            .map_err(|e| RrrError::invalid(e.to_string()))?;
+
+        if !errors.is_empty() {
+            return Err(RrrError::InvalidRules {
+                profile: self.profile,
+                errors,
+            });
+        }
+        self.default_rule = default_rules.pop();
+        for rule in self
+            .exact_rules
+            .iter_mut()
+            .chain(&mut self.affix_rules)
+            .chain(&mut self.matcher_rules)
+            .chain(&mut self.regex_rules)
+            .chain(&mut self.glob_rules)
+            .chain(&mut self.default_rule)
+        {
+            rule.substitutions = substitutions.clone();
+        }
+
+        // rank the rules in the order they are tried, matcher, regex and glob rules being
+        // interleaved (but for the regex rules with regex-first)
+        let mut pattern_rules: Vec<&mut Rule> = self
+            .matcher_rules
+            .iter_mut()
+            .chain(&mut self.regex_rules)
+            .chain(&mut self.glob_rules)
+            .collect();
+        let after_regexes =
+            |rule: &Rule| settings.regex_first && !matches!(rule.pattern, Pattern::Regex(_));
+        pattern_rules.sort_by(|a, b| {
+            let declaration = match settings.first_match {
+                true => a.declaration.cmp(&b.declaration),
+                false => b.declaration.cmp(&a.declaration),
+            };
+            after_regexes(a).cmp(&after_regexes(b)).then(declaration)
+        });
+        let ranked = self
+            .exact_rules
+            .iter_mut()
+            .chain(&mut self.affix_rules)
+            .chain(pattern_rules)
+            .chain(&mut self.default_rule);
+        for (rank, rule) in ranked.enumerate() {
+            rule.rank = rank;
         }
-        let glob_set = glob_set_builder.build()?;
+
+        let conditional = self
+            .exact_rules
+            .iter()
+            .chain(&self.affix_rules)
+            .chain(&self.matcher_rules)
+            .chain(&self.regex_rules)
+            .chain(&self.glob_rules)
+            .any(|rule| rule.options.condition.is_some() || !rule.options.schedule.is_always());
 
         Ok(RuleSet {
+            exact_literals,
+            affix_literals,
             regex_set,
+            regex_set_rules,
+            fancy_regex_rules,
             glob_set,
+            glob_set_rules,
+            glob_suffixes,
+            conditional,
             builder: self,
+            settings: settings.clone(),
         })
     }
 }
 
+/**
+  Keep the rules passing the check. The failing rules are removed and their error is added to
+  `errors` with their origin, unless in lenient mode where they are only skipped with a warning.
+*/
+fn retain_valid(
+    rules: &mut Vec<Rule>,
+    lenient: bool,
+    errors: &mut Vec<String>,
+    mut check: impl FnMut(&mut Rule) -> Result<()>,
+) {
+    rules.retain_mut(|rule| match check(rule) {
+        Ok(()) => true,
+        Err(e) if lenient => {
+            warn!("skipping rule {}: {}", rule.config_origin, e.report());
+            false
+        }
+        Err(e) => {
+            let error = format!("{}: {}", rule.config_origin, e.report());
+            errors.push(error.replace('\n', "\n    ")); // e.g. regex errors span several lines
+            false
+        }
+    });
+}
+
+#[cfg(feature = "import")]
+/**
+  Parse a desktop file into the glob patterns and command of the rules it should create.
+  It reads the "Exec" and "MimeType" attributes and infers the possible file extensions
+  for each MIME type. Files missing one of those attributes create no rule when
+  `ignore_missing_attrs` is set.
+*/
+pub fn desktop_file_rules(
+    imported_path: &Path,
+    ignore_missing_attrs: bool,
+) -> Result<Vec<(String, ActionCommand)>> {
+    let desktop_entry = freedesktop_entry_parser::parse_entry(imported_path)
+        .map_err(|e| RrrError::invalid(e.to_string()))?;
+    let desktop_section = desktop_entry
+        .section("Desktop Entry")
+        .context("missing 'Desktop Entry' section")?;
+
+    let get_attr = |name: &str| -> Result<Option<&str>> {
+        match desktop_section.attr(name).first() {
+            Some(val) => Ok(Some(val)),
+            None if ignore_missing_attrs => Ok(None),
+            None => Err(RrrError::invalid(format!("missing '{}' attribute", name))),
+        }
+    };
+
+    let Some(exec_cmd) = get_attr("Exec")?.map(|s| {
+        // Handle most common desktop flags. We still don't handle %i, %c, %k.
+        ["%U", "%u", "%F", "%f"]
+            .iter()
+            .fold(s.to_string(), |acc, format_specifier| {
+                acc.replace(format_specifier, "%s")
+            })
+    }) else {
+        return Ok(vec![]);
+    };
+    let Some(mime_types) = get_attr("MimeType")?.map(|s| s.to_string()) else {
+        return Ok(vec![]);
+    };
+
+    let mut rules = vec![];
+    for mime_type in mime_types.split(";").filter(|s| !s.is_empty()) {
+        if let Some(extensions) = mime_guess::get_mime_extensions_str(mime_type) {
+            for extension in extensions {
+                rules.push((format!("*.{}", extension), exec_cmd.clone()));
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Literal that a glob made of `*` followed by a literal (e.g. `*.pdf`, `*.tar.gz`) ends with.
+fn glob_literal_suffix(glob: &str) -> Option<&str> {
+    let suffix = glob.strip_prefix('*')?;
+    let is_literal = !suffix.contains(['*', '?', '[', ']', '{', '}', '\\']);
+    is_literal.then_some(suffix)
+}
+
+/// Error of a pattern that cannot be compiled, e.g. an invalid regex.
+fn invalid_pattern<E: fmt::Display>(pattern: &str) -> impl FnOnce(E) -> RrrError + '_ {
+    move |error| RrrError::InvalidPattern {
+        pattern: pattern.to_string(),
+        reason: error.to_string(),
+    }
+}
+
+/**
+  Translate a glob written with the extended syntax into the syntax of globset, i.e. the POSIX
+  character classes (e.g. `[[:digit:]]`) and the ksh patterns `@(a|b)` (one of the patterns)
+  and `?(a|b)` (optionally one of the patterns), which become alternates.
+*/
+pub fn extended_glob(glob: &str) -> Result<String> {
+    let mut translated = String::with_capacity(glob.len());
+    let mut groups = vec![]; // closing of the opened ksh patterns
+    let mut in_class = false;
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                translated.push(c);
+                translated.extend(chars.next());
+            }
+            '[' if in_class && chars.peek() == Some(&':') => {
+                let rest: String = chars.clone().collect();
+                let (name, _) = rest[1..]
+                    .split_once(":]")
+                    .ok_or_else(|| invalid_pattern(glob)("unclosed character class"))?;
+                let class = match name {
+                    "alpha" => "a-zA-Z",
+                    "digit" => "0-9",
+                    "alnum" => "a-zA-Z0-9",
+                    "upper" => "A-Z",
+                    "lower" => "a-z",
+                    "xdigit" => "0-9a-fA-F",
+                    "word" => "a-zA-Z0-9_",
+                    "space" => " \t\n\r",
+                    _ => {
+                        let reason = format!("unknown character class '[:{}:]'", name);
+                        return Err(invalid_pattern(glob)(reason));
+                    }
+                };
+                translated.push_str(class);
+                chars.nth(name.len() + 2); // skip ':name:]'
+            }
+            '[' if !in_class => {
+                in_class = true;
+                translated.push(c);
+                // a closing bracket right after the opening one is part of the class
+                if let Some(negation @ ('!' | '^')) = chars.peek().copied() {
+                    translated.push(negation);
+                    chars.next();
+                }
+                if chars.peek() == Some(&']') {
+                    translated.push(']');
+                    chars.next();
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                translated.push(c);
+            }
+            '@' | '?' | '+' | '*' | '!' if !in_class && chars.peek() == Some(&'(') => {
+                chars.next();
+                match c {
+                    '@' => groups.push("}"),
+                    '?' => groups.push(",}"),
+                    _ => {
+                        let reason = format!("unsupported pattern '{}('", c);
+                        return Err(invalid_pattern(glob)(reason));
+                    }
+                }
+                translated.push('{');
+            }
+            '|' if !in_class && !groups.is_empty() => translated.push(','),
+            ',' if !in_class && !groups.is_empty() => translated.push_str("\\,"),
+            ')' if !in_class && !groups.is_empty() => translated.push_str(groups.pop().unwrap()),
+            _ => translated.push(c),
+        }
+    }
+    if !groups.is_empty() {
+        return Err(invalid_pattern(glob)("unclosed pattern"));
+    }
+    Ok(translated)
+}
+
+/// Bucket of a literal suffix or an input, i.e. its lowercase extension.
+fn suffix_key(s: &str) -> Option<String> {
+    s.rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+}
+
 impl RuleResolver for &RuleSetBuilder {
     fn resolve<'a>(&'a self, action: &'a Action) -> Result<&'a str> {
         match action {
@@ -277,30 +1206,189 @@ impl RuleResolver for &RuleSetBuilder {
             Action::Alias(alias_identifier) => self
                 .alias
                 .get(alias_identifier)
-                .ok_or_else(|| {
-                    anyhow!(
-                        "Alias '{}' does not exist in profile '{}'",
-                        alias_identifier,
-                        self.profile
-                    )
+                .ok_or_else(|| RrrError::UnresolvedAlias {
+                    alias: alias_identifier.clone(),
+                    profile: self.profile.clone(),
                 })
                 .map(|s| s.as_str()),
         }
     }
 }
 
-impl RuleSet {
-    pub fn matches_glob(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
-        self.glob_set.matches(input).into_iter().map(|index| {
-            self.builder
-                .glob_rules
+/**
+  The logical contents of the rule set: its profile, its aliases and its rules, in the order they
+  are tried. The compiled patterns are not serialized.
+*/
+#[cfg(feature = "serde")]
+impl Serialize for RuleSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let aliases: std::collections::BTreeMap<_, _> = self.builder.alias.iter().collect();
+        let rules: Vec<&Rule> = self.rules().collect();
+        let mut state = serializer.serialize_struct("RuleSet", 4)?;
+        state.serialize_field("profile", &self.builder.profile)?;
+        state.serialize_field("case_insensitive", &self.builder.case_insensitive)?;
+        state.serialize_field("aliases", &aliases)?;
+        state.serialize_field("rules", &rules)?;
+        state.end()
+    }
+}
+
+impl RuleSet {
+    /// Profile this rule set was built for.
+    pub fn profile(&self) -> &str {
+        &self.builder.profile
+    }
+
+    /// Identifiers of the aliases of the profile, in no particular order.
+    pub fn aliases(&self) -> impl Iterator<Item = &str> + '_ {
+        self.builder.aliases()
+    }
+
+    /**
+      Iterate over every rule in precedence order (exact and affix rules first, then matcher,
+      regex and glob rules interleaved by declaration, and finally the default action).
+    */
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> + '_ {
+        self.builder
+            .exact_rules
+            .iter()
+            .chain(self.builder.affix_rules.iter())
+            .chain(self.pattern_rules())
+            .chain(self.builder.default_rule.iter())
+    }
+
+    /// Matcher, regex and glob rules in precedence order (see `matches_declared`).
+    fn pattern_rules(&self) -> Vec<&Rule> {
+        let mut rules: Vec<&Rule> = self
+            .builder
+            .matcher_rules
+            .iter()
+            .chain(&self.builder.regex_rules)
+            .chain(&self.builder.glob_rules)
+            .collect();
+        rules.sort_by_key(|rule| rule.rank);
+        rules
+    }
+
+    /// Rule of the default action, if the profile has one (and its program is available).
+    fn default_rule(&self) -> Option<&Rule> {
+        self.builder
+            .default_rule
+            .as_ref()
+            .filter(|rule| self.is_available(rule))
+    }
+
+    /// Exact rules whose literal is the input, in precedence order.
+    pub fn matches_exact(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
+        let lowercase_input = input.to_lowercase();
+        let case_sensitive = self.exact_literals.get(input).into_iter().flatten();
+        let case_insensitive = self
+            .exact_literals
+            .get(&lowercase_input)
+            .into_iter()
+            .flatten();
+        let mut indices: Vec<usize> = case_sensitive
+            .filter(|&&index| !self.builder.exact_rules[index].case_insensitive)
+            .chain(
+                case_insensitive.filter(|&&index| self.builder.exact_rules[index].case_insensitive),
+            )
+            .copied()
+            .collect();
+        indices.sort_unstable();
+
+        indices
+            .into_iter()
+            .map(|index| &self.builder.exact_rules[index])
+    }
+
+    /// Prefix and suffix rules matching the input, in precedence order.
+    pub fn matches_affix<'s, 'i>(
+        &'s self,
+        input: &'i str,
+    ) -> impl Iterator<Item = &'s Rule> + use<'s, 'i> {
+        let lowercase_input = self
+            .builder
+            .affix_rules
+            .iter()
+            .any(|rule| rule.case_insensitive)
+            .then(|| input.to_lowercase());
+        self.builder
+            .affix_rules
+            .iter()
+            .zip(&self.affix_literals)
+            .filter(move |(rule, literal)| {
+                let input = match (&lowercase_input, rule.case_insensitive) {
+                    (Some(lowercase_input), true) => lowercase_input.as_str(),
+                    _ => input,
+                };
+                match rule.pattern {
+                    Pattern::Prefix(_) => input.starts_with(literal.as_str()),
+                    _ => input.ends_with(literal.as_str()),
+                }
+            })
+            .map(|(rule, _)| rule)
+    }
+
+    /**
+      Matcher and field rules accepting the input, in precedence order among themselves. Matcher
+      commands are only run as iterated.
+    */
+    pub fn matches_matcher<'s, 'i>(
+        &'s self,
+        input: &'i str,
+        fields: &'i InputFields,
+    ) -> impl Iterator<Item = Match<'s>> + use<'s, 'i> {
+        self.builder.matcher_rules.iter().filter_map(move |rule| {
+            let captures = rule.matcher_accepts(input, fields)?;
+            Some(Match {
+                rule,
+                captures: Some(captures),
+            })
+        })
+    }
+
+    pub fn matches_glob(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
+        self.glob_matches_indices(input).into_iter().map(|index| {
+            self.builder
+                .glob_rules
                 .get(index)
                 .expect("Glob matches gave a non existing index")
         })
     }
 
+    /// Index of the glob rules matching the input, in precedence order.
+    fn glob_matches_indices(&self, input: &str) -> Vec<usize> {
+        let mut indices: Vec<usize> = self
+            .glob_set
+            .matches(input)
+            .into_iter()
+            .map(|i| self.glob_set_rules[i])
+            .collect();
+
+        if let Some(key) = suffix_key(input)
+            && let Some(candidates) = self.glob_suffixes.get(&key)
+        {
+            let lowercase_input = input.to_lowercase();
+            for index in candidates {
+                let rule = &self.builder.glob_rules[*index];
+                let suffix = rule.pattern_as_str().trim_start_matches('*');
+                let matched = if rule.case_insensitive {
+                    lowercase_input.ends_with(&suffix.to_lowercase())
+                } else {
+                    input.ends_with(suffix)
+                };
+                if matched {
+                    indices.push(*index);
+                }
+            }
+            indices.sort_unstable();
+        }
+
+        indices
+    }
+
     pub fn matches_regex(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
-        self.regex_set.matches(input).into_iter().map(|index| {
+        self.regex_matches_indices(input).into_iter().map(|index| {
             self.builder
                 .regex_rules
                 .get(index)
@@ -308,12 +1396,143 @@ impl RuleSet {
         })
     }
 
-    pub fn matches(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
-        self.matches_regex(input).chain(self.matches_glob(input))
+    /// Index of the regex rules matching the input, in precedence order.
+    fn regex_matches_indices(&self, input: &str) -> Vec<usize> {
+        let mut indices: Vec<usize> = self
+            .regex_set
+            .matches(input)
+            .into_iter()
+            .map(|i| self.regex_set_rules[i])
+            .collect();
+
+        if !self.fancy_regex_rules.is_empty() {
+            for index in &self.fancy_regex_rules {
+                let rule = &self.builder.regex_rules[*index];
+                if rule.regex.as_ref().is_some_and(|re| re.is_match(input)) {
+                    indices.push(*index);
+                }
+            }
+            indices.sort_unstable();
+        }
+
+        indices
+    }
+
+    /**
+      Regex and glob rules matching the input, in precedence order: the last declared one first
+      (the first one with `match-order first`), or every regex rule before the glob rules with
+      `regex-first`.
+    */
+    pub fn matches_pattern(&self, input: &str) -> impl Iterator<Item = &Rule> + '_ {
+        let mut matches: Vec<&Rule> = self
+            .matches_regex(input)
+            .chain(self.matches_glob(input))
+            .collect();
+        matches.sort_by_key(|rule| rule.rank);
+        matches.into_iter()
+    }
+
+    /**
+      Matcher, regex and glob rules accepting the input, in precedence order (i.e. by declaration,
+      see `matches_pattern`). The regex and glob rules are matched at once, while a matcher rule is
+      only checked once the rules before it are iterated, so that its command is not run when an
+      earlier rule is enough.
+    */
+    pub fn matches_declared<'s, 'i>(
+        &'s self,
+        input: &'i str,
+        fields: &'i InputFields,
+    ) -> impl Iterator<Item = Match<'s>> + use<'s, 'i> {
+        let mut patterns = self.matches_pattern(input).peekable();
+        let mut matchers = self.builder.matcher_rules.iter().peekable();
+        iter::from_fn(move || {
+            loop {
+                let matcher = match (patterns.peek(), matchers.peek()) {
+                    (Some(rule), Some(matcher)) if rule.rank < matcher.rank => {
+                        return patterns.next().map(Match::from);
+                    }
+                    (_, Some(_)) => matchers.next().unwrap(),
+                    (_, None) => return patterns.next().map(Match::from),
+                };
+                if let Some(captures) = matcher.matcher_accepts(input, fields) {
+                    return Some(Match {
+                        rule: matcher,
+                        captures: Some(captures),
+                    });
+                }
+            }
+        })
+    }
+
+    /**
+      Every rule matching the input, whatever the kind of its pattern, in precedence order: the
+      rule `r#match` returns first, then the following ones (e.g. the fallbacks).
+    */
+    pub fn matches<'s, 'i>(
+        &'s self,
+        input: &'i str,
+    ) -> impl Iterator<Item = Match<'s>> + use<'s, 'i> {
+        self.matches_with_fields(input, &NO_FIELDS)
+    }
+
+    /**
+      Same as `matches`, along with the rank of each rule among all the rules of the profile
+      (i.e. its index in `rules`).
+    */
+    pub fn ranked_matches<'s, 'i>(
+        &'s self,
+        input: &'i str,
+    ) -> impl Iterator<Item = (usize, Match<'s>)> + use<'s, 'i> {
+        self.matches(input).map(|r#match| (r#match.rank, r#match))
+    }
+
+    /**
+      Rules matching a structured input, those targeting a field only match when it is known.
+      The default action is only given when no other rule matches.
+    */
+    pub fn matches_with_fields<'s, 'i>(
+        &'s self,
+        input: &'i str,
+        fields: &'i InputFields,
+    ) -> impl Iterator<Item = Match<'s>> + use<'s, 'i> {
+        let mut matches = self
+            .matches_exact(input)
+            .chain(self.matches_affix(input))
+            .map(Match::from)
+            .chain(self.matches_declared(input, fields))
+            .filter(move |r#match| {
+                self.is_available(r#match) && r#match.condition_accepts(input, &r#match.captures)
+            })
+            .peekable();
+        let default_rule = match matches.peek() {
+            Some(_) => None,
+            None => self.default_rule(),
+        };
+        matches.chain(default_rule.map(Match::from))
+    }
+
+    /// Check if the program of the rule can be executed, when missing programs should be skipped.
+    fn is_available(&self, rule: &Rule) -> bool {
+        if !self.settings.skip_missing_commands {
+            return true;
+        }
+
+        match rule.missing_program() {
+            Some(program) => {
+                log!(
+                    rule.log_level(Level::Debug),
+                    "skipping rule {}: '{}' not found",
+                    rule.config_origin,
+                    program
+                );
+                false
+            }
+            None => true,
+        }
     }
 
     fn match_glob(&self, input: &str) -> Option<&Rule> {
-        let matches = self.glob_set.matches(input);
+        let matches = self.glob_matches_indices(input);
 
         if let Some(index) = matches.first() {
             Some(
@@ -328,7 +1547,7 @@ impl RuleSet {
     }
 
     fn match_regex(&self, input: &str) -> Option<&Rule> {
-        let matches: Vec<usize> = self.regex_set.matches(input).into_iter().collect();
+        let matches = self.regex_matches_indices(input);
 
         if let Some(index) = matches.first() {
             Some(
@@ -342,162 +1561,1486 @@ impl RuleSet {
         }
     }
 
-    /// Return the first glob or regex rule that matches the input.
-    pub fn r#match(&self, input: &str) -> Option<&Rule> {
-        if let r @ Some(_) = self.match_regex(input) {
-            return r;
+    /// Return the first rule that matches the input (or the default action).
+    pub fn r#match(&self, input: &str) -> Option<Match<'_>> {
+        self.match_with_fields(input, &NO_FIELDS)
+    }
+
+    /// Return the first rule that matches a structured input.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, fields), fields(profile = %self.builder.profile))
+    )]
+    pub fn match_with_fields(&self, input: &str, fields: &InputFields) -> Option<Match<'_>> {
+        if self.settings.skip_missing_commands || self.conditional {
+            return self.matches_with_fields(input, fields).next();
         }
-        if let r @ Some(_) = self.match_glob(input) {
-            return r;
+        if let Some(rule) = self.matches_exact(input).next() {
+            return Some(rule.into());
         }
-        None
+        if let Some(rule) = self.matches_affix(input).next() {
+            return Some(rule.into());
+        }
+        if !self.builder.matcher_rules.is_empty() {
+            let r#match = self.matches_declared(input, fields).next();
+            return r#match.or_else(|| self.default_rule().map(Match::from));
+        }
+        if self.settings.regex_first
+            && let Some(rule) = self.match_regex(input)
+        {
+            return Some(rule.into());
+        }
+        let rule = match (self.match_regex(input), self.match_glob(input)) {
+            (Some(regex), Some(glob)) if glob.rank < regex.rank => Some(glob),
+            (regex, glob) => regex.or(glob),
+        };
+        rule.or_else(|| self.default_rule()).map(Match::from)
+    }
+}
+
+impl Schedule {
+    /// Check if the rule can match at any time.
+    pub fn is_always(&self) -> bool {
+        self.hours.is_empty() && self.days.is_empty()
+    }
+
+    /// Check if the time is within the hours and the days of the schedule.
+    pub(crate) fn contains(&self, time: &DateTime<Local>) -> bool {
+        let hour = time.hour();
+        let day = time.weekday().num_days_from_monday();
+        let in_hours = self.hours.iter().any(|(start, end)| match start < end {
+            true => (*start..*end).contains(&hour),
+            false => hour >= *start || hour < *end, // over midnight
+        });
+        let in_days = self.days.iter().any(|(start, end)| match start <= end {
+            true => (*start..=*end).contains(&day),
+            false => day >= *start || day <= *end, // over the end of the week
+        });
+        (self.hours.is_empty() || in_hours) && (self.days.is_empty() || in_days)
+    }
+}
+
+impl RuleRegex {
+    fn new(rule: &Rule) -> Result<Self> {
+        #[cfg(feature = "fancy")]
+        if rule.options.fancy_regex {
+            let regex = fancy_regex::RegexBuilder::new(rule.pattern_as_str())
+                .case_insensitive(rule.case_insensitive)
+                .build()
+                .map_err(invalid_pattern(rule.pattern_as_str()))?;
+            return Ok(Self::Fancy(regex));
+        }
+
+        let regex = RegexBuilder::new(rule.pattern_as_str())
+            .case_insensitive(rule.case_insensitive)
+            .build()
+            .map_err(invalid_pattern(rule.pattern_as_str()))?;
+        Ok(Self::Standard(regex))
+    }
+
+    /// Check if the regex matches, a fancy regex exceeding its backtrack limit does not match.
+    fn is_match(&self, input: &str) -> bool {
+        match self {
+            Self::Standard(re) => re.is_match(input),
+            #[cfg(feature = "fancy")]
+            Self::Fancy(re) => re.is_match(input).unwrap_or_else(|e| {
+                warn!("cannot match regex '{}': {}", re.as_str(), e);
+                false
+            }),
+        }
+    }
+
+    /// Captured groups, without the full match, or None if the regex does not match.
+    fn captures(&self, input: &str) -> Result<Option<Vec<String>>> {
+        fn strings<'t>(groups: impl Iterator<Item = Option<&'t str>>) -> Vec<String> {
+            groups
+                .skip(1) // first capture is the full match (we don't need that)
+                .flatten()
+                .map(str::to_string)
+                .collect()
+        }
+
+        match self {
+            Self::Standard(re) => Ok(re
+                .captures(input)
+                .map(|c| strings(c.iter().map(|m| m.map(|m| m.as_str()))))),
+            #[cfg(feature = "fancy")]
+            Self::Fancy(re) => Ok(re
+                .captures(input)
+                .map_err(|e| {
+                    RrrError::invalid(format!("cannot match regex '{}': {}", re.as_str(), e))
+                })?
+                .map(|c| strings(c.iter().map(|m| m.map(|m| m.as_str()))))),
+        }
+    }
+}
+
+/// Rules are serialized with their resolved action (once resolved), without their compiled state.
+#[cfg(feature = "serde")]
+impl Serialize for Rule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Rule", 8)?;
+        state.serialize_field("pattern", &self.pattern)?;
+        state.serialize_field("action", &self.action)?;
+        state.serialize_field("resolved", &self.resolved.get())?;
+        state.serialize_field("case_insensitive", &self.case_insensitive)?;
+        state.serialize_field("options", &self.options)?;
+        state.serialize_field("profile", &self.profile)?;
+        state.serialize_field("rule_origin", &self.rule_origin)?;
+        state.serialize_field("config_origin", &self.config_origin)?;
+        state.end()
     }
 }
 
 impl Rule {
+    fn new(
+        pattern: Pattern,
+        action: Action,
+        case_insensitive: bool,
+        options: RuleOptions,
+        profile: ProfileIdentifier,
+        rule_origin: RuleOrigin,
+        config_origin: ConfigOrigin,
+    ) -> Self {
+        Self {
+            pattern,
+            action,
+            resolved: OnceLock::new(),
+            regex: None,
+            field_glob: None,
+            content_regex: None,
+            case_insensitive: options.nocase.unwrap_or(case_insensitive),
+            options,
+            substitutions: Arc::default(),
+            profile,
+            declaration: 0,
+            rank: 0,
+            rule_origin,
+            config_origin,
+        }
+    }
+
     pub fn pattern_as_str(&self) -> &str {
         match &self.pattern {
-            Pattern::Glob(pattern) | Pattern::Regex(pattern) => pattern,
+            Pattern::Glob(pattern)
+            | Pattern::Regex(pattern)
+            | Pattern::Matcher(pattern)
+            | Pattern::Field(_, pattern)
+            | Pattern::Fuzzy(pattern)
+            | Pattern::Exact(pattern)
+            | Pattern::Prefix(pattern)
+            | Pattern::Suffix(pattern)
+            | Pattern::Content(pattern)
+            | Pattern::Binary(pattern) => pattern,
+            Pattern::Plugin(plugin) => plugin.name(),
         }
     }
 
     /// Map the action as orginally speicfied to an actual command to execute.
     fn resolve(&self, resolver: impl RuleResolver) -> Result<()> {
-        let resolved_action = resolver.resolve(&self.action)?.to_string();
+        let mut resolved_action = resolver.resolve(&self.action)?.to_string();
+        if self.options.expand {
+            resolved_action = utils::expand_command(&resolved_action).with_context(|| {
+                format!(
+                    "expanding action '{}' ({})",
+                    resolved_action, self.config_origin
+                )
+            })?;
+        }
         self.resolved
             .set(resolved_action)
             .expect("rule should not be already resolved");
         Ok(())
     }
 
-    pub fn is_executable(&self) -> bool {
-        self.execution.get().is_some()
+    /// Action with its eventual alias resolved into a command.
+    pub fn resolved_action(&self) -> &str {
+        self.resolved.get().expect("rule must be resolved")
     }
 
-    pub fn get_executed_action(&self) -> Result<&str> {
-        Ok(self
-            .execution
-            .get()
-            .ok_or_else(|| anyhow!("Rule was not prepared for execution."))?)
+    /// Check if the action takes all the inputs at once (%F), so that they can be grouped.
+    pub fn is_groupable(&self) -> bool {
+        !self.options.script
+            && self.options.plugin.is_none()
+            && segments(self.resolved_action()).contains(&Segment::Files)
     }
 
-    /// Substitute %s in the action with the input that we matched against
-    fn substitute_file(action: String, input: &str) -> Result<String> {
-        // automatically append "%s" if not present
-        let action_with_tag = if action.contains("%s") {
-            action
+    /// Program executed by the resolved action, if it cannot be found in PATH.
+    pub fn missing_program(&self) -> Option<String> {
+        if self.options.script || self.options.plugin.is_some() {
+            return None; // the command is only known once the script or plugin is evaluated
+        }
+        let program = utils::command_program(self.resolved_action())?;
+        utils::find_executable(&program)
+            .is_none()
+            .then_some(program)
+    }
+
+    /// Substitute the tokens of a shell action, with the values quoted for the shell.
+    fn substitute_shell(
+        action: &str,
+        captures: &[String],
+        tokens: &[(String, String)],
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<String> {
+        let segments = segments(action);
+        let mut executable_action = render(&segments, |segment| {
+            let value = match segment {
+                Segment::Input => inputs[0].to_string(),
+                Segment::Files => return Ok(Some(quote_all(inputs)?)),
+                Segment::Extra => return Ok(Some(quote_all(extra_args)?)),
+                Segment::Capture(tag) => match capture(captures, tag) {
+                    Some(capture) => capture.to_string(),
+                    None => return Ok(None),
+                },
+                Segment::Token(tag) => match token(tokens, tag) {
+                    Some(value) => value.to_string(),
+                    None => return Ok(None),
+                },
+                Segment::Text(_) => return Ok(None),
+            };
+            Ok(Some(utils::quote(&value)?.into_owned()))
+        })?;
+
+        // automatically append the input if there is no %s (nor %F), then the extra arguments
+        if !has_file_tag(&segments) {
+            executable_action = format!("{} {}", executable_action, utils::quote(inputs[0])?);
+        }
+        if !segments.contains(&Segment::Extra) && !extra_args.is_empty() {
+            executable_action = format!("{} {}", executable_action, quote_all(extra_args)?);
+        }
+        Ok(executable_action)
+    }
+
+    /**
+      Substitute the input and the captures in each argument of a raw action.
+      Values are never interpreted by a shell, so they are substituted without quotes and the
+      input is appended as its own argument when %s is not present. A %F (or %@) argument is
+      expanded into one argument per input (or extra argument), the extra arguments are appended
+      when %@ is not present. The result is joined back with quotes, so that it can be
+      displayed and split again losslessly on execution.
+    */
+    fn substitute_raw(
+        action: &str,
+        captures: &[String],
+        tokens: &[(String, String)],
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<String> {
+        let input = inputs[0];
+        let action_args = shlex::split(action)
+            .ok_or_else(|| RrrError::invalid(format!("invalid quoting in action '{}'", action)))?;
+        let has_file_tag = action_args.iter().any(|arg| has_file_tag(&segments(arg)));
+        let has_extra_tag = action_args
+            .iter()
+            .any(|arg| segments(arg).contains(&Segment::Extra));
+
+        let mut args = vec![];
+        for arg in action_args {
+            if arg == "%F" {
+                args.extend(inputs.iter().map(|input| input.to_string()));
+                continue;
+            }
+            if arg == "%@" {
+                args.extend(extra_args.iter().cloned());
+                continue;
+            }
+            args.push(render(&segments(&arg), |segment| {
+                Ok(match segment {
+                    Segment::Input => Some(input.to_string()),
+                    Segment::Files => Some(inputs.join(" ")),
+                    Segment::Extra => Some(extra_args.join(" ")),
+                    Segment::Capture(tag) => capture(captures, tag).cloned(),
+                    Segment::Token(tag) => token(tokens, tag).cloned(),
+                    Segment::Text(_) => None,
+                })
+            })?);
+        }
+        if !has_file_tag {
+            args.push(input.to_string());
+        }
+        if !has_extra_tag {
+            args.extend(extra_args.iter().cloned());
+        }
+
+        shlex::try_join(args.iter().map(|arg| arg.as_str()))
+            .map_err(|e| RrrError::invalid(e.to_string()))
+    }
+
+    /**
+      Substitute the input and the captures with references to the shell positional parameters
+      ("$1" for %s, "${2}" for %1, ... then the custom tokens, the inputs of %F and the extra
+      arguments of %@, appended when it is not present), the actual
+      values are then given to the shell as separate arguments and never end up in the command
+      string itself.
+    */
+    fn substitute_argv(
+        action: &str,
+        captures: Vec<String>,
+        tokens: Vec<(String, String)>,
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<(String, Vec<String>)> {
+        let segments = segments(action);
+        let has_files_tag = segments.contains(&Segment::Files);
+        let parameter = |position: usize| format!("\"${{{}}}\"", position);
+        let extra_position =
+            captures.len() + tokens.len() + 2 + if has_files_tag { inputs.len() } else { 0 };
+        let extra_parameters = || {
+            let parameters: Vec<String> = (0..extra_args.len())
+                .map(|i| parameter(extra_position + i))
+                .collect();
+            parameters.join(" ")
+        };
+        let mut action = render(&segments, |segment| {
+            Ok(match segment {
+                Segment::Input => Some("\"$1\"".to_string()),
+                Segment::Files => {
+                    let position = captures.len() + tokens.len() + 2;
+                    let files: Vec<String> =
+                        (0..inputs.len()).map(|i| parameter(position + i)).collect();
+                    Some(files.join(" "))
+                }
+                Segment::Extra => Some(extra_parameters()),
+                Segment::Capture(tag) => capture_index(tag)
+                    .filter(|i| *i < captures.len())
+                    .map(|i| parameter(i + 2)),
+                Segment::Token(tag) => tokens
+                    .iter()
+                    .position(|(t, _)| t == tag)
+                    .map(|i| parameter(captures.len() + i + 2)),
+                Segment::Text(_) => None,
+            })
+        })?;
+        if !has_file_tag(&segments) {
+            action = format!("{} \"$1\"", action);
+        }
+        if !segments.contains(&Segment::Extra) && !extra_args.is_empty() {
+            action = format!("{} {}", action, extra_parameters());
+        }
+
+        let mut args = vec![inputs[0].to_string()];
+        args.extend(captures);
+        args.extend(tokens.into_iter().map(|(_, value)| value));
+        if has_files_tag {
+            args.extend(inputs.iter().map(|input| input.to_string()));
+        }
+        args.extend(extra_args.iter().cloned());
+        Ok((action, args))
+    }
+
+    /**
+      Substitute in the action the inputs that we matched against, the captures of the Regex
+      (of the first input) and the extra arguments. Return the executable action along with the
+      positional parameters given to the shell.
+    */
+    fn substitute(
+        &self,
+        captures: Vec<String>,
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<(String, Vec<String>)> {
+        let resolved_action = self.resolved_action();
+        let input = inputs[0];
+
+        // scripts and plugins build the command themselves, the extra arguments are appended
+        let with_extra_args = |action: String| -> Result<(String, Vec<String>)> {
+            match extra_args.is_empty() {
+                true => Ok((action, vec![])),
+                false => Ok((format!("{} {}", action, quote_all(extra_args)?), vec![])),
+            }
+        };
+
+        #[cfg(feature = "script")]
+        if self.options.script {
+            return with_extra_args(script::eval_action(resolved_action, input, &captures)?);
+        }
+
+        if let Some(plugin) = &self.options.plugin {
+            let executable_action = plugin
+                .substitute(resolved_action, input)
+                .with_context(|| format!("running plugin '{}'", plugin.name()))?;
+            return with_extra_args(executable_action);
+        }
+
+        let segments = segments(resolved_action);
+        let mut tokens = self.substitutions.values(&segments, input)?;
+        let metadata = [
+            ("%profile", self.profile.clone()),
+            ("%origin", self.config_origin.to_string()),
+        ];
+        for (tag, value) in metadata {
+            if segments.contains(&Segment::Token(tag)) {
+                tokens.push((tag.to_string(), value));
+            }
+        }
+        if self.options.argv {
+            return Self::substitute_argv(resolved_action, captures, tokens, inputs, extra_args);
+        }
+        let executable_action = if self.options.raw {
+            Self::substitute_raw(resolved_action, &captures, &tokens, inputs, extra_args)?
         } else {
-            format!("{} %s", action)
+            Self::substitute_shell(resolved_action, &captures, &tokens, inputs, extra_args)?
         };
+        Ok((executable_action, vec![]))
+    }
+
+    /**
+      Run the matcher command with the input as last argument and on its stdin.
+      It matches when it exits successfully, each line it prints is a capture (%1, %2, ...).
+    */
+    fn run_matcher(&self, input: &str) -> Result<Option<Vec<String>>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", self.pattern_as_str()))
+            .arg("sh")
+            .arg(utils::input_to_os_string(input))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|source| RrrError::ExecFailed {
+                command: self.pattern_as_str().to_string(),
+                source,
+            })?;
+
+        // the matcher may not read its stdin, failing to write it is not an error
+        let mut stdin = child.stdin.take().expect("matcher stdin should be piped");
+        let _ = stdin.write_all(&utils::input_to_bytes(&format!("{}\n", input)));
+        drop(stdin);
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let stdout = utils::input_from_bytes(&output.stdout);
+        Ok(Some(stdout.lines().map(|l| l.to_string()).collect()))
+    }
+
+    /// Check if the exit status of the action means success, according to its success codes.
+    pub fn is_success(&self, status: ExitStatus) -> bool {
+        match status.code() {
+            Some(code) if !self.options.success_codes.is_empty() => {
+                self.options.success_codes.contains(&code)
+            }
+            _ => status.success(),
+        }
+    }
 
-        // replace with the matched input
-        let action_with_input = action_with_tag.replace("%s", &utils::quote(input)?);
+    /// Level of a log about the rule, quiet rules only log at debug level (or trace).
+    pub fn log_level(&self, level: Level) -> Level {
+        match level {
+            _ if !self.options.quiet => level,
+            Level::Error => Level::Error,
+            Level::Warn | Level::Info => Level::Debug,
+            Level::Debug | Level::Trace => Level::Trace,
+        }
+    }
+
+    /**
+      Check if the first bytes of the input file match the content regex. Inputs that are not
+      regular files, or that cannot be read, never match.
+    */
+    fn content_accepts(&self, input: &str) -> bool {
+        let Some(regex) = &self.content_regex else {
+            return false;
+        };
+        let path = utils::input_path(input);
+        if !path.is_file() {
+            return false;
+        }
 
-        Ok(action_with_input)
+        let mut head = Vec::with_capacity(self.options.content_head_size);
+        let read = fs::File::open(path).and_then(|file| {
+            file.take(self.options.content_head_size as u64)
+                .read_to_end(&mut head)
+        });
+        match read {
+            Ok(_) => regex.is_match(&head),
+            Err(e) => {
+                log!(
+                    self.log_level(Level::Debug),
+                    "cannot read '{}' for rule {}: {}",
+                    input,
+                    self.config_origin,
+                    e
+                );
+                false
+            }
+        }
     }
 
-    /// Substitute in the action the captures of the Regex with %1, %2, %3, ...
-    fn substitute_captures(mut action: String, captures: Vec<String>) -> Result<String> {
-        for (i, capture) in captures.iter().enumerate() {
-            let tag = format!("%{}", i + 1); // %1, %2, %3, ...
-            action = action.replace(&tag, &utils::quote(capture)?)
+    /**
+      Check if the matcher accepts the input and return its captures (only a matcher command
+      captures, the other kinds of matcher rules give none).
+    */
+    fn matcher_accepts(&self, input: &str, fields: &InputFields) -> Option<Vec<String>> {
+        let accepted = |accepted: bool| accepted.then(Vec::new);
+        if let Pattern::Field(field, _) = &self.pattern {
+            return accepted(
+                fields
+                    .get(*field)
+                    .zip(self.field_glob.as_ref())
+                    .is_some_and(|(value, glob)| glob.is_match(value)),
+            );
+        }
+        if let Pattern::Content(_) = &self.pattern {
+            return accepted(self.content_accepts(input));
+        }
+        if let Pattern::Binary(formats) = &self.pattern {
+            return accepted(utils::binary_format(&utils::input_path(input)).is_some_and(
+                |format| formats.is_empty() || formats.split('|').any(|f| f == format),
+            ));
+        }
+        if let Pattern::Fuzzy(pattern) = &self.pattern {
+            let score = utils::fuzzy_score(pattern, input, self.case_insensitive);
+            log!(
+                self.log_level(Level::Trace),
+                "fuzzy score of '{}' for '{}': {:.2}",
+                input,
+                pattern,
+                score
+            );
+            return accepted(score >= self.options.fuzzy_score);
+        }
+        if let Pattern::Plugin(plugin) = &self.pattern {
+            return accepted(plugin.matches(input).unwrap_or_else(|e| {
+                log!(
+                    self.log_level(Level::Warn),
+                    "running plugin '{}' ({}): {}",
+                    plugin.name(),
+                    self.config_origin,
+                    e.report()
+                );
+                false
+            }));
         }
 
-        Ok(action)
+        match self.run_matcher(input) {
+            Ok(captures) => captures,
+            Err(e) => {
+                log!(
+                    self.log_level(Level::Warn),
+                    "running matcher '{}' ({}): {}",
+                    self.pattern_as_str(),
+                    self.config_origin,
+                    e.report()
+                );
+                None
+            }
+        }
     }
 
-    /// Substitute in the action the input that we matched against and the captures of the Regex.
-    fn substitute(&self, captures: Vec<String>, input: &str) -> Result<()> {
-        let resolved_action = self.resolved.get().expect("rule must be resolved");
+    /**
+      Check the schedule and the condition of the rule (if any) against the input, with the
+      captures of its matcher when it already accepted the input.
+    */
+    #[cfg_attr(not(feature = "script"), allow(unused_variables))]
+    fn condition_accepts(&self, input: &str, captures: &Option<Vec<String>>) -> bool {
+        if !self.options.schedule.contains(&Local::now()) {
+            log!(
+                self.log_level(Level::Debug),
+                "skipping rule {}: out of its hours or days",
+                self.config_origin
+            );
+            return false;
+        }
 
-        let executable_action = Self::substitute_captures(resolved_action.to_string(), captures)?;
-        let executable_action = Self::substitute_file(executable_action, input)?;
-        self.execution
-            .set(executable_action)
-            .expect("rule should not be ready for execution");
-        Ok(())
+        #[cfg(feature = "script")]
+        if let Some(condition) = &self.options.condition {
+            let result = match captures {
+                Some(captures) => Ok(captures.clone()),
+                None => self.captures(input),
+            }
+            .and_then(|captures| script::eval_condition(condition, input, &captures));
+            return match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log!(
+                        self.log_level(Level::Warn),
+                        "{} ({})",
+                        e.report(),
+                        self.config_origin
+                    );
+                    false
+                }
+            };
+        }
+
+        true
     }
 
     /// Cature the matched regex group into a vector.
     fn captures(&self, input: &str) -> Result<Vec<String>> {
-        // captures is a regex thing, skip if this is a glob pattern
-        if let Pattern::Glob(_) = self.pattern {
+        // captures is a regex thing, skip if this is a glob pattern (or a plugin, field or fuzzy)
+        if let Pattern::Glob(_)
+        | Pattern::Plugin(_)
+        | Pattern::Field(..)
+        | Pattern::Fuzzy(_)
+        | Pattern::Exact(_)
+        | Pattern::Prefix(_)
+        | Pattern::Suffix(_)
+        | Pattern::Content(_)
+        | Pattern::Binary(_) = self.pattern
+        {
             return Ok(vec![]);
         }
 
+        if let Pattern::Matcher(_) = self.pattern {
+            return self.run_matcher(input)?.ok_or_else(|| {
+                RrrError::invalid("The matcher should accept the input in order to capture")
+            });
+        }
+
         // match capture groups of the regex
-        let re = RegexBuilder::new(self.pattern_as_str())
-            .case_insensitive(self.case_insensitive)
-            .build()?;
-        let captures = re
-            .captures(input)
-            .ok_or_else(|| anyhow!("The rule should already match in order to capture"))?;
+        let re = self.regex.as_ref().ok_or_else(|| {
+            RrrError::invalid("The rule regex should be compiled in order to capture")
+        })?;
+        re.captures(input)?
+            .ok_or_else(|| RrrError::invalid("The rule should already match in order to capture"))
+    }
+
+    /**
+      Prepare the rule for execution with proper substitution against the matched input.
+      The rule itself is left untouched, so it can be prepared for several inputs (possibly
+      from several threads) and each prepared action executed independently.
+    */
+    pub fn prepare(&self, input: &str) -> Result<PreparedAction<'_>> {
+        self.prepare_group(&[input])
+    }
+
+    /**
+      Prepare the rule for execution against several inputs at once, %F is substituted with
+      all of them while %s and the captures are those of the first one (see `is_groupable`).
+    */
+    pub fn prepare_group(&self, inputs: &[&str]) -> Result<PreparedAction<'_>> {
+        self.prepare_with_args(inputs, &[])
+    }
 
-        let captures_strings: Vec<String> = captures
+    /**
+      Same as `prepare_group`, with extra arguments for the program (e.g. given after `--` on
+      the command line), substituted with %@ or appended after the input.
+    */
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self), fields(rule = %self.config_origin), err)
+    )]
+    pub fn prepare_with_args(
+        &self,
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<PreparedAction<'_>> {
+        self.prepare_with_captures(inputs, extra_args, None)
+    }
+
+    /// Same as `prepare_with_args`, reusing the captures of the matcher when already known.
+    fn prepare_with_captures(
+        &self,
+        inputs: &[&str],
+        extra_args: &[String],
+        captures: Option<Vec<String>>,
+    ) -> Result<PreparedAction<'_>> {
+        let input = *inputs
+            .first()
+            .ok_or_else(|| RrrError::invalid("Rule cannot be prepared without input"))?;
+
+        let captures = match captures {
+            Some(captures) => captures,
+            None => self.captures(input)?,
+        };
+        // the remote inputs are substituted with their local copies, fetched on execution
+        let fetched: Vec<(String, PathBuf)> = inputs
             .iter()
-            .skip(1) // first capture is the full match (we don't need that)
-            .filter_map(|m| m.map(|m| m.as_str().to_string()))
+            .filter(|input| self.options.fetch && utils::is_remote(input))
+            .map(|input| (input.to_string(), utils::fetch_path(input)))
             .collect();
+        let local_inputs = inputs
+            .iter()
+            .map(|input| match fetched.iter().find(|(url, _)| url == input) {
+                Some((_, path)) => path.to_str().context("temporary path is not UTF-8"),
+                None => Ok(*input),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (action, args) = self.substitute(captures, &local_inputs, extra_args)?;
+        Ok(PreparedAction {
+            rule: self,
+            action,
+            args,
+            fetched,
+        })
+    }
+}
+
+impl<'r> Match<'r> {
+    /// Prepare the matched rule for execution against the input (see `Rule::prepare`).
+    pub fn prepare(&self, input: &str) -> Result<PreparedAction<'r>> {
+        self.prepare_group(&[input])
+    }
+
+    /// Prepare the matched rule against several inputs, the captures are those of the first one.
+    pub fn prepare_group(&self, inputs: &[&str]) -> Result<PreparedAction<'r>> {
+        self.prepare_with_args(inputs, &[])
+    }
+
+    /// Same as `prepare_group`, with extra arguments for the program (see `Rule::prepare_with_args`).
+    pub fn prepare_with_args(
+        &self,
+        inputs: &[&str],
+        extra_args: &[String],
+    ) -> Result<PreparedAction<'r>> {
+        self.rule
+            .prepare_with_captures(inputs, extra_args, self.captures.clone())
+    }
+}
+
+impl<'r> From<&'r Rule> for Match<'r> {
+    fn from(rule: &'r Rule) -> Self {
+        Self {
+            rule,
+            captures: None,
+        }
+    }
+}
+
+impl ops::Deref for Match<'_> {
+    type Target = Rule;
 
-        Ok(captures_strings)
+    fn deref(&self) -> &Rule {
+        self.rule
+    }
+}
+
+impl<'r> PreparedAction<'r> {
+    /// Rule the action was prepared from.
+    pub fn rule(&self) -> &'r Rule {
+        self.rule
+    }
+
+    /// Action substituted and ready for execution.
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    /// Replace the action to execute (e.g. once edited by the user), keeping its parameters.
+    pub fn set_action(&mut self, action: String) {
+        self.action = action;
+    }
+
+    /// Positional parameters given to the shell along the action (see the argv option).
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Check if remote inputs are fetched before execution, the action should then be waited for.
+    pub fn is_fetched(&self) -> bool {
+        !self.fetched.is_empty()
     }
 
-    /// Prepare the rule for execution with proper substitution against the matched file.
-    pub fn prepare(&self, input: &str) -> Result<()> {
-        let captures = self.captures(input)?;
-        self.substitute(captures, input)?;
+    /// Download the remote inputs to the local copies substituted in the action.
+    fn fetch(&self) -> Result<()> {
+        for (url, path) in &self.fetched {
+            log!(
+                self.rule.log_level(Level::Info),
+                "fetching '{}' to '{}'",
+                url,
+                path.display()
+            );
+            utils::fetch(url, path)?;
+        }
         Ok(())
     }
 
-    /// Execute the rule action as a shell command (only returns if there was an error)
-    pub fn exec(&self, execution_type: ExecutionType, sh: &Option<Vec<&str>>) -> Result<()> {
+    /// Remove the local copies of the remote inputs, along with their directory.
+    fn clean_fetched(&self) {
+        let process_dir = utils::process_dir_path();
+        if !utils::is_private_dir(&process_dir) {
+            return;
+        }
+        for (_, path) in &self.fetched {
+            // only the fetch-N directory created by rrr in the one of the process is removed
+            let Some(dir) = path.parent().filter(|dir| {
+                dir.parent() == Some(process_dir.as_path())
+                    && dir
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with("fetch-"))
+            }) else {
+                continue;
+            };
+            if let Err(e) = fs::remove_dir_all(dir)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                warn!("cannot remove '{}': {}", dir.display(), e);
+            }
+        }
+        // the directory of the process is left when used by other inputs (or --stdin-data)
+        let _ = fs::remove_dir(process_dir);
+    }
+
+    /**
+      Spawn the rule action in a child process and return it (see `exec` for the arguments).
+      With `own_process_group` it leads its process group, which `kill_process_group` can kill.
+    */
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(action = %self.action), err)
+    )]
+    pub fn spawn(
+        &self,
+        sh: &Option<Vec<&str>>,
+        env_keep: Option<&[String]>,
+        systemd_scope: bool,
+        own_process_group: bool,
+    ) -> Result<Child> {
+        self.fetch()?;
+        let mut cmd = self.command(sh, env_keep, systemd_scope)?;
+        if own_process_group {
+            cmd.process_group(0); // so that it can be killed along its children on timeout
+        }
+        spawn(&mut cmd)
+    }
+
+    /**
+      Prepare the command executing the rule action (see `exec` for the arguments), so that it
+      can be customized before being spawned, e.g. to pipe its standard streams.
+    */
+    pub fn command(
+        &self,
+        sh: &Option<Vec<&str>>,
+        env_keep: Option<&[String]>,
+        systemd_scope: bool,
+    ) -> Result<Command> {
         let default_shell = vec!["sh", "-c"];
         let shell = sh.as_ref().unwrap_or(&default_shell);
-        let command_to_execute = self
-            .execution
-            .get()
-            .ok_or_else(|| anyhow!("Rule not prepared for execution"))?;
-
-        ensure!(
-            shell.len() > 0,
-            "provided shell should have at least one argument"
-        );
+        let command_to_execute = &self.action;
+
+        if shell.is_empty() {
+            return Err(RrrError::invalid(
+                "provided shell should have at least one argument",
+            ));
+        }
+
+        let mut argv: Vec<String> = if systemd_scope {
+            ["systemd-run", "--user", "--scope", "--quiet", "--"]
+                .map(String::from)
+                .to_vec()
+        } else {
+            vec![]
+        };
+
+        if self.rule.options.raw {
+            let args = shlex::split(command_to_execute).ok_or_else(|| {
+                RrrError::invalid(format!("invalid quoting in '{}'", command_to_execute))
+            })?;
+            if args.is_empty() {
+                return Err(RrrError::invalid(
+                    "raw action should have at least one argument",
+                ));
+            }
+            argv.extend(args);
+        } else {
+            argv.extend(shell.iter().map(|s| s.to_string()));
+            argv.push(command_to_execute.to_string());
+            if !self.args.is_empty() {
+                // "--" is the name of the shell ($0), the arguments start at $1
+                argv.push("--".to_string());
+                argv.extend(self.args.iter().cloned());
+            }
+        }
 
-        let mut cmd = Command::new(shell[0]);
-        cmd.args(&shell[1..]).arg(command_to_execute);
+        // the inputs that are not valid UTF-8 are given back their original bytes
+        let mut cmd = Command::new(utils::input_to_os_string(&argv[0]));
+        cmd.args(argv[1..].iter().map(|arg| utils::input_to_os_string(arg)));
 
-        let mut wait_success = |ignore_signals: bool| -> Result<()> {
-            let mut child = cmd.spawn()?;
-            let status = child.wait()?;
-            if status.success() {
+        if let Some(env_keep) = env_keep {
+            cmd.env_clear();
+            for var in env_keep {
+                if let Some(value) = env::var_os(var) {
+                    cmd.env(var, value);
+                }
+            }
+        }
+
+        Ok(cmd)
+    }
+
+    /**
+      Execute the rule action as a shell command (only returns if there was an error).
+      With `env_keep` the action is executed in a clean environment that only contains
+      the listed variables. With `systemd_scope` the action is executed in its own transient
+      systemd scope unit, so that it gets its own cgroup and outlives rrr's session.
+      When waiting for the action, its process group is killed if it runs longer than `timeout`.
+      In fork mode, a handle on the running action is returned (None when it was waited for).
+      The remote inputs are fetched first, their local copies are removed once the action is
+      waited for (they are left to the action otherwise).
+    */
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(action = %self.action), err)
+    )]
+    pub fn exec(
+        &self,
+        execution_type: ExecutionType,
+        sh: &Option<Vec<&str>>,
+        env_keep: Option<&[String]>,
+        systemd_scope: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ExecHandle>> {
+        let mut cmd = self.command(sh, env_keep, systemd_scope)?;
+        if timeout.is_some() {
+            cmd.process_group(0); // so that it can be killed along its children on timeout
+        }
+        if let Err(e) = self.fetch() {
+            self.clean_fetched();
+            return Err(e);
+        }
+
+        let ignore_signals = match execution_type {
+            ExecutionType::Exec => {
+                let source = cmd.exec();
+                return Err(RrrError::ExecFailed {
+                    command: cmd.get_program().to_string_lossy().into_owned(),
+                    source,
+                });
+            }
+            ExecutionType::Fork => return Ok(Some(spawn(&mut cmd)?.into())),
+            ExecutionType::WaitSuccess => false,
+            ExecutionType::WaitSuccessSignalOk => true,
+        };
+
+        let mut wait_success = || -> Result<()> {
+            let mut child = spawn(&mut cmd)?;
+            let status = match timeout {
+                Some(timeout) => wait_timeout(&mut child, timeout)?,
+                None => child.wait()?,
+            };
+            if self.rule.is_success(status) {
                 Ok(())
             } else {
                 if let Some(signal) = status.signal() {
                     if ignore_signals {
                         Ok(())
                     } else {
-                        Err(anyhow!("process killed by signal {}", signal))
+                        Err(RrrError::Signal(signal))
                     }
                 } else {
-                    Err(anyhow!(
-                        "process exited with code {}",
+                    Err(RrrError::ExitCode(
                         status
                             .code()
-                            .expect("not killed by signal => code must exist")
+                            .expect("not killed by signal => code must exist"),
                     ))
                 }
             }
         };
 
-        match execution_type {
-            ExecutionType::Exec => Err(cmd.exec())?,
-            ExecutionType::Fork => Ok(cmd.spawn().map(|_| ())?),
-            ExecutionType::WaitSuccess => wait_success(false),
-            ExecutionType::WaitSuccessSignalOk => wait_success(true),
-        }
+        let RetryPolicy {
+            retries,
+            mut backoff,
+        } = self.rule.options.retry;
+        let mut wait_retries = || -> Result<()> {
+            for attempt in 1..=retries {
+                match wait_success() {
+                    Ok(()) => return Ok(()),
+                    Err(e) => log!(
+                        self.rule.log_level(Level::Warn),
+                        "attempt {} of {} failed for rule {}: {}, retrying in {:?}",
+                        attempt,
+                        retries + 1,
+                        self.rule.config_origin,
+                        e.report(),
+                        backoff
+                    ),
+                }
+                thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            wait_success()
+        };
+        let result = wait_retries();
+        self.clean_fetched();
+        result.map(|()| None)
     }
 }
 
-/* todo: add unit test for RuleSetBuilder and RuleSet, test matching, substitution and eventually
-   execution
+/**
+  Wait for the child until the timeout, after which its process group is killed. A timeout too
+  far to be represented is waited for without deadline.
 */
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus> {
+    let Some(deadline) = Instant::now().checked_add(timeout) else {
+        return Ok(child.wait()?);
+    };
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    kill_process_group(child);
+    child.wait()?;
+    Err(RrrError::Timeout(timeout))
+}
+
+/// Spawn the command, reporting the program that cannot be executed.
+fn spawn(cmd: &mut Command) -> Result<Child> {
+    cmd.spawn().map_err(|source| RrrError::ExecFailed {
+        command: cmd.get_program().to_string_lossy().into_owned(),
+        source,
+    })
+}
+
+/// Kill the child along with the processes of its group (it must have been spawned as its leader).
+pub fn kill_process_group(child: &Child) {
+    // SAFETY: kill() has no memory safety requirements, a negative pid targets the process group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Captures of a regex with ten groups, to check that %10 is not %1 followed by a 0.
+    const TEN_CAPTURES: &[&str] = &["c1", "c2", "c3", "c4", "c5", "c6", "c7", "c8", "c9", "c10"];
+
+    /// Action, captures, inputs and extra arguments, then the substituted action.
+    type Case<'a> = (
+        &'a str,
+        &'a [&'a str],
+        &'a [&'a str],
+        &'a [&'a str],
+        &'a str,
+    );
+
+    /// Same as `Case`, then the positional parameters given to the shell.
+    type ArgvCase<'a> = (
+        &'a str,
+        &'a [&'a str],
+        &'a [&'a str],
+        &'a [&'a str],
+        &'a str,
+        &'a [&'a str],
+    );
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn host_token() -> Vec<(String, String)> {
+        vec![("%{host}".to_string(), "example.com".to_string())]
+    }
+
+    #[test]
+    fn segments_split_tokens_in_a_single_pass() {
+        use Segment::*;
+
+        let cases: &[(&str, &[Segment])] = &[
+            ("", &[]),
+            ("echo", &[Text("echo")]),
+            ("echo %s", &[Text("echo "), Input]),
+            ("%F %@", &[Files, Text(" "), Extra]),
+            ("%1%2", &[Capture("%1"), Capture("%2")]),
+            ("%10", &[Capture("%10")]),
+            ("%123x", &[Capture("%123"), Text("x")]),
+            ("%x.%profile", &[Token("%x"), Text("."), Token("%profile")]),
+            ("%origin", &[Token("%origin")]),
+            ("%{ENV:HOME:-/}", &[Token("%{ENV:HOME:-/}")]),
+            ("100%%", &[Text("100"), Text("%")]),
+            ("%%s", &[Text("%"), Text("s")]),
+            ("50% off", &[Text("50"), Text("%"), Text(" off")]),
+            ("%", &[Text("%")]),
+            ("%0", &[Text("%"), Text("0")]),
+            ("%pro", &[Text("%"), Text("pro")]),
+            ("%{host %s", &[Text("%"), Text("{host "), Input]),
+        ];
+        for (action, expected) in cases {
+            assert_eq!(segments(action), *expected, "segments of '{}'", action);
+        }
+    }
+
+    #[test]
+    fn substitute_shell_quotes_the_values() {
+        let cases: &[Case] = &[
+            ("echo %s", &[], &["a b"], &[], "echo 'a b'"),
+            ("echo", &[], &["a b"], &[], "echo 'a b'"),
+            ("echo %F", &[], &["a", "b c"], &[], "echo a 'b c'"),
+            ("echo %s", &[], &["a"], &["-v", "x y"], "echo a -v 'x y'"),
+            ("echo %@ %s", &[], &["a"], &["-v"], "echo -v a"),
+            ("echo %1 %s", &["c1"], &["a"], &[], "echo c1 a"),
+            ("echo %10 %1", TEN_CAPTURES, &["a"], &[], "echo c10 c1 a"),
+            ("echo %3 %s", &["c1"], &["a"], &[], "echo %3 a"),
+            ("echo %s", &["c1"], &["%1"], &[], "echo '%1'"),
+            ("echo %1 %s", &["%s"], &["a"], &[], "echo '%s' a"),
+            ("printf 100%% %s", &[], &["a"], &[], "printf 100% a"),
+            ("echo %{host", &[], &["a"], &[], "echo %{host a"),
+        ];
+        for (action, captures, inputs, extra_args, expected) in cases {
+            let substituted = Rule::substitute_shell(
+                action,
+                &strings(captures),
+                &[],
+                inputs,
+                &strings(extra_args),
+            );
+            assert_eq!(
+                substituted.unwrap(),
+                *expected,
+                "substitution of '{}'",
+                action
+            );
+        }
+
+        let substituted =
+            Rule::substitute_shell("open %{host} %{path}", &[], &host_token(), &["u"], &[]);
+        assert_eq!(substituted.unwrap(), "open example.com %{path} u");
+    }
+
+    #[test]
+    fn substitute_raw_keeps_the_arguments_apart() {
+        let cases: &[Case] = &[
+            ("cmd --name=%s", &[], &["a b"], &[], "cmd '--name=a b'"),
+            ("cmd", &[], &["a b"], &[], "cmd 'a b'"),
+            ("cmd %F", &[], &["a", "b c"], &[], "cmd a 'b c'"),
+            ("cmd -- %@ %s", &[], &["a"], &["-v", "x"], "cmd -- -v x a"),
+            ("cmd %s", &[], &["a"], &["-v"], "cmd a -v"),
+            ("cmd %10 %s", TEN_CAPTURES, &["a"], &[], "cmd c10 a"),
+            ("cmd %s", &["c1"], &["%1"], &[], "cmd '%1'"),
+            ("cmd 100%% %s", &[], &["a"], &[], "cmd '100%' a"),
+        ];
+        for (action, captures, inputs, extra_args, expected) in cases {
+            let substituted = Rule::substitute_raw(
+                action,
+                &strings(captures),
+                &[],
+                inputs,
+                &strings(extra_args),
+            );
+            assert_eq!(
+                substituted.unwrap(),
+                *expected,
+                "substitution of '{}'",
+                action
+            );
+        }
+
+        let substituted = Rule::substitute_raw("open %{host}", &[], &host_token(), &["u"], &[]);
+        assert_eq!(substituted.unwrap(), "open example.com u");
+    }
+
+    #[test]
+    fn substitute_argv_passes_the_values_as_parameters() {
+        let cases: &[ArgvCase] = &[
+            ("echo %s", &[], &["a b"], &[], r#"echo "$1""#, &["a b"]),
+            ("echo", &[], &["a"], &[], r#"echo "$1""#, &["a"]),
+            (
+                "echo %2 %s",
+                &["c1"],
+                &["a"],
+                &[],
+                r#"echo %2 "$1""#,
+                &["a", "c1"],
+            ),
+            (
+                "echo 100%% %s",
+                &[],
+                &["a"],
+                &[],
+                r#"echo 100% "$1""#,
+                &["a"],
+            ),
+            (
+                "echo %s",
+                &[],
+                &["a"],
+                &["-v"],
+                r#"echo "$1" "${2}""#,
+                &["a", "-v"],
+            ),
+            (
+                "cat %F",
+                &[],
+                &["a", "b"],
+                &["-v"],
+                r#"cat "${2}" "${3}" "${4}""#,
+                &["a", "a", "b", "-v"],
+            ),
+        ];
+        for (action, captures, inputs, extra_args, expected, expected_args) in cases {
+            let (substituted, args) = Rule::substitute_argv(
+                action,
+                strings(captures),
+                vec![],
+                inputs,
+                &strings(extra_args),
+            )
+            .unwrap();
+            assert_eq!(substituted, *expected, "substitution of '{}'", action);
+            assert_eq!(args, strings(expected_args), "parameters of '{}'", action);
+        }
+
+        let (substituted, args) =
+            Rule::substitute_argv("echo %10 %s", strings(TEN_CAPTURES), vec![], &["a"], &[])
+                .unwrap();
+        assert_eq!(substituted, r#"echo "${11}" "$1""#);
+        assert_eq!(args[0], "a");
+        assert_eq!(args[1..], strings(TEN_CAPTURES));
+
+        let (substituted, args) =
+            Rule::substitute_argv("open %{host} %s", vec![], host_token(), &["u"], &[]).unwrap();
+        assert_eq!(substituted, r#"open "${2}" "$1""#);
+        assert_eq!(args, strings(&["u", "example.com"]));
+    }
+
+    /// Whether the glob written with the extended syntax matches the input, as in the rule sets.
+    fn extended_glob_matches(glob: &str, input: &str) -> bool {
+        let glob = extended_glob(glob).unwrap();
+        let glob = GlobBuilder::new(&glob).empty_alternates(true).build();
+        glob.unwrap().compile_matcher().is_match(input)
+    }
+
+    #[test]
+    fn extended_glob_translates_to_globset() {
+        let cases = [
+            ("*.pdf", "*.pdf"),
+            ("[[:digit:]]*", "[0-9]*"),
+            ("[[:alpha:][:digit:]_]", "[a-zA-Z0-9_]"),
+            ("[![:space:]]", "[! \t\n\r]"),
+            ("[]@(]", "[]@(]"),
+            ("[[]", "[[]"),
+            ("*.@(jpg|png)", "*.{jpg,png}"),
+            ("*.?(tar.)gz", "*.{tar.,}gz"),
+            ("@(a|?(b|c))", "{a,{b,c,}}"),
+            ("@(a,b|c)", "{a\\,b,c}"),
+            ("a|b,c)", "a|b,c)"),
+            ("\\@(a)", "\\@(a)"),
+            ("@([[:digit:]]|x)", "{[0-9],x}"),
+        ];
+        for (glob, expected) in cases {
+            assert_eq!(
+                extended_glob(glob).unwrap(),
+                expected,
+                "translation of '{}'",
+                glob
+            );
+        }
+    }
+
+    #[test]
+    fn extended_glob_matches_like_ksh() {
+        let cases = [
+            ("*.?(tar.)gz", "a.gz", true),
+            ("*.?(tar.)gz", "a.tar.gz", true),
+            ("*.?(tar.)gz", "a.zip.gz", true), // the star matches 'a.zip'
+            ("?(tar.)gz", "zip.gz", false),
+            (
+                "IMG_[[:digit:]][[:digit:]].@(jpg|jpeg)",
+                "IMG_42.jpeg",
+                true,
+            ),
+            (
+                "IMG_[[:digit:]][[:digit:]].@(jpg|jpeg)",
+                "IMG_4x.jpg",
+                false,
+            ),
+            ("@(a|?(b|c))d", "d", true),
+            ("@(a|?(b|c))d", "cd", true),
+            ("@(a|?(b|c))d", "bcd", false),
+            ("@(a,b|c)", "a,b", true),
+            ("@(a,b|c)", "a", false),
+            ("[![:upper:]]*", "readme", true),
+            ("[![:upper:]]*", "README", false),
+        ];
+        for (glob, input, expected) in cases {
+            assert_eq!(
+                extended_glob_matches(glob, input),
+                expected,
+                "'{}' matching '{}'",
+                glob,
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn extended_glob_rejects_invalid_patterns() {
+        let cases = [
+            ("[[:digit", "unclosed character class"),
+            ("[[:foo:]]", "unknown character class '[:foo:]'"),
+            ("!(a)", "unsupported pattern '!('"),
+            ("*(a|b)", "unsupported pattern '*('"),
+            ("@(a|+(b))", "unsupported pattern '+('"),
+            ("@(a|b", "unclosed pattern"),
+            ("@(a|?(b)", "unclosed pattern"),
+        ];
+        for (glob, expected) in cases {
+            match extended_glob(glob) {
+                Err(RrrError::InvalidPattern { pattern, reason }) => {
+                    assert_eq!(pattern, glob);
+                    assert_eq!(reason, expected, "error of '{}'", glob);
+                }
+                result => panic!("'{}' should be invalid, got {:?}", glob, result),
+            }
+        }
+    }
+
+    /// Rules of every kind matching 'report.pdf' (and one that does not), declared in this order.
+    fn declared_rule_set(first_match: bool, regex_first: bool) -> RuleSet {
+        let mut builder = RuleSetBuilder::new("default".to_string(), false);
+        builder
+            .add_rule(Pattern::Glob("*.pdf".to_string()), "glob-1")
+            .add_rule(Pattern::Regex(r"^report\.".to_string()), "regex-1")
+            .add_rule(Pattern::Fuzzy("report".to_string()), "fuzzy")
+            .add_rule(Pattern::Suffix(".pdf".to_string()), "suffix")
+            .add_rule(Pattern::Glob("report.*".to_string()), "glob-2")
+            .add_rule(Pattern::Glob("*.txt".to_string()), "unmatched")
+            .add_rule(Pattern::Regex(r"\.pdf$".to_string()), "regex-2");
+        let settings = Settings {
+            first_match,
+            regex_first,
+            ..Settings::default()
+        };
+        builder.build(&settings, &Arc::default()).unwrap()
+    }
+
+    fn command<'a>(r#match: &Match<'a>) -> &'a str {
+        match &r#match.rule.action {
+            Action::Command(command) => command,
+            Action::Alias(alias) => alias,
+        }
+    }
+
+    #[test]
+    fn matches_follow_the_declaration_order_across_kinds() {
+        let cases: [(bool, bool, &[&str]); 4] = [
+            (
+                false,
+                false,
+                &["suffix", "regex-2", "glob-2", "fuzzy", "regex-1", "glob-1"],
+            ),
+            (
+                true,
+                false,
+                &["suffix", "glob-1", "regex-1", "fuzzy", "glob-2", "regex-2"],
+            ),
+            (
+                false,
+                true,
+                &["suffix", "regex-2", "regex-1", "glob-2", "fuzzy", "glob-1"],
+            ),
+            (
+                true,
+                true,
+                &["suffix", "regex-1", "regex-2", "glob-1", "fuzzy", "glob-2"],
+            ),
+        ];
+        for (first_match, regex_first, expected) in cases {
+            let rule_set = declared_rule_set(first_match, regex_first);
+            let settings = format!("first_match={} regex_first={}", first_match, regex_first);
+
+            // the exact and affix rules are not interleaved with the others
+            let declared: Vec<&str> = rule_set
+                .matches_declared("report.pdf", &NO_FIELDS)
+                .map(|r#match| command(&r#match))
+                .collect();
+            assert_eq!(
+                declared,
+                expected[1..],
+                "declared matches with {}",
+                settings
+            );
+
+            let ranked: Vec<(usize, Match)> = rule_set.ranked_matches("report.pdf").collect();
+            let commands: Vec<&str> = ranked.iter().map(|(_, r#match)| command(r#match)).collect();
+            assert_eq!(commands, expected, "ranked matches with {}", settings);
+            assert_eq!(
+                rule_set
+                    .r#match("report.pdf")
+                    .map(|r#match| command(&r#match)),
+                Some(expected[0]),
+                "match with {}",
+                settings
+            );
+
+            // the ranks are the indices in the rules, in precedence order
+            let rules: Vec<&Rule> = rule_set.rules().collect();
+            assert!(
+                ranked.windows(2).all(|pair| pair[0].0 < pair[1].0),
+                "{}",
+                settings
+            );
+            for (rank, r#match) in &ranked {
+                assert!(
+                    std::ptr::eq(rules[*rank], r#match.rule),
+                    "rank of {}",
+                    command(r#match)
+                );
+            }
+        }
+    }
+
+    /// Valid rules along with an invalid regex, an invalid glob and a rule with an unknown alias.
+    fn invalid_rule_set(lenient: bool) -> Result<RuleSet> {
+        let alias = |alias: &str| Action::Alias(alias.to_string());
+        let mut builder = RuleSetBuilder::new("default".to_string(), false);
+        builder
+            .add_alias("[viewer]", "zathura")
+            .add_rule(Pattern::Regex(r"\.pdf$".to_string()), "pdf")
+            .add_rule(Pattern::Regex("(unclosed".to_string()), "regex")
+            .add_rule(Pattern::Glob("*.txt".to_string()), "txt")
+            .add_rule(Pattern::Glob("a[".to_string()), "glob")
+            .add_rule_with_options(
+                Pattern::Glob("*.djvu".to_string()),
+                alias("[reader]"),
+                RuleOptions::default(),
+            )
+            .add_rule_with_options(
+                Pattern::Glob("*.ps".to_string()),
+                alias("[viewer]"),
+                RuleOptions::default(),
+            );
+        let settings = Settings {
+            lenient,
+            ..Settings::default()
+        };
+        builder.build(&settings, &Arc::default())
+    }
+
+    #[test]
+    fn build_reports_every_invalid_rule() {
+        let Err(RrrError::InvalidRules { profile, errors }) = invalid_rule_set(false) else {
+            panic!("the invalid rules should be reported");
+        };
+        assert_eq!(profile, "default");
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        let expected = [
+            "Alias '[reader]' does not exist in profile 'default'",
+            "invalid pattern '(unclosed'",
+            "invalid pattern 'a['",
+        ];
+        for expected in expected {
+            let error = errors.iter().find(|error| error.contains(expected));
+            let error = error.unwrap_or_else(|| panic!("'{}' not in {:?}", expected, errors));
+            // each error starts with the origin of its rule
+            assert!(error.starts_with(file!()), "{}", error);
+        }
+    }
+
+    #[test]
+    fn build_skips_the_invalid_rules_when_lenient() {
+        let rule_set = invalid_rule_set(true).unwrap();
+        let mut commands: Vec<&str> = rule_set
+            .rules()
+            .map(|rule| rule.resolved.get().unwrap().as_str())
+            .collect();
+        commands.sort();
+        assert_eq!(commands, ["pdf", "txt", "zathura"]);
+
+        let matched = |input| rule_set.r#match(input).map(|r#match| command(&r#match));
+        assert_eq!(matched("a.pdf"), Some("pdf"));
+        assert_eq!(matched("a.ps"), Some("[viewer]"));
+        assert_eq!(matched("a.djvu"), None);
+        assert_eq!(matched("unclosed"), None);
+    }
+}
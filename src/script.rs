@@ -0,0 +1,104 @@
+use std::{env, fs, path::Path};
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope};
+
+use crate::error::{Result, RrrError};
+
+/// Number of operations (roughly) a script can execute in an evaluation.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Deepest nesting of the expressions of a script, and of those of its functions.
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+
+/// Largest string a script can build.
+const MAX_STRING_SIZE: usize = 1024 * 1024;
+
+/**
+  Engine with the helpers available to the scripts. As the plugins, the scripts are bounded so
+  that a faulty guard (e.g. `loop {}`) fails the match instead of hanging rrr.
+*/
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE);
+    engine.register_fn("env", |name: &str| env::var(name).unwrap_or_default());
+    engine
+}
+
+/**
+  Variables available to the scripts: the `input`, the `captures` of the pattern
+  and some metadata about the input when it is a file (`exists`, `is_file`, `is_dir`,
+  `size` and `extension`).
+*/
+fn scope(input: &str, captures: &[String]) -> Scope<'static> {
+    let metadata = fs::metadata(input).ok();
+    let extension = Path::new(input)
+        .extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut scope = Scope::new();
+    scope.push("input", input.to_string());
+    scope.push(
+        "captures",
+        captures
+            .iter()
+            .cloned()
+            .map(Dynamic::from)
+            .collect::<Array>(),
+    );
+    scope.push("exists", metadata.is_some());
+    scope.push("is_file", metadata.as_ref().is_some_and(|m| m.is_file()));
+    scope.push("is_dir", metadata.as_ref().is_some_and(|m| m.is_dir()));
+    scope.push("size", metadata.map(|m| m.len() as i64).unwrap_or(0));
+    scope.push("extension", extension);
+    scope
+}
+
+/// Check that the script compiles.
+pub fn check(script: &str) -> Result<()> {
+    engine()
+        .compile(script)
+        .map(|_| ())
+        .map_err(|e| script_error("invalid script", script, e))
+}
+
+/// Evaluate a rule condition, the rule only matches when it is true.
+pub fn eval_condition(script: &str, input: &str, captures: &[String]) -> Result<bool> {
+    engine()
+        .eval_with_scope::<bool>(&mut scope(input, captures), script)
+        .map_err(|e| script_error("evaluating condition", script, eval_reason(*e)))
+}
+
+/// Evaluate an action script, it returns the command to execute.
+pub fn eval_action(script: &str, input: &str, captures: &[String]) -> Result<String> {
+    engine()
+        .eval_with_scope::<String>(&mut scope(input, captures), script)
+        .map_err(|e| script_error("evaluating action script", script, eval_reason(*e)))
+}
+
+/// Why the evaluation of a script failed, telling which limit was reached if any.
+fn eval_reason(error: EvalAltResult) -> String {
+    match error {
+        EvalAltResult::ErrorTooManyOperations(position) => format!(
+            "exceeded the limit of {} operations ({})",
+            MAX_OPERATIONS, position
+        ),
+        EvalAltResult::ErrorDataTooLarge(data, position) => format!(
+            "{} exceeded the limit of {} bytes ({})",
+            data, MAX_STRING_SIZE, position
+        ),
+        error => error.to_string(),
+    }
+}
+
+fn script_error(context: &'static str, script: &str, reason: impl ToString) -> RrrError {
+    RrrError::Script {
+        context,
+        script: script.to_string(),
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    env, fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::{BorrowedFd, FromRawFd, OwnedFd},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Context, Result, RrrError};
+
+/// Largest accepted request body (inputs are paths and URIs, this is plenty).
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Largest accepted request line and headers, altogether.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// Time given to a client to send its whole request (not each read of it).
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval between accept attempts while checking for the idle timeout.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// First file descriptor passed by the service manager (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: i32 = 3;
+
+/// Upper bounds of the buckets of the request duration histogram, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Body of a `POST /open` request.
+#[derive(Debug, Deserialize)]
+pub struct OpenRequest {
+    pub input: String,
+    pub profile: Option<String>, // profile of the server when missing
+    #[serde(default)]
+    pub query: bool, // only resolve the action, do not execute it
+}
+
+/// Action resolved by the handler of a request, along with the rule it comes from.
+pub struct Opened {
+    pub action: String,
+    pub origin: String,  // where the rule was declared (e.g. "/etc/rrr.conf:3:1")
+    pub pattern: String, // pattern of the rule, as written in the config
+}
+
+#[derive(Serialize)]
+struct OpenResponse<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/**
+  Tiny HTTP endpoint dispatching the `POST /open` requests to a handler.
+  Clients authenticate with an `Authorization: Bearer <token>` header.
+  Connections are handled one at a time, which is enough for local integrations.
+*/
+pub struct Server {
+    listener: TcpListener,
+    token: String,
+    idle_timeout: Option<Duration>, // stop serving after this long without any request
+    metrics: RefCell<Metrics>,
+}
+
+/// Counters of the `POST /open` requests, served on `GET /metrics` in the Prometheus format.
+#[derive(Default)]
+struct Metrics {
+    requests: u64,
+    matches: u64,
+    no_matches: u64,
+    failures: u64,                              // requests whose handler failed
+    rule_hits: BTreeMap<(String, String), u64>, // by origin and pattern of the rule
+    durations: [u64; DURATION_BUCKETS.len()],   // requests handled within each bucket
+    duration_sum: f64,                          // in seconds
+}
+
+impl Server {
+    pub fn bind(addr: &str, token: &str) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("cannot listen on '{}'", addr))?;
+        Ok(Self::from_listener(listener, token))
+    }
+
+    pub fn from_listener(listener: TcpListener, token: &str) -> Self {
+        Server {
+            listener,
+            token: token.to_string(),
+            idle_timeout: None,
+            metrics: RefCell::default(),
+        }
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /**
+      Serve the requests until the idle timeout (forever without one). The handler returns
+      the action for the request, or `None` when no rule matches the input.
+    */
+    pub fn serve<F, E>(&self, mut handler: F) -> Result<()>
+    where
+        F: FnMut(&OpenRequest) -> Result<Option<Opened>, E>,
+        E: fmt::Display,
+    {
+        while let Some(stream) = self.accept()? {
+            if let Err(e) = self.handle_connection(stream, &mut handler) {
+                warn!("cannot handle request: {}", e.report());
+            }
+        }
+        info!(
+            "no request for {:?}, exiting",
+            self.idle_timeout.unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    /// Wait for the next connection, `None` once the idle timeout expired.
+    fn accept(&self) -> Result<Option<TcpStream>> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            let (stream, _) = self.listener.accept().context("accepting connection")?;
+            return Ok(Some(stream));
+        };
+
+        // std cannot wait for a connection with a timeout, poll the listener instead
+        self.listener.set_nonblocking(true)?;
+        let idle_since = Instant::now();
+        while idle_since.elapsed() < idle_timeout {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    return Ok(Some(stream));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(IDLE_POLL_INTERVAL)
+                }
+                Err(e) => return Err(e).context("accepting connection"),
+            }
+        }
+        Ok(None)
+    }
+
+    fn handle_connection<F, E>(&self, mut stream: TcpStream, handler: &mut F) -> Result<()>
+    where
+        F: FnMut(&OpenRequest) -> Result<Option<Opened>, E>,
+        E: fmt::Display,
+    {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut head = (&mut reader).take(MAX_HEAD_SIZE);
+
+        let request_line = read_head_line(&stream, &mut head, deadline)?;
+        let mut request_line = request_line.split_whitespace();
+        let (method, path) = match (request_line.next(), request_line.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(RrrError::BadRequest("malformed request line".to_string())),
+        };
+        debug!("{} {}", method, path);
+
+        let mut content_length = 0;
+        let mut authorized = false;
+        loop {
+            let header = read_head_line(&stream, &mut head, deadline)?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(RrrError::BadRequest(format!(
+                    "malformed header '{}'",
+                    header
+                )));
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .parse()
+                    .map_err(|_| RrrError::BadRequest("invalid content length".to_string()))?;
+            } else if name.eq_ignore_ascii_case("authorization")
+                && let Some(token) = value.strip_prefix("Bearer ")
+            {
+                authorized = constant_time_eq(token.as_bytes(), self.token.as_bytes());
+            }
+        }
+
+        let allowed_method = match path.as_str() {
+            "/open" => "POST",
+            "/metrics" => "GET",
+            _ => {
+                return respond(
+                    &mut stream,
+                    404,
+                    "Not Found",
+                    None,
+                    Some("unknown endpoint"),
+                );
+            }
+        };
+        if method != allowed_method {
+            let error = format!("only {} is allowed", allowed_method);
+            return respond(&mut stream, 405, "Method Not Allowed", None, Some(&error));
+        }
+        if !authorized {
+            return respond(
+                &mut stream,
+                401,
+                "Unauthorized",
+                None,
+                Some("missing or invalid token"),
+            );
+        }
+        if path == "/metrics" {
+            let body = self.metrics.borrow().to_string();
+            return write_response(&mut stream, 200, "OK", METRICS_CONTENT_TYPE, &body);
+        }
+        if content_length > MAX_BODY_SIZE {
+            return respond(
+                &mut stream,
+                413,
+                "Payload Too Large",
+                None,
+                Some("request too large"),
+            );
+        }
+
+        let mut body = vec![0; content_length];
+        set_deadline(&stream, deadline)?;
+        reader.read_exact(&mut body)?;
+        let request: OpenRequest = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = format!("invalid request: {}", e);
+                return respond(&mut stream, 400, "Bad Request", None, Some(&error));
+            }
+        };
+
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::info_span!("request", input = %request.input, profile = ?request.profile)
+                .entered();
+        let start = Instant::now();
+        let result = handler(&request);
+        self.metrics.borrow_mut().record(&result, start.elapsed());
+        match result {
+            Ok(Some(opened)) => respond(&mut stream, 200, "OK", Some(&opened.action), None),
+            Ok(None) => respond(&mut stream, 404, "Not Found", None, Some("no match")),
+            Err(e) => {
+                let error = format!("{:#}", e);
+                respond(
+                    &mut stream,
+                    500,
+                    "Internal Server Error",
+                    None,
+                    Some(&error),
+                )
+            }
+        }
+    }
+}
+
+/**
+  Read a line of the request head (the request line or a header), failing when the head
+  is too large or when the deadline of the request expires.
+*/
+fn read_head_line(
+    stream: &TcpStream,
+    head: &mut io::Take<&mut BufReader<TcpStream>>,
+    deadline: Instant,
+) -> Result<String> {
+    set_deadline(stream, deadline)?;
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        let error = match head.limit() {
+            0 => "request head too large",
+            _ => "connection closed in headers",
+        };
+        return Err(RrrError::BadRequest(error.to_string()));
+    }
+    Ok(line)
+}
+
+/// Bound the next reads of the request by the time left before its deadline.
+fn set_deadline(stream: &TcpStream, deadline: Instant) -> Result<()> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout.is_zero() {
+        return Err(RrrError::BadRequest(
+            "timed out reading the request".to_string(),
+        ));
+    }
+    stream.set_read_timeout(Some(timeout))?;
+    Ok(())
+}
+
+/**
+  Take the listening socket passed by the service manager with the systemd LISTEN_FDS protocol
+  (socket activation), if any. The variables of the protocol are removed from the environment
+  and the socket is not inherited by the executed actions.
+*/
+pub fn activation_listener() -> Result<Option<TcpListener>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    // SAFETY: called while starting, before any other thread could read the environment
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(None);
+    };
+    if listen_pid.parse::<u32>().ok() != Some(process::id()) {
+        return Ok(None); // meant for another process
+    }
+    let listen_fds: usize = listen_fds.parse().ok().context("invalid LISTEN_FDS")?;
+    if listen_fds != 1 {
+        return Err(RrrError::invalid(format!(
+            "expected a single socket from the service manager, got {}",
+            listen_fds
+        )));
+    }
+
+    // SAFETY: the protocol guarantees that the descriptor is open and ours
+    let fd = unsafe { BorrowedFd::borrow_raw(LISTEN_FDS_START) }
+        .try_clone_to_owned()
+        .context("duplicating the activation socket")?; // the duplicate is close-on-exec
+    // SAFETY: nothing else owns the original descriptor, it is only closed once (here) as the
+    // duplicate replaces it
+    drop(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) });
+
+    let listener = TcpListener::from(fd);
+    let addr = listener
+        .local_addr()
+        .context("the activation socket is not a TCP socket")?;
+    info!("listening on '{}' (socket activation)", addr);
+    Ok(Some(listener))
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    code: u16,
+    reason: &str,
+    action: Option<&str>,
+    error: Option<&str>,
+) -> Result<()> {
+    let body = serde_json::to_string(&OpenResponse { action, error })?;
+    write_response(stream, code, reason, "application/json", &body)
+}
+
+/// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+fn write_response(
+    stream: &mut TcpStream,
+    code: u16,
+    reason: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+impl Metrics {
+    /// Count a request handled in `duration`, along with the rule that matched it (if any).
+    fn record<E>(&mut self, result: &Result<Option<Opened>, E>, duration: Duration) {
+        self.requests += 1;
+        match result {
+            Ok(Some(opened)) => {
+                self.matches += 1;
+                let rule = (opened.origin.clone(), opened.pattern.clone());
+                *self.rule_hits.entry(rule).or_default() += 1;
+            }
+            Ok(None) => self.no_matches += 1,
+            Err(_) => self.failures += 1,
+        }
+
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&mut self.durations) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.duration_sum += seconds;
+    }
+}
+
+/// Metrics in the Prometheus text exposition format.
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counters = [
+            ("requests", "Requests to open an input.", self.requests),
+            (
+                "matches",
+                "Requests whose input matched a rule.",
+                self.matches,
+            ),
+            (
+                "no_matches",
+                "Requests whose input matched no rule.",
+                self.no_matches,
+            ),
+            (
+                "failures",
+                "Requests that could not be resolved or executed.",
+                self.failures,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(f, "# HELP rrr_{}_total {}", name, help)?;
+            writeln!(f, "# TYPE rrr_{}_total counter", name)?;
+            writeln!(f, "rrr_{}_total {}", name, value)?;
+        }
+
+        writeln!(
+            f,
+            "# HELP rrr_rule_hits_total Requests matched by each rule."
+        )?;
+        writeln!(f, "# TYPE rrr_rule_hits_total counter")?;
+        for ((origin, pattern), hits) in &self.rule_hits {
+            writeln!(
+                f,
+                "rrr_rule_hits_total{{origin=\"{}\",pattern=\"{}\"}} {}",
+                escape_label(origin),
+                escape_label(pattern),
+                hits
+            )?;
+        }
+
+        let name = "rrr_request_duration_seconds";
+        writeln!(
+            f,
+            "# HELP {} Time to resolve (and execute) the action of a request.",
+            name
+        )?;
+        writeln!(f, "# TYPE {} histogram", name)?;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(self.durations) {
+            writeln!(f, "{}_bucket{{le=\"{}\"}} {}", name, bound, count)?;
+        }
+        writeln!(f, "{}_bucket{{le=\"+Inf\"}} {}", name, self.requests)?;
+        writeln!(f, "{}_sum {}", name, self.duration_sum)?;
+        writeln!(f, "{}_count {}", name, self.requests)
+    }
+}
+
+/// Escape a label value of the Prometheus format (backslashes, quotes and newlines).
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Compare the tokens without leaking where they differ through the timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,440 @@
+use std::{path::PathBuf, time::Duration};
+
+use regex::Regex;
+
+use crate::{
+    error::{Result, RrrError},
+    utils,
+};
+
+/// Content refused in the commands when deny-unsafe-input is set, as it could alter a shell command.
+const UNSAFE_INPUT_PATTERNS: [&str; 3] = ["`", "$(", "\n"];
+
+/// Global settings that can be changed from the config with `:set <name> [value]`.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Expand tilde and environment variables in the actions of the following rules.
+    pub expand_actions: bool,
+    /// Ignore the matching rules whose program cannot be found in PATH.
+    pub skip_missing_commands: bool,
+    /// Pass the input to the shell as a positional parameter for the following rules.
+    pub argv_input: bool,
+    /// Refuse to execute the commands containing shell command substitutions or newlines.
+    pub deny_unsafe_input: bool,
+    /// Refuse to execute the commands matching one of these regexes once substituted (see `:deny`).
+    pub deny_patterns: Vec<Regex>,
+    /// Record every execution in this append-only log.
+    pub audit_log: Option<PathBuf>,
+    /// Size in bytes after which the audit log is rotated.
+    pub audit_log_max_size: u64,
+    /// Number of rotated audit logs that are kept.
+    pub audit_log_rotate: usize,
+    /// Cache the rules created by `:import` until the imported desktop files change.
+    pub import_cache: bool,
+    /// Directory of the WASM plugins, $XDG_CONFIG_HOME/rrr/plugins when not set.
+    pub plugin_dir: Option<PathBuf>,
+    /// Skip the invalid lines and rules with a warning instead of failing to load.
+    pub lenient: bool,
+    /// Accept the POSIX character classes and the ksh patterns in globs ("glob-syntax extended").
+    pub extended_glob: bool,
+    /// Compile the regexes with fancy-regex (lookarounds, backreferences), "regex-engine fancy".
+    pub fancy_regex: bool,
+    /// Try every regex rule before the glob rules instead of following the config order.
+    pub regex_first: bool,
+    /// The first matching rule of the config wins instead of the last one, "match-order first".
+    pub first_match: bool,
+    /// Environment variables kept when an action is executed in a clean environment.
+    pub env_keep: Vec<String>,
+    /// Remove the whitespace around the inputs before matching them.
+    pub trim_input: bool,
+    /// Remove the slashes at the end of the inputs before matching them (except for "/").
+    pub strip_trailing_slash: bool,
+    /// Minimum score (from 0 to 1) of the input for the `fuzzy:` patterns of the following rules.
+    pub fuzzy_score: f64,
+    /// Number of bytes read from the input files for the `content:` patterns of the following rules.
+    pub content_head_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            expand_actions: false,
+            skip_missing_commands: false,
+            argv_input: false,
+            deny_unsafe_input: false,
+            deny_patterns: vec![],
+            audit_log: None,
+            audit_log_max_size: 1024 * 1024,
+            audit_log_rotate: 5,
+            import_cache: true,
+            plugin_dir: None,
+            lenient: false,
+            extended_glob: false,
+            fancy_regex: false,
+            regex_first: false,
+            first_match: false,
+            env_keep: [
+                "PATH",
+                "HOME",
+                "USER",
+                "LOGNAME",
+                "LANG",
+                "TERM",
+                "DISPLAY",
+                "WAYLAND_DISPLAY",
+                "XDG_RUNTIME_DIR",
+            ]
+            .map(String::from)
+            .to_vec(),
+            trim_input: false,
+            strip_trailing_slash: false,
+            fuzzy_score: 0.75,
+            content_head_size: 4096,
+        }
+    }
+}
+
+impl Settings {
+    /// Change a setting by name. A setting without value is a boolean set to true.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<()> {
+        match name {
+            "expand-actions" => self.expand_actions = parse_bool(name, value)?,
+            "skip-missing-commands" => self.skip_missing_commands = parse_bool(name, value)?,
+            "deny-unsafe-input" => self.deny_unsafe_input = parse_bool(name, value)?,
+            "audit-log" => self.audit_log = value.map(utils::expand).transpose()?,
+            "audit-log-max-size" => self.audit_log_max_size = parse_number(name, value)?,
+            "audit-log-rotate" => self.audit_log_rotate = parse_number(name, value)?,
+            "import-cache" => self.import_cache = parse_bool(name, value)?,
+            "plugin-dir" => self.plugin_dir = value.map(utils::expand).transpose()?,
+            "lenient" => self.lenient = parse_bool(name, value)?,
+            "glob-syntax" => {
+                self.extended_glob = match value {
+                    Some("standard") => false,
+                    Some("extended") => true,
+                    _ => {
+                        return Err(RrrError::invalid(
+                            "Invalid value for 'glob-syntax', expected 'standard' or 'extended'",
+                        ));
+                    }
+                }
+            }
+            "regex-engine" => {
+                self.fancy_regex = match value {
+                    Some("standard") => false,
+                    Some("fancy") if cfg!(feature = "fancy") => true,
+                    Some("fancy") => return Err(RrrError::NotCompiled("fancy")),
+                    _ => {
+                        return Err(RrrError::invalid(
+                            "Invalid value for 'regex-engine', expected 'standard' or 'fancy'",
+                        ));
+                    }
+                }
+            }
+            "regex-first" => self.regex_first = parse_bool(name, value)?,
+            "match-order" => {
+                self.first_match = match value {
+                    Some("last") => false,
+                    Some("first") => true,
+                    _ => {
+                        return Err(RrrError::invalid(
+                            "Invalid value for 'match-order', expected 'first' or 'last'",
+                        ));
+                    }
+                }
+            }
+            "argv-input" => self.argv_input = parse_bool(name, value)?,
+            "env-keep" => self.env_keep = parse_list(value),
+            "trim-input" => self.trim_input = parse_bool(name, value)?,
+            "strip-trailing-slash" => self.strip_trailing_slash = parse_bool(name, value)?,
+            "fuzzy-score" => self.fuzzy_score = parse_score(name, value)?,
+            "content-head-size" => self.content_head_size = parse_number(name, value)?,
+            _ => return Err(RrrError::invalid(format!("Unknown setting '{}'", name))),
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Input as it should be matched, according to trim-input and strip-trailing-slash.
+    pub fn normalize_input<'a>(&self, mut input: &'a str) -> &'a str {
+        if self.trim_input {
+            input = input.trim();
+        }
+        if self.strip_trailing_slash && input.len() > 1 {
+            input = match input.trim_end_matches('/') {
+                "" => "/", // the root directory
+                stripped => stripped,
+            };
+        }
+        input
+    }
+
+    /// Return the denied pattern found in the substituted command (or one of its arguments), if any.
+    pub fn denied_pattern(&self, command: &str) -> Option<String> {
+        if self.deny_unsafe_input
+            && let Some(pattern) = UNSAFE_INPUT_PATTERNS.iter().find(|p| command.contains(*p))
+        {
+            return Some(pattern.escape_debug().to_string());
+        }
+
+        self.deny_patterns
+            .iter()
+            .find(|re| re.is_match(command))
+            .map(|re| re.as_str().to_string())
+    }
+}
+
+/// Parse the value of a boolean setting or option, no value means true.
+pub(crate) fn parse_bool(name: &str, value: Option<&str>) -> Result<bool> {
+    match value {
+        None | Some("true" | "yes" | "on" | "1") => Ok(true),
+        Some("false" | "no" | "off" | "0") => Ok(false),
+        Some(value) => Err(invalid_value("boolean", value, name)),
+    }
+}
+
+fn invalid_value(kind: &'static str, value: impl ToString, name: &str) -> RrrError {
+    RrrError::InvalidValue {
+        kind,
+        value: value.to_string(),
+        name: name.to_string(),
+    }
+}
+
+/// Parse the value of a numeric setting or option.
+pub(crate) fn parse_number<T: std::str::FromStr>(name: &str, value: Option<&str>) -> Result<T> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid_value("number", value.unwrap_or(""), name))
+}
+
+/// Parse a score between 0 and 1.
+pub(crate) fn parse_score(name: &str, value: Option<&str>) -> Result<f64> {
+    let score: f64 = parse_number(name, value)?;
+    if !(0.0..=1.0).contains(&score) {
+        return Err(RrrError::invalid(format!(
+            "Invalid score '{}' for '{}', expected from 0 to 1",
+            score, name
+        )));
+    }
+    Ok(score)
+}
+
+/// Parse a duration in seconds, or with a unit (e.g. `500ms`, `2s`, `1m`).
+pub(crate) fn parse_duration(name: &str, value: Option<&str>) -> Result<Duration> {
+    let value = value.unwrap_or("");
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| c.is_alphabetic())
+            .unwrap_or(value.len()),
+    );
+    let number: f64 = number
+        .parse()
+        .map_err(|_| invalid_value("duration", value, name))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(invalid_value("duration unit", unit, name)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid_value("duration", value, name))
+}
+
+/// Parse a list of words separated by commas or whitespaces, no value means an empty list.
+pub(crate) fn parse_list(value: Option<&str>) -> Vec<String> {
+    value
+        .unwrap_or("")
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Days of the week as written in the `days` option, from monday.
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/**
+  Parse ranges of hours such as `9-18` (from 9:00 to 18:00, excluded), `22-6` (over midnight)
+  or `12` (from 12:00 to 13:00), separated by commas.
+*/
+pub(crate) fn parse_hours(name: &str, value: Option<&str>) -> Result<Vec<(u32, u32)>> {
+    let invalid = |range: &str| invalid_value("hours", range, name);
+    let ranges = parse_list(value);
+    if ranges.is_empty() {
+        return Err(invalid(""));
+    }
+    ranges
+        .iter()
+        .map(|range| {
+            let hour = |h: &str| h.parse::<u32>().ok().filter(|h| *h <= 24);
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (hour(start), hour(end)),
+                None => (hour(range), hour(range).map(|h| h + 1)),
+            };
+            match (start, end) {
+                (Some(start), Some(end)) if start < 24 && start != end => Ok((start, end)),
+                _ => Err(invalid(range)),
+            }
+        })
+        .collect()
+}
+
+/**
+  Parse ranges of days such as `mon-fri` (included), `fri-mon` (over the weekend) or `sat`,
+  separated by commas. Days are numbered from monday (0).
+*/
+pub(crate) fn parse_days(name: &str, value: Option<&str>) -> Result<Vec<(u32, u32)>> {
+    let invalid = |range: &str| invalid_value("days", range, name);
+    let ranges = parse_list(value);
+    if ranges.is_empty() {
+        return Err(invalid(""));
+    }
+    ranges
+        .iter()
+        .map(|range| {
+            let day = |d: &str| {
+                let d = d.to_ascii_lowercase();
+                DAYS.iter().position(|day| *day == d).map(|i| i as u32)
+            };
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (day(start), day(end)),
+                None => (day(range), day(range)),
+            };
+            start.zip(end).ok_or_else(|| invalid(range))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Local, TimeZone};
+
+    use super::*;
+    use crate::rule_set::Schedule;
+
+    /// Local time at the hour of the day of the week numbered from monday (0).
+    fn time(day: u32, hour: u32) -> DateTime<Local> {
+        // 2024-01-01 is a monday
+        Local
+            .with_ymd_and_hms(2024, 1, 1 + day, hour, 0, 0)
+            .earliest()
+            .unwrap()
+    }
+
+    fn schedule(hours: Option<&str>, days: Option<&str>) -> Schedule {
+        Schedule {
+            hours: hours.map_or(vec![], |hours| parse_hours("hours", Some(hours)).unwrap()),
+            days: days.map_or(vec![], |days| parse_days("days", Some(days)).unwrap()),
+        }
+    }
+
+    #[test]
+    fn parse_hours_ranges() {
+        let cases: &[(&str, &[(u32, u32)])] = &[
+            ("9-18", &[(9, 18)]),
+            ("22-6", &[(22, 6)]),
+            ("12", &[(12, 13)]),
+            ("23", &[(23, 24)]),
+            ("0-24", &[(0, 24)]),
+            ("8-12,14-18", &[(8, 12), (14, 18)]),
+            ("8-12 14", &[(8, 12), (14, 15)]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                parse_hours("hours", Some(value)).unwrap(),
+                *expected,
+                "'{}'",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn parse_hours_rejects_invalid_ranges() {
+        for value in [
+            "", "24", "24-6", "12-12", "9-25", "-6", "9-", "noon", "9-18,x",
+        ] {
+            let error = parse_hours("hours", Some(value)).unwrap_err();
+            assert!(
+                matches!(error, RrrError::InvalidValue { kind: "hours", .. }),
+                "'{}' should be invalid, got {:?}",
+                value,
+                error
+            );
+        }
+        assert!(parse_hours("hours", None).is_err());
+    }
+
+    #[test]
+    fn parse_days_ranges() {
+        let cases: &[(&str, &[(u32, u32)])] = &[
+            ("mon-fri", &[(0, 4)]),
+            ("fri-mon", &[(4, 0)]),
+            ("sat", &[(5, 5)]),
+            ("Sun", &[(6, 6)]),
+            ("sat-sat", &[(5, 5)]),
+            ("mon,wed-thu", &[(0, 0), (2, 3)]),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(
+                parse_days("days", Some(value)).unwrap(),
+                *expected,
+                "'{}'",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn parse_days_rejects_invalid_ranges() {
+        for value in ["", "monday", "mon-", "-fri", "mon-xyz", "0-4"] {
+            let error = parse_days("days", Some(value)).unwrap_err();
+            assert!(
+                matches!(error, RrrError::InvalidValue { kind: "days", .. }),
+                "'{}' should be invalid, got {:?}",
+                value,
+                error
+            );
+        }
+        assert!(parse_days("days", None).is_err());
+    }
+
+    #[test]
+    fn schedule_contains_the_times_of_its_ranges() {
+        let cases = [
+            // hours over midnight
+            (schedule(Some("22-6"), None), time(0, 22), true),
+            (schedule(Some("22-6"), None), time(0, 0), true),
+            (schedule(Some("22-6"), None), time(0, 5), true),
+            (schedule(Some("22-6"), None), time(0, 6), false),
+            (schedule(Some("22-6"), None), time(0, 21), false),
+            // a single hour, up to the end of the day
+            (schedule(Some("12"), None), time(0, 12), true),
+            (schedule(Some("12"), None), time(0, 13), false),
+            (schedule(Some("18-24"), None), time(0, 23), true),
+            (schedule(Some("18-24"), None), time(0, 0), false),
+            // days over the end of the week
+            (schedule(None, Some("fri-mon")), time(4, 12), true),
+            (schedule(None, Some("fri-mon")), time(6, 12), true),
+            (schedule(None, Some("fri-mon")), time(0, 12), true),
+            (schedule(None, Some("fri-mon")), time(1, 12), false),
+            (schedule(None, Some("fri-mon")), time(3, 12), false),
+            // both hours and days
+            (schedule(Some("9-18"), Some("mon-fri")), time(2, 10), true),
+            (schedule(Some("9-18"), Some("mon-fri")), time(2, 20), false),
+            (schedule(Some("9-18"), Some("mon-fri")), time(5, 10), false),
+            // hours over midnight are only checked against the day of the time
+            (schedule(Some("22-6"), Some("fri")), time(5, 1), false),
+            (schedule(None, None), time(3, 3), true),
+        ];
+        for (schedule, time, expected) in cases {
+            assert_eq!(
+                schedule.contains(&time),
+                expected,
+                "{:?} at {}",
+                schedule,
+                time
+            );
+        }
+    }
+}
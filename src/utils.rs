@@ -1,25 +1,534 @@
-use anyhow::{Result, anyhow};
-use std::{borrow::Cow, path::PathBuf};
+use std::{
+    borrow::Cow,
+    env,
+    ffi::{CStr, OsString},
+    fs,
+    io::{self, Read},
+    mem,
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    process::{self, Command},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::error::{Context, Result, RrrError};
+
+/// Largest buffer given to getpwuid_r for the passwd entry of a user.
+const MAX_PASSWD_BUFFER: usize = 1 << 20;
+
+/// Character standing for the byte 0x00 in an input, only the bytes from 0x80 are ever mapped.
+const ESCAPED_BYTES: u32 = 0x10FF00;
+
+/**
+  Input given as bytes, e.g. a latin-1 filename. The bytes that are not valid UTF-8 are mapped to
+  private use characters (U+10FF80 to U+10FFFF) so that `input_to_bytes` gives them back.
+*/
+pub fn input_from_bytes(bytes: &[u8]) -> String {
+    let mut input = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        input.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            input.extend(char::from_u32(ESCAPED_BYTES + u32::from(*byte)));
+        }
+    }
+    input
+}
+
+/// Bytes of an input read with `input_from_bytes`, or of an action where it was substituted.
+pub fn input_to_bytes(input: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(input.len());
+    for c in input.chars() {
+        match u32::from(c).checked_sub(ESCAPED_BYTES) {
+            Some(byte @ 0x80..=0xff) => bytes.push(byte as u8),
+            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Same as `input_to_bytes`, e.g. for an argument of a command.
+pub fn input_to_os_string(input: &str) -> OsString {
+    OsString::from_vec(input_to_bytes(input))
+}
+
+/// Path of a local input, see `input_from_bytes`.
+pub fn input_path(input: &str) -> PathBuf {
+    PathBuf::from(input_to_os_string(input))
+}
 
 /// Add quotes around a string (if needed)
 pub(crate) fn quote(s: &str) -> Result<Cow<'_, str>> {
-    Ok(shlex::try_quote(s)?)
+    shlex::try_quote(s).map_err(|e| RrrError::invalid(e.to_string()))
 }
 
 /// Remove the quotes from a string, e.g. "\"hello world\"" -> "hello world"
 pub(crate) fn unquote(s: &str) -> Result<String> {
     // we use shlex as it's already in the dependencies
-    if let Some(parts) = shlex::split(s) {
-        if parts.len() == 1 {
-            return Ok(parts[0].clone());
-        }
+    if let Some(parts) = shlex::split(s)
+        && parts.len() == 1
+    {
+        return Ok(parts[0].clone());
     }
-    Err(anyhow!("invalid quoted string"))
+    Err(RrrError::invalid("invalid quoted string"))
 }
 
 /// Parse a string as a path with tilde and environment expansion
 pub(crate) fn expand(s: &str) -> Result<PathBuf> {
-    // todo: add more context for anyhow, see https://docs.rs/shellexpand/latest/shellexpand/fn.full.html
-    let expanded_str = shellexpand::full(s)?;
+    // todo: add more context to the error, see https://docs.rs/shellexpand/latest/shellexpand/fn.full.html
+    let expanded_str = shellexpand::full(s).map_err(|e| RrrError::invalid(e.to_string()))?;
     Ok(PathBuf::from(expanded_str.as_ref()))
 }
+
+/// Expand tilde (at the start of words) and environment variables in a shell command
+pub(crate) fn expand_command(s: &str) -> Result<String> {
+    let home = || shellexpand::tilde("~").into_owned();
+
+    let mut with_home = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    let mut word_start = true;
+    while let Some(c) = chars.next() {
+        if c == '~' && word_start && matches!(chars.peek(), None | Some('/') | Some(' ' | '\t')) {
+            with_home.push_str(&home());
+        } else {
+            with_home.push(c);
+        }
+        word_start = c.is_whitespace() || c == '=' || c == ':';
+    }
+
+    shellexpand::env(&with_home)
+        .map(Cow::into_owned)
+        .map_err(|e| RrrError::invalid(e.to_string()))
+}
+
+/// Program executed by a shell command, i.e. the first word that is not a variable assignment
+pub(crate) fn command_program(command: &str) -> Option<String> {
+    shlex::split(command)?
+        .into_iter()
+        .find(|word| !is_assignment(word))
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+/// Look for an executable program either as a path or in the directories of PATH
+pub(crate) fn find_executable(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+/**
+  Format of an executable binary file (elf, macho or pe), recognized by its magic bytes.
+  Files without the executable bit are not considered as executable binaries.
+*/
+pub(crate) fn binary_format(path: &Path) -> Option<&'static str> {
+    let metadata = path.metadata().ok()?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return None;
+    }
+
+    let mut magic = [0u8; 8];
+    fs::File::open(path).ok()?.read_exact(&mut magic).ok()?;
+    match magic {
+        [0x7f, b'E', b'L', b'F', ..] => Some("elf"),
+        [0xfe, 0xed, 0xfa, 0xce | 0xcf, ..] | [0xce | 0xcf, 0xfa, 0xed, 0xfe, ..] => Some("macho"),
+        // universal binaries share their magic with Java classes, which have a larger version
+        [0xca, 0xfe, 0xba, 0xbe, a, b, c, d] if u32::from_be_bytes([a, b, c, d]) < 45 => {
+            Some("macho")
+        }
+        [b'M', b'Z', ..] => Some("pe"),
+        _ => None,
+    }
+}
+
+/// Name of the machine, as given by gethostname(2)
+pub(crate) fn hostname() -> Result<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, the name is truncated to fit in it
+    let result = unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) };
+    if result != 0 {
+        return Err(io::Error::last_os_error()).context("cannot get the hostname");
+    }
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    Ok(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+/// Name of the effective user of the process, or its uid when it has no passwd entry.
+pub(crate) fn effective_user() -> String {
+    // SAFETY: geteuid has no precondition and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    let mut buffer = vec![0u8; 1024];
+    loop {
+        // SAFETY: a zeroed passwd is valid (null pointers and integers), getpwuid_r fills it with
+        // pointers into the buffer, which is valid for its whole length and outlives them
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let error = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if error == libc::ERANGE && buffer.len() < MAX_PASSWD_BUFFER {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if error != 0 || result.is_null() {
+            return uid.to_string();
+        }
+        // SAFETY: on success pw_name is a NUL-terminated string in the buffer
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        return name.to_string_lossy().into_owned();
+    }
+}
+
+/// Candidate closest to a misspelled word, if it is close enough to be a likely typo
+pub(crate) fn closest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let max_distance = word.chars().count().div_ceil(3);
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(word, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/**
+  Score from 0 to 1 of how closely the input contains the pattern, i.e. one minus the smallest
+  edit distance between the pattern and a substring of the input, relative to the pattern length.
+*/
+pub(crate) fn fuzzy_score(pattern: &str, input: &str, case_insensitive: bool) -> f64 {
+    let fold = |s: &str| -> Vec<char> {
+        if case_insensitive {
+            s.chars().flat_map(char::to_lowercase).collect()
+        } else {
+            s.chars().collect()
+        }
+    };
+    let (pattern, input) = (fold(pattern), fold(input));
+    if pattern.is_empty() {
+        return 1.0;
+    }
+
+    // approximate substring matching: the pattern may start anywhere in the input for free
+    let mut previous = vec![0; input.len() + 1];
+    for (i, cp) in pattern.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, ci) in input.iter().enumerate() {
+            let substitution = previous[j] + usize::from(cp != ci);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    let distance = previous.into_iter().min().unwrap_or(pattern.len());
+    1.0 - distance as f64 / pattern.len() as f64
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Value of a URL component token (`scheme`, `host`, `port`, `path`, `query`, `fragment` or
+/// `query.<param>`) of the input, None if the name is not a URL component. The value is empty
+/// when the input is not a URL or lacks this component.
+pub(crate) fn url_component(url: &str, name: &str) -> Option<String> {
+    let components = UrlComponents::parse(url).unwrap_or_default();
+    let value = match name {
+        "scheme" => components.scheme,
+        "host" => components.host,
+        "port" => components.port,
+        "path" => components.path,
+        "query" => components.query,
+        "fragment" => components.fragment,
+        _ => {
+            let param = name.strip_prefix("query.")?;
+            return Some(
+                components
+                    .query
+                    .split('&')
+                    .filter_map(|pair| {
+                        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        let decode = |s: &str| percent_decode(&s.replace('+', " "));
+                        (decode(key) == param).then(|| decode(value))
+                    })
+                    .next()
+                    .unwrap_or_default(),
+            );
+        }
+    };
+    Some(value.to_string())
+}
+
+/// Check if the input is a remote file that can be fetched (see `fetch`)
+pub(crate) fn is_remote(input: &str) -> bool {
+    UrlComponents::parse(input).is_some_and(|url| {
+        matches!(url.scheme, "ssh" | "http" | "https" | "ftp") && !url.host.is_empty()
+    })
+}
+
+/// Directory of the temporary files of the process (e.g. the fetched inputs), see `process_dir`.
+pub(crate) fn process_dir_path() -> PathBuf {
+    env::temp_dir().join(format!("rrr-{}", process::id()))
+}
+
+/// Check if the path is a directory (not a link) of the user that nobody else can access.
+pub(crate) fn is_private_dir(path: &Path) -> bool {
+    // SAFETY: geteuid has no precondition and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    fs::symlink_metadata(path).is_ok_and(|metadata| {
+        metadata.is_dir() && metadata.uid() == uid && metadata.mode() & 0o077 == 0
+    })
+}
+
+/**
+  Create the directory of the temporary files of the process, only accessible by the user. It is
+  never created recursively, and an existing one is only reused when it is private to the user
+  (i.e. created by rrr earlier), so that a directory prepared by someone else in /tmp is refused.
+*/
+pub fn process_dir() -> Result<PathBuf> {
+    let dir = process_dir_path();
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && is_private_dir(&dir) => Ok(dir),
+        Err(e) => Err(e).with_context(|| format!("cannot create directory '{}'", dir.display())),
+        Ok(()) => Ok(dir),
+    }
+}
+
+/**
+  Local path where a remote file is fetched, named after the last segment of its URL path in a
+  directory only readable by the user (created on fetch), unique for each fetched file.
+*/
+pub(crate) fn fetch_path(url: &str) -> PathBuf {
+    static FETCHED: AtomicUsize = AtomicUsize::new(0);
+    let file_name = UrlComponents::parse(url)
+        .and_then(|url| url.path.rsplit('/').next())
+        .map(percent_decode)
+        .filter(|name| is_file_name(name))
+        .unwrap_or_else(|| "index".to_string());
+    process_dir_path()
+        .join(format!("fetch-{}", FETCHED.fetch_add(1, Ordering::Relaxed)))
+        .join(file_name)
+}
+
+/// Check if a decoded name is a single path component, e.g. not an encoded "/etc/passwd" or "..".
+fn is_file_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\0'])
+}
+
+/// Download a remote file to a local path, with scp for ssh:// and curl otherwise
+pub(crate) fn fetch(url: &str, path: &Path) -> Result<()> {
+    // the directory of the fetched file is created in the one of the process, never reused
+    let process_dir = process_dir()?;
+    let dir = path
+        .parent()
+        .filter(|dir| dir.parent() == Some(process_dir.as_path()))
+        .with_context(|| format!("invalid path to fetch '{}' to", url))?;
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("cannot create directory '{}'", dir.display()))?;
+
+    let components = UrlComponents::parse(url).unwrap_or_default();
+    let mut cmd = if components.scheme == "ssh" {
+        // scp takes the port apart, user@host:path is left of the port of the authority
+        let authority = url["ssh://".len()..].split('/').next().unwrap_or_default();
+        let user_host = match authority.rsplit_once(':') {
+            Some((user_host, _)) if !components.port.is_empty() => user_host,
+            _ => authority,
+        };
+        // the input may be untrusted, it must not be taken for an option of scp or ssh
+        let is_safe = |part: &str| {
+            !part.starts_with('-') && !part.chars().any(|c| c.is_whitespace() || c.is_control())
+        };
+        if !user_host.split('@').all(is_safe)
+            || !components.port.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(RrrError::invalid(format!("invalid host in '{}'", url)));
+        }
+        let mut cmd = Command::new("scp");
+        cmd.args(["-q", "-T"]);
+        if !components.port.is_empty() {
+            cmd.args(["-P", components.port]);
+        }
+        // the remote path goes through the shell of the remote host
+        let remote_path = percent_decode(components.path);
+        cmd.arg("--");
+        cmd.arg(format!("{}:{}", user_host, quote(&remote_path)?));
+        cmd.arg(path);
+        cmd
+    } else {
+        let mut cmd = Command::new("curl");
+        cmd.args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ]);
+        cmd.arg(path).arg(url);
+        cmd
+    };
+
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd.status().map_err(|source| RrrError::ExecFailed {
+        command: program.clone(),
+        source,
+    })?;
+    if !status.success() {
+        return Err(RrrError::invalid(format!(
+            "cannot fetch '{}': {} failed",
+            url, program
+        )));
+    }
+    Ok(())
+}
+
+/// Components of a URL, e.g. https://host:port/path?query#fragment
+#[derive(Default)]
+struct UrlComponents<'a> {
+    scheme: &'a str,
+    host: &'a str,
+    port: &'a str,
+    path: &'a str,
+    query: &'a str,
+    fragment: &'a str,
+}
+
+impl<'a> UrlComponents<'a> {
+    fn parse(url: &'a str) -> Option<Self> {
+        let (scheme, rest) = url.split_once(':')?;
+        let mut scheme_chars = scheme.chars();
+        if !scheme_chars.next()?.is_ascii_alphabetic()
+            || !scheme_chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        {
+            return None;
+        }
+
+        let (rest, fragment) = rest.split_once('#').unwrap_or((rest, ""));
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, path) = match rest.strip_prefix("//") {
+            Some(rest) => rest.split_at(rest.find('/').unwrap_or(rest.len())),
+            None => ("", rest), // e.g. mailto:user@host
+        };
+        let host_port = authority.rsplit_once('@').map_or(authority, |(_, hp)| hp);
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port), // not within [ipv6]
+            _ => (host_port, ""),
+        };
+
+        Some(UrlComponents {
+            scheme,
+            host: host.trim_start_matches('[').trim_end_matches(']'),
+            port,
+            path,
+            query,
+            fragment,
+        })
+    }
+}
+
+/// Decode the %XX escapes of a URL element
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if let Some(byte) = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()) =>
+            {
+                decoded.push(byte);
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    input_from_bytes(&decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_score_of_empty_patterns_and_inputs() {
+        assert_eq!(fuzzy_score("", "report.pdf", false), 1.0);
+        assert_eq!(fuzzy_score("", "", false), 1.0);
+        assert_eq!(fuzzy_score("pdf", "", false), 0.0);
+        assert_eq!(fuzzy_score("pdf", "xyz", false), 0.0);
+    }
+
+    #[test]
+    fn fuzzy_score_of_exact_substrings() {
+        assert_eq!(fuzzy_score("report", "report", false), 1.0);
+        assert_eq!(fuzzy_score("report", "~/docs/report-2024.pdf", false), 1.0);
+        assert_eq!(fuzzy_score("Report", "report.pdf", true), 1.0);
+        assert_eq!(fuzzy_score("Report", "report.pdf", false), 1.0 - 1.0 / 6.0);
+    }
+
+    #[test]
+    fn fuzzy_score_of_typos() {
+        // substitution, insertion and deletion cost one edit each, a transposition two
+        assert_eq!(
+            fuzzy_score("invoise", "invoice.pdf", false),
+            1.0 - 1.0 / 7.0
+        );
+        assert_eq!(fuzzy_score("invoce", "invoice.pdf", false), 1.0 - 1.0 / 6.0);
+        assert_eq!(
+            fuzzy_score("invoicce", "invoice.pdf", false),
+            1.0 - 1.0 / 8.0
+        );
+        assert_eq!(
+            fuzzy_score("invioce", "invoice.pdf", false),
+            1.0 - 2.0 / 7.0
+        );
+        // the closest substring counts, wherever it is in the input
+        assert_eq!(fuzzy_score("report", "repo/reprt/report.pdf", false), 1.0);
+    }
+
+    #[test]
+    fn fuzzy_score_at_the_threshold() {
+        // with the default minimum score of 0.75, one edit is accepted from four characters
+        assert_eq!(fuzzy_score("cats", "my_cots.txt", false), 0.75);
+        assert!(fuzzy_score("cat", "my_cot.txt", false) < 0.75);
+        assert!(fuzzy_score("catss", "my_cots.txt", false) < 0.75);
+        assert!(fuzzy_score("kittens", "my_kitens.txt", false) > 0.75);
+    }
+}